@@ -1,5 +1,4 @@
-use mqttbytes::QoS;
-use qute::{ClientBuilder, HandlerRouterBuilder};
+use qute::{ClientBuilder, HandlerRouterBuilder, QoS};
 
 #[tokio::main]
 async fn main() {
@@ -11,10 +10,16 @@ async fn main() {
 
     let handlers = handlers.build();
 
-    let client = ClientBuilder::new("127.0.0.1:1883").build(handlers).await;
+    let client = ClientBuilder::new("127.0.0.1:1883")
+        .build(handlers)
+        .await
+        .unwrap();
 
     // Send a test message that the client then handles.
-    client.publish("test", QoS::AtMostOnce, b"hello").await;
+    client
+        .publish("test", QoS::AtMostOnce, b"hello")
+        .await
+        .unwrap();
 
     client.shutdown().await;
 }
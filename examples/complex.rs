@@ -30,31 +30,60 @@ async fn main() {
     router.add("callback", |publish: Publish| {
         tracing::warn!(?publish, "Callback handler!");
     });
+    router.fallback(|Topic(topic): Topic| {
+        tracing::warn!(?topic, "Fallback handler!");
+    });
     let router = router.build();
 
-    let client = ClientBuilder::new("127.0.0.1:1883").build(router).await;
+    let client = ClientBuilder::new("127.0.0.1:1883")
+        .build(router)
+        .await
+        .unwrap();
 
-    client.publish("test", QoS::AtMostOnce, b"hello").await;
+    client
+        .publish("test", QoS::AtMostOnce, b"hello")
+        .await
+        .unwrap();
     client
         .publish("test", QoS::AtLeastOnce, b"hello world")
-        .await;
+        .await
+        .unwrap();
     client
         .publish("test", QoS::ExactlyOnce, b"hello complicated world")
-        .await;
-    client.publish("foo", QoS::AtLeastOnce, b"hello").await;
-    client.publish("foo/bar", QoS::AtLeastOnce, b"hello").await;
+        .await
+        .unwrap();
+    client
+        .publish("foo", QoS::AtLeastOnce, b"hello")
+        .await
+        .unwrap();
+    client
+        .publish("foo/bar", QoS::AtLeastOnce, b"hello")
+        .await
+        .unwrap();
     client
         .publish("foo/bar/baz", QoS::AtLeastOnce, b"hello")
-        .await;
-    client.publish("foo/foo", QoS::AtLeastOnce, b"hello").await;
-    client.publish("bar/bar", QoS::AtLeastOnce, b"hello").await;
-    client.publish("foobar", QoS::AtLeastOnce, b"hello").await;
+        .await
+        .unwrap();
+    client
+        .publish("foo/foo", QoS::AtLeastOnce, b"hello")
+        .await
+        .unwrap();
+    client
+        .publish("bar/bar", QoS::AtLeastOnce, b"hello")
+        .await
+        .unwrap();
+    client
+        .publish("foobar", QoS::AtLeastOnce, b"hello")
+        .await
+        .unwrap();
     client
         .publish("foobar/baz", QoS::AtLeastOnce, b"hello")
-        .await;
+        .await
+        .unwrap();
     client
         .publish("foobar/baz/bax", QoS::AtLeastOnce, b"hello")
-        .await;
+        .await
+        .unwrap();
 
     client.shutdown().await;
 }
@@ -96,7 +125,8 @@ async fn foobar(
     tracing::info!("Publishing stuff from handler.");
     publisher
         .publish("callback", QoS::AtLeastOnce, b"Real callback!")
-        .await;
+        .await
+        .unwrap();
     tracing::info!("Stuff from handler published.");
 }
 
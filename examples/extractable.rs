@@ -13,9 +13,15 @@ async fn main() {
 
     let handlers = handlers.build();
 
-    let client = ClientBuilder::new("127.0.0.1:1883").build(handlers).await;
-
-    client.publish("test", QoS::AtMostOnce, b"hello").await;
+    let client = ClientBuilder::new("127.0.0.1:1883")
+        .build(handlers)
+        .await
+        .unwrap();
+
+    client
+        .publish("test", QoS::AtMostOnce, b"hello")
+        .await
+        .unwrap();
 
     client.shutdown().await;
 }
@@ -21,17 +21,27 @@ async fn main() {
     });
     let handlers = handlers.with_state(Arc::new(AtomicU32::new(0))).build();
 
-    let client = ClientBuilder::new("127.0.0.1:1883").build(handlers).await;
+    let client = ClientBuilder::new("127.0.0.1:1883")
+        .build(handlers)
+        .await
+        .unwrap();
 
-    client.publish("count", QoS::AtMostOnce, b"hello").await;
-    client.publish("test", QoS::AtMostOnce, b"hello").await;
+    client
+        .publish("count", QoS::AtMostOnce, b"hello")
+        .await
+        .unwrap();
+    client
+        .publish("test", QoS::AtMostOnce, b"hello")
+        .await
+        .unwrap();
     client
         .publish(
             "count",
             QoS::AtMostOnce,
             b"This will not be printed anyway.",
         )
-        .await;
+        .await
+        .unwrap();
 
     client.shutdown().await;
 }
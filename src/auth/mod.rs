@@ -0,0 +1,29 @@
+use bytes::Bytes;
+
+use crate::Error;
+
+pub(crate) mod packet;
+#[cfg(feature = "scram")]
+mod scram;
+
+#[cfg(feature = "scram")]
+pub use scram::ScramSha256;
+
+/// Challenge/response authentication exchanged through AUTH packets, see [`ClientBuilder::set_authenticator`](crate::ClientBuilder::set_authenticator).
+///
+/// The same exchange runs when connecting and when re-authenticating with [`Client::reauthenticate`](crate::Client::reauthenticate).
+pub trait Authenticator: Send + 'static {
+    /// Authentication Method sent to the broker, e.g. `SCRAM-SHA-256`.
+    fn method(&self) -> &str;
+
+    /// Starts a new exchange and returns the Authentication Data sent in CONNECT or in the AUTH packet starting re-authentication.
+    fn start(&mut self) -> Result<Option<Bytes>, Error>;
+
+    /// Returns the response to Authentication Data sent by the broker.
+    fn challenge(&mut self, data: Option<Bytes>) -> Result<Option<Bytes>, Error>;
+
+    /// Called when the broker accepts the authentication, e.g. to verify the broker's final data.
+    fn finish(&mut self, _data: Option<Bytes>) -> Result<(), Error> {
+        Ok(())
+    }
+}
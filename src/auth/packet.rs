@@ -0,0 +1,222 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use mqttbytes::Error;
+
+// `mqttbytes` does not support AUTH packets so they are encoded here.
+
+pub(crate) const AUTH_PACKET_TYPE: u8 = 15;
+
+const AUTHENTICATION_METHOD: u8 = 0x15;
+const AUTHENTICATION_DATA: u8 = 0x16;
+const REASON_STRING: u8 = 0x1F;
+const USER_PROPERTY: u8 = 0x26;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum AuthReason {
+    Success = 0x00,
+    ContinueAuthentication = 0x18,
+    ReAuthenticate = 0x19,
+}
+
+impl TryFrom<u8> for AuthReason {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Self::Success),
+            0x18 => Ok(Self::ContinueAuthentication),
+            0x19 => Ok(Self::ReAuthenticate),
+            value => Err(Error::InvalidReason(value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Auth {
+    pub reason: AuthReason,
+    pub method: Option<String>,
+    pub data: Option<Bytes>,
+    pub reason_string: Option<String>,
+    pub user_properties: Vec<(String, String)>,
+}
+
+impl Auth {
+    pub fn new(reason: AuthReason, method: String, data: Option<Bytes>) -> Self {
+        Self {
+            reason,
+            method: Some(method),
+            data,
+            reason_string: None,
+            user_properties: Vec::new(),
+        }
+    }
+
+    /// Reads the whole packet including the fixed header.
+    pub fn read(mut bytes: Bytes) -> Result<Self, Error> {
+        let byte1 = read_u8(&mut bytes)?;
+        if byte1 != AUTH_PACKET_TYPE << 4 {
+            return Err(Error::IncorrectPacketFormat);
+        }
+        let remaining_len = read_variable_length(&mut bytes)?;
+        if bytes.len() != remaining_len {
+            return Err(Error::MalformedPacket);
+        }
+
+        let mut auth = Self {
+            reason: AuthReason::Success,
+            method: None,
+            data: None,
+            reason_string: None,
+            user_properties: Vec::new(),
+        };
+
+        if remaining_len == 0 {
+            return Ok(auth);
+        }
+        auth.reason = read_u8(&mut bytes)?.try_into()?;
+        if remaining_len == 1 {
+            return Ok(auth);
+        }
+
+        let properties_len = read_variable_length(&mut bytes)?;
+        if bytes.len() < properties_len {
+            return Err(Error::MalformedPacket);
+        }
+        let mut properties = bytes.split_to(properties_len);
+
+        while properties.has_remaining() {
+            match read_u8(&mut properties)? {
+                AUTHENTICATION_METHOD => auth.method = Some(read_string(&mut properties)?),
+                AUTHENTICATION_DATA => auth.data = Some(read_binary(&mut properties)?),
+                REASON_STRING => auth.reason_string = Some(read_string(&mut properties)?),
+                USER_PROPERTY => {
+                    let key = read_string(&mut properties)?;
+                    let value = read_string(&mut properties)?;
+                    auth.user_properties.push((key, value));
+                }
+                property => return Err(Error::InvalidPropertyType(property)),
+            }
+        }
+
+        Ok(auth)
+    }
+
+    pub fn write(&self, buf: &mut BytesMut) -> Result<usize, Error> {
+        let mut properties = BytesMut::new();
+        if let Some(method) = &self.method {
+            properties.put_u8(AUTHENTICATION_METHOD);
+            write_binary(&mut properties, method.as_bytes())?;
+        }
+        if let Some(data) = &self.data {
+            properties.put_u8(AUTHENTICATION_DATA);
+            write_binary(&mut properties, data)?;
+        }
+        if let Some(reason_string) = &self.reason_string {
+            properties.put_u8(REASON_STRING);
+            write_binary(&mut properties, reason_string.as_bytes())?;
+        }
+        for (key, value) in &self.user_properties {
+            properties.put_u8(USER_PROPERTY);
+            write_binary(&mut properties, key.as_bytes())?;
+            write_binary(&mut properties, value.as_bytes())?;
+        }
+
+        let mut variable_header = BytesMut::new();
+        variable_header.put_u8(self.reason as u8);
+        write_variable_length(&mut variable_header, properties.len())?;
+        variable_header.extend_from_slice(&properties);
+
+        let start = buf.len();
+        buf.put_u8(AUTH_PACKET_TYPE << 4);
+        write_variable_length(buf, variable_header.len())?;
+        buf.extend_from_slice(&variable_header);
+        Ok(buf.len() - start)
+    }
+}
+
+fn read_u8(bytes: &mut Bytes) -> Result<u8, Error> {
+    if !bytes.has_remaining() {
+        return Err(Error::MalformedPacket);
+    }
+    Ok(bytes.get_u8())
+}
+
+fn read_binary(bytes: &mut Bytes) -> Result<Bytes, Error> {
+    if bytes.remaining() < 2 {
+        return Err(Error::MalformedPacket);
+    }
+    let len = bytes.get_u16() as usize;
+    if bytes.remaining() < len {
+        return Err(Error::BoundaryCrossed(len));
+    }
+    Ok(bytes.split_to(len))
+}
+
+fn read_string(bytes: &mut Bytes) -> Result<String, Error> {
+    let binary = read_binary(bytes)?;
+    String::from_utf8(binary.to_vec()).map_err(|_| Error::TopicNotUtf8)
+}
+
+fn read_variable_length(bytes: &mut Bytes) -> Result<usize, Error> {
+    let mut len = 0;
+    for shift in [0, 7, 14, 21] {
+        let byte = read_u8(bytes)?;
+        len |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(len);
+        }
+    }
+    Err(Error::MalformedRemainingLength)
+}
+
+fn write_binary(buf: &mut BytesMut, bytes: &[u8]) -> Result<(), Error> {
+    let len = u16::try_from(bytes.len()).map_err(|_| Error::PayloadTooLong)?;
+    buf.put_u16(len);
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn write_variable_length(buf: &mut BytesMut, mut len: usize) -> Result<(), Error> {
+    if len > 268_435_455 {
+        return Err(Error::PayloadTooLong);
+    }
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        buf.put_u8(byte);
+        if len == 0 {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auth_round_trips() {
+        let mut auth = Auth::new(
+            AuthReason::ContinueAuthentication,
+            "SCRAM-SHA-256".to_owned(),
+            Some(Bytes::from(vec![7; 300])),
+        );
+        auth.user_properties
+            .push(("key".to_owned(), "value".to_owned()));
+
+        let mut buf = BytesMut::new();
+        let len = auth.write(&mut buf).unwrap();
+        assert_eq!(len, buf.len());
+        assert_eq!(Auth::read(buf.freeze()).unwrap(), auth);
+    }
+
+    #[test]
+    fn empty_auth_is_success() {
+        let auth = Auth::read(Bytes::from_static(&[0xF0, 0x00])).unwrap();
+        assert_eq!(auth.reason, AuthReason::Success);
+        assert_eq!(auth.method, None);
+    }
+}
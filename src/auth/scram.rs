@@ -0,0 +1,358 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+
+use super::Authenticator;
+use crate::Error;
+
+// Channel binding is not supported, the GS2 header is always `n,,`.
+const GS2_HEADER: &str = "n,,";
+
+/// SCRAM-SHA-256 as described in RFC 7677.
+pub struct ScramSha256 {
+    username: String,
+    password: String,
+    state: ScramState,
+}
+
+enum ScramState {
+    Initial,
+    ClientFirst {
+        nonce: String,
+        client_first_bare: String,
+    },
+    ClientFinal {
+        server_signature: Vec<u8>,
+    },
+}
+
+impl ScramSha256 {
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            username: username.into(),
+            password: password.into(),
+            state: ScramState::Initial,
+        }
+    }
+
+    fn start_with_nonce(&mut self, nonce: String) -> Bytes {
+        let username = self.username.replace('=', "=3D").replace(',', "=2C");
+        let client_first_bare = format!("n={username},r={nonce}");
+        let message = format!("{GS2_HEADER}{client_first_bare}");
+
+        self.state = ScramState::ClientFirst {
+            nonce,
+            client_first_bare,
+        };
+        Bytes::from(message)
+    }
+}
+
+impl Authenticator for ScramSha256 {
+    fn method(&self) -> &str {
+        "SCRAM-SHA-256"
+    }
+
+    fn start(&mut self) -> Result<Option<Bytes>, Error> {
+        let nonce = rand::thread_rng()
+            .sample_iter(Alphanumeric)
+            .take(24)
+            .map(char::from)
+            .collect();
+        Ok(Some(self.start_with_nonce(nonce)))
+    }
+
+    fn challenge(&mut self, data: Option<Bytes>) -> Result<Option<Bytes>, Error> {
+        let ScramState::ClientFirst {
+            nonce,
+            client_first_bare,
+        } = &self.state
+        else {
+            return Err(failure("unexpected challenge"));
+        };
+
+        let server_first = utf8(data)?;
+        let server_nonce = attribute(&server_first, 'r')?;
+        let salt = STANDARD
+            .decode(attribute(&server_first, 's')?)
+            .map_err(|_| failure("salt is not valid base64"))?;
+        let iterations = attribute(&server_first, 'i')?
+            .parse()
+            .map_err(|_| failure("iteration count is not a number"))?;
+
+        if !server_nonce.starts_with(nonce.as_str()) {
+            return Err(failure("server nonce does not start with client nonce"));
+        }
+
+        let mut salted_password = [0; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(
+            self.password.as_bytes(),
+            &salt,
+            iterations,
+            &mut salted_password,
+        );
+        let client_key = hmac(&salted_password, b"Client Key");
+        let stored_key = Sha256::digest(&client_key);
+        let server_key = hmac(&salted_password, b"Server Key");
+
+        let client_final_without_proof =
+            format!("c={},r={server_nonce}", STANDARD.encode(GS2_HEADER));
+        let auth_message =
+            format!("{client_first_bare},{server_first},{client_final_without_proof}");
+
+        let client_signature = hmac(&stored_key, auth_message.as_bytes());
+        let proof: Vec<u8> = client_key
+            .iter()
+            .zip(client_signature)
+            .map(|(key, signature)| key ^ signature)
+            .collect();
+
+        self.state = ScramState::ClientFinal {
+            server_signature: hmac(&server_key, auth_message.as_bytes()),
+        };
+
+        let client_final = format!("{client_final_without_proof},p={}", STANDARD.encode(proof));
+        Ok(Some(Bytes::from(client_final)))
+    }
+
+    fn finish(&mut self, data: Option<Bytes>) -> Result<(), Error> {
+        let ScramState::ClientFinal { server_signature } =
+            std::mem::replace(&mut self.state, ScramState::Initial)
+        else {
+            return Err(failure("authentication finished before the final message"));
+        };
+
+        let server_final = utf8(data)?;
+        if let Ok(error) = attribute(&server_final, 'e') {
+            return Err(failure(&format!("server rejected authentication: {error}")));
+        }
+        let verifier = STANDARD
+            .decode(attribute(&server_final, 'v')?)
+            .map_err(|_| failure("server signature is not valid base64"))?;
+        if verifier != server_signature {
+            return Err(failure("server signature does not match"));
+        }
+
+        Ok(())
+    }
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length.");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn utf8(data: Option<Bytes>) -> Result<String, Error> {
+    let data = data.ok_or_else(|| failure("missing authentication data"))?;
+    String::from_utf8(data.to_vec()).map_err(|_| failure("authentication data is not UTF-8"))
+}
+
+fn attribute(message: &str, name: char) -> Result<&str, Error> {
+    message
+        .split(',')
+        .find_map(|attribute| attribute.strip_prefix(name)?.strip_prefix('='))
+        .ok_or_else(|| failure(&format!("missing attribute `{name}`")))
+}
+
+fn failure(reason: &str) -> Error {
+    Error::Authentication(format!("SCRAM: {reason}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use mqttbytes::{
+        v5::{ConnAck, ConnAckProperties, ConnectReturnCode, Packet},
+        Protocol,
+    };
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        auth::packet::{Auth, AuthReason},
+        connection::{Connection, Incoming, Reader, Writer, DEFAULT_MAX_PACKET_SIZE},
+        ClientBuilder, HandlerRouterBuilder,
+    };
+
+    // Example exchange from RFC 7677.
+    #[test]
+    fn rfc_7677_example() {
+        let mut scram = ScramSha256::new("user", "pencil");
+
+        let client_first = scram.start_with_nonce("rOprNGfwEbeRWgbNEkqO".to_owned());
+        assert_eq!(client_first, "n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        let client_final = scram
+            .challenge(Some(Bytes::from_static(server_first.as_bytes())))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            client_final,
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+        );
+
+        let server_final = "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=";
+        scram
+            .finish(Some(Bytes::from_static(server_final.as_bytes())))
+            .unwrap();
+    }
+
+    #[test]
+    fn wrong_server_signature_is_rejected() {
+        let mut scram = ScramSha256::new("user", "pencil");
+        scram.start_with_nonce("rOprNGfwEbeRWgbNEkqO".to_owned());
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        scram
+            .challenge(Some(Bytes::from_static(server_first.as_bytes())))
+            .unwrap();
+
+        let server_final = "v=AAAATRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=";
+        assert!(scram
+            .finish(Some(Bytes::from_static(server_final.as_bytes())))
+            .is_err());
+    }
+
+    // Server side of SCRAM-SHA-256 for the stand-in broker below.
+    struct ScramServer {
+        salted_password: [u8; 32],
+        client_first_bare: String,
+        server_first: String,
+    }
+
+    impl ScramServer {
+        fn new(password: &str, client_first: &[u8]) -> Self {
+            let salt = b"qute-salt";
+            let mut salted_password = [0; 32];
+            pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, 4096, &mut salted_password);
+
+            let client_first_bare = std::str::from_utf8(client_first)
+                .unwrap()
+                .strip_prefix(GS2_HEADER)
+                .unwrap()
+                .to_owned();
+            let nonce = attribute(&client_first_bare, 'r').unwrap();
+            let server_first = format!("r={nonce}server,s={},i=4096", STANDARD.encode(salt));
+
+            Self {
+                salted_password,
+                client_first_bare,
+                server_first,
+            }
+        }
+
+        // Returns the server final message if the proof is valid.
+        fn verify(&self, client_final: &[u8]) -> Option<Bytes> {
+            let client_final = std::str::from_utf8(client_final).unwrap();
+            let (without_proof, proof) = client_final.split_once(",p=").unwrap();
+            let auth_message = format!(
+                "{},{},{without_proof}",
+                self.client_first_bare, self.server_first
+            );
+
+            let stored_key = Sha256::digest(hmac(&self.salted_password, b"Client Key"));
+            let client_signature = hmac(&stored_key, auth_message.as_bytes());
+            let client_key: Vec<u8> = STANDARD
+                .decode(proof)
+                .unwrap()
+                .iter()
+                .zip(client_signature)
+                .map(|(proof, signature)| proof ^ signature)
+                .collect();
+            if Sha256::digest(client_key) != stored_key {
+                return None;
+            }
+
+            let server_key = hmac(&self.salted_password, b"Server Key");
+            let server_signature = hmac(&server_key, auth_message.as_bytes());
+            Some(Bytes::from(format!(
+                "v={}",
+                STANDARD.encode(server_signature)
+            )))
+        }
+    }
+
+    async fn recv_auth(connection: &Connection<Reader, Writer>) -> Auth {
+        match connection.recv().await.unwrap() {
+            Some(Incoming::Auth(auth)) => auth,
+            packet => panic!("expected AUTH, got {packet:?}"),
+        }
+    }
+
+    // Handles the exchange on connect and one re-authentication.
+    async fn stand_in_broker(listener: TcpListener, password: &str) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let connection =
+            Connection::with_stream(Box::new(stream), DEFAULT_MAX_PACKET_SIZE, Protocol::V5);
+
+        let Some(Incoming::Packet(Packet::Connect(connect))) = connection.recv().await.unwrap()
+        else {
+            panic!("expected CONNECT");
+        };
+        let properties = connect.properties.unwrap();
+        assert_eq!(properties.authentication_method.unwrap(), "SCRAM-SHA-256");
+        let server = ScramServer::new(password, &properties.authentication_data.unwrap());
+
+        let challenge = Auth::new(
+            AuthReason::ContinueAuthentication,
+            "SCRAM-SHA-256".to_owned(),
+            Some(Bytes::from(server.server_first.clone())),
+        );
+        connection.send_auth(&challenge).unwrap().await;
+
+        let response = recv_auth(&connection).await;
+        let server_final = server.verify(&response.data.unwrap());
+        let mut connack = ConnAck::new(ConnectReturnCode::NotAuthorized, false);
+        if let Some(server_final) = server_final {
+            let mut properties = ConnAckProperties::new();
+            properties.authentication_method = Some("SCRAM-SHA-256".to_owned());
+            properties.authentication_data = Some(server_final);
+            connack = ConnAck::new(ConnectReturnCode::Success, false);
+            connack.properties = Some(properties);
+        }
+        connection.send(&Packet::ConnAck(connack)).unwrap().await;
+
+        let reauthenticate = recv_auth(&connection).await;
+        assert_eq!(reauthenticate.reason, AuthReason::ReAuthenticate);
+        let server = ScramServer::new(password, &reauthenticate.data.unwrap());
+        let challenge = Auth::new(
+            AuthReason::ContinueAuthentication,
+            "SCRAM-SHA-256".to_owned(),
+            Some(Bytes::from(server.server_first.clone())),
+        );
+        connection.send_auth(&challenge).unwrap().await;
+
+        let response = recv_auth(&connection).await;
+        let server_final = server.verify(&response.data.unwrap()).unwrap();
+        let success = Auth::new(
+            AuthReason::Success,
+            "SCRAM-SHA-256".to_owned(),
+            Some(server_final),
+        );
+        connection.send_auth(&success).unwrap().await;
+
+        // Keep the connection open until the client disconnects.
+        while let Ok(Some(_)) = connection.recv().await {}
+    }
+
+    #[tokio::test]
+    async fn authenticates_with_stand_in_broker() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let broker = tokio::spawn(stand_in_broker(listener, "pencil"));
+
+        let mut builder = ClientBuilder::new(address);
+        builder.set_authenticator(ScramSha256::new("user", "pencil"));
+        let client = builder
+            .build(HandlerRouterBuilder::new().build())
+            .await
+            .unwrap();
+
+        client.reauthenticate().await.unwrap();
+
+        client.shutdown().await;
+        broker.await.unwrap();
+    }
+}
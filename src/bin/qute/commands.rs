@@ -0,0 +1,222 @@
+use std::{
+    error::Error,
+    future::poll_fn,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use futures_core::Stream;
+use mqttbytes::{v5::Publish, QoS};
+use qute::{Client, ClientBuilder, HandlerRouterBuilder, SubscriptionStream};
+use tokio::{sync::mpsc, task::JoinSet};
+
+use crate::{output, BenchArgs, PublishArgs, RequestArgs, SubscribeArgs};
+
+type Result<T = ()> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+type Builder = ClientBuilder<(String, u16)>;
+
+/// Time to wait for benchmark messages to come back after all of them were published.
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub(crate) async fn publish(builder: Builder, args: PublishArgs) -> Result {
+    let mut properties = args.message.properties.properties();
+    properties.response_topic = args.response_topic;
+    properties.correlation_data = args.correlation_data.map(Bytes::from);
+    let mut publish = Publish::from_bytes(
+        &args.message.topic,
+        args.message.qos,
+        args.message.payload()?,
+    );
+    publish.retain = args.retain;
+    publish.properties = Some(properties);
+
+    let client = connect(builder).await?;
+    let published = client.publish_message(publish).await;
+    client.shutdown().await;
+    Ok(published?)
+}
+
+pub(crate) async fn subscribe(builder: Builder, args: SubscribeArgs) -> Result {
+    let client = connect(builder).await?;
+    let (sender, mut received) = mpsc::unbounded_channel();
+    for topic in &args.topics {
+        let mut stream = client.subscribe_stream(topic, args.qos).await?;
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            while let Some(publish) = next(&mut stream).await {
+                if sender.send(publish).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+    drop(sender);
+
+    let mut remaining = args.count;
+    while remaining != Some(0) {
+        let publish = tokio::select! {
+            publish = received.recv() => publish,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        let Some(publish) = publish else {
+            break;
+        };
+        println!("{}", output::format(&publish, &args.output));
+        if let Some(remaining) = &mut remaining {
+            *remaining -= 1;
+        }
+    }
+
+    client.shutdown().await;
+    Ok(())
+}
+
+pub(crate) async fn request(builder: Builder, args: RequestArgs) -> Result {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let correlation_data = format!("{}-{}", std::process::id(), elapsed.as_nanos());
+    let response_topic = args
+        .response_topic
+        .unwrap_or_else(|| format!("qute/responses/{correlation_data}"));
+
+    let mut properties = args.message.properties.properties();
+    properties.response_topic = Some(response_topic.clone());
+    properties.correlation_data = Some(Bytes::from(correlation_data.clone()));
+    let mut publish = Publish::from_bytes(
+        &args.message.topic,
+        args.message.qos,
+        args.message.payload()?,
+    );
+    publish.properties = Some(properties);
+
+    let client = connect(builder).await?;
+    let mut responses = client
+        .subscribe_stream(&response_topic, QoS::AtLeastOnce)
+        .await?;
+    client.publish_message(publish).await?;
+
+    let response = tokio::time::timeout(Duration::from_secs(args.timeout), async {
+        loop {
+            let response = next(&mut responses).await?;
+            // Responses to other requests on a shared response topic are skipped.
+            let correlated = response
+                .properties
+                .as_ref()
+                .and_then(|properties| properties.correlation_data.as_ref())
+                .is_none_or(|data| data == correlation_data.as_bytes());
+            if correlated {
+                return Some(response);
+            }
+        }
+    })
+    .await;
+    drop(responses);
+    client.shutdown().await;
+
+    match response {
+        Ok(Some(response)) => {
+            println!("{}", output::format(&response, &args.output));
+            Ok(())
+        }
+        Ok(None) => Err(qute::Error::ConnectionClosed.into()),
+        Err(_) => Err(format!("no response within {} seconds", args.timeout).into()),
+    }
+}
+
+pub(crate) async fn bench(builder: Builder, args: BenchArgs) -> Result {
+    let client = connect(builder).await?;
+    let received = Arc::new(AtomicUsize::new(0));
+    let receiving = if args.receive {
+        let mut stream = client.subscribe_stream(&args.topic, args.qos).await?;
+        let received = received.clone();
+        let count = args.count;
+        Some(tokio::spawn(async move {
+            while received.load(Ordering::Relaxed) < count && next(&mut stream).await.is_some() {
+                received.fetch_add(1, Ordering::Relaxed);
+            }
+            Instant::now()
+        }))
+    } else {
+        None
+    };
+
+    let payload: Arc<[u8]> = vec![0; args.size].into();
+    let topic: Arc<str> = args.topic.into();
+    let next_message = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+    let mut publishers = JoinSet::new();
+    for _ in 0..args.concurrency.max(1) {
+        let (client, payload, topic, next_message) = (
+            client.clone(),
+            payload.clone(),
+            topic.clone(),
+            next_message.clone(),
+        );
+        publishers.spawn(async move {
+            let mut latencies = Vec::new();
+            while next_message.fetch_add(1, Ordering::Relaxed) < args.count {
+                let sent = Instant::now();
+                client.publish(&topic, args.qos, &payload).await?;
+                latencies.push(sent.elapsed());
+            }
+            Ok::<_, qute::Error>(latencies)
+        });
+    }
+    let mut latencies = Vec::with_capacity(args.count);
+    while let Some(published) = publishers.join_next().await {
+        latencies.extend(published??);
+    }
+    let elapsed = started.elapsed();
+
+    latencies.sort();
+    println!(
+        "published {} messages of {} bytes at QoS {} in {elapsed:.2?} ({:.0} messages/s)",
+        args.count,
+        args.size,
+        args.qos as u8,
+        args.count as f64 / elapsed.as_secs_f64(),
+    );
+    if !latencies.is_empty() {
+        let percentile = |percent: usize| latencies[(latencies.len() - 1) * percent / 100];
+        let average = latencies.iter().sum::<Duration>() / latencies.len() as u32;
+        println!(
+            "latency min {:.2?}, average {average:.2?}, p50 {:.2?}, p99 {:.2?}, max {:.2?}",
+            latencies[0],
+            percentile(50),
+            percentile(99),
+            latencies[latencies.len() - 1],
+        );
+    }
+
+    if let Some(receiving) = receiving {
+        match tokio::time::timeout(RECEIVE_TIMEOUT, receiving).await {
+            Ok(finished) => println!(
+                "received {} messages in {:.2?}",
+                received.load(Ordering::Relaxed),
+                finished? - started
+            ),
+            Err(_) => println!(
+                "received only {} of {} messages",
+                received.load(Ordering::Relaxed),
+                args.count
+            ),
+        }
+    }
+
+    client.shutdown().await;
+    Ok(())
+}
+
+async fn connect(builder: Builder) -> Result<Client> {
+    Ok(builder.build(HandlerRouterBuilder::new().build()).await?)
+}
+
+async fn next(stream: &mut SubscriptionStream) -> Option<Publish> {
+    poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await
+}
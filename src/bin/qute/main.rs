@@ -0,0 +1,328 @@
+//! Command-line client for publishing, subscribing and benchmarking a broker, built with the `cli` feature.
+
+use std::{path::PathBuf, process::ExitCode};
+
+use bytes::Bytes;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use mqttbytes::{
+    v5::{Login, PublishProperties},
+    Protocol, QoS,
+};
+use qute::{ClientBuilder, Error, TlsConfig};
+use tracing_subscriber::util::SubscriberInitExt;
+
+mod commands;
+mod output;
+
+#[derive(Parser)]
+#[command(
+    name = "qute",
+    version,
+    about = "MQTT client which behaves like services built with qute"
+)]
+struct Cli {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Publishes a single message.
+    Pub(PublishArgs),
+    /// Prints messages matching the topic filters until interrupted.
+    Sub(SubscribeArgs),
+    /// Publishes a request with a response topic and prints the first response.
+    Request(RequestArgs),
+    /// Publishes many messages and reports throughput and latency.
+    Bench(BenchArgs),
+}
+
+#[derive(Args)]
+struct ConnectionArgs {
+    /// Host name or address of the broker.
+    #[arg(short = 'H', long, default_value = "localhost", global = true)]
+    host: String,
+    /// Port of the broker, defaults to 1883 or 8883 with TLS.
+    #[arg(short, long, global = true)]
+    port: Option<u16>,
+    #[arg(short = 'i', long, global = true)]
+    client_id: Option<String>,
+    /// Keep alive in seconds.
+    #[arg(short, long, default_value_t = 60, global = true)]
+    keep_alive: u16,
+    #[arg(long, value_enum, default_value_t = MqttVersion::V5, global = true)]
+    mqtt_version: MqttVersion,
+    /// Session Expiry Interval in seconds, the session is kept by the broker after disconnecting.
+    #[arg(long, global = true)]
+    session_expiry: Option<u32>,
+    #[arg(short, long, global = true)]
+    username: Option<String>,
+    #[arg(short = 'P', long, requires = "username", global = true)]
+    password: Option<String>,
+    /// Connects over TLS, verifying the broker against the Mozilla root certificates unless `--cafile` is given.
+    #[arg(long, global = true)]
+    tls: bool,
+    /// PEM file with the certificate authorities trusted to sign the broker's certificate, implies `--tls`.
+    #[arg(long, global = true)]
+    cafile: Option<PathBuf>,
+    /// PEM file with the client certificate chain, implies `--tls`.
+    #[arg(long, requires = "key", global = true)]
+    cert: Option<PathBuf>,
+    /// PEM file with the private key of the client certificate.
+    #[arg(long, requires = "cert", global = true)]
+    key: Option<PathBuf>,
+    /// Name the broker's certificate is verified for, defaults to the host.
+    #[arg(long, global = true)]
+    tls_server_name: Option<String>,
+    /// Logs what the client does, e.g. the packets it sends and receives.
+    #[arg(short, long, global = true)]
+    debug: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum MqttVersion {
+    #[value(name = "3.1.1")]
+    V311,
+    #[value(name = "5")]
+    V5,
+}
+
+#[derive(Args)]
+struct MessageArgs {
+    #[arg(short, long)]
+    topic: String,
+    /// Payload of the message, read from standard input if neither this nor `--file` is given.
+    #[arg(short, long, conflicts_with = "file")]
+    message: Option<String>,
+    /// File with the payload of the message.
+    #[arg(short, long)]
+    file: Option<PathBuf>,
+    #[arg(short, long, default_value = "0", value_parser = parse_qos)]
+    qos: QoS,
+    #[command(flatten)]
+    properties: PropertyArgs,
+}
+
+#[derive(Args)]
+struct PropertyArgs {
+    #[arg(long)]
+    content_type: Option<String>,
+    /// Message Expiry Interval in seconds.
+    #[arg(long)]
+    message_expiry: Option<u32>,
+    /// Marks the payload as UTF-8 text.
+    #[arg(long)]
+    utf8: bool,
+    /// User property as `name=value`, can be repeated.
+    #[arg(long = "user-property", value_parser = parse_user_property)]
+    user_properties: Vec<(String, String)>,
+}
+
+#[derive(Args)]
+struct PublishArgs {
+    #[command(flatten)]
+    message: MessageArgs,
+    #[arg(short, long)]
+    retain: bool,
+    #[arg(long)]
+    response_topic: Option<String>,
+    #[arg(long)]
+    correlation_data: Option<String>,
+}
+
+#[derive(Args)]
+struct SubscribeArgs {
+    /// Topic filter, can be repeated.
+    #[arg(short, long = "topic", required = true)]
+    topics: Vec<String>,
+    #[arg(short, long, default_value = "2", value_parser = parse_qos)]
+    qos: QoS,
+    /// Exits after receiving this many messages.
+    #[arg(short = 'C', long)]
+    count: Option<usize>,
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Args)]
+struct RequestArgs {
+    #[command(flatten)]
+    message: MessageArgs,
+    /// Topic the response is expected on, defaults to one unique to this request.
+    #[arg(long)]
+    response_topic: Option<String>,
+    /// Seconds to wait for the response.
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    #[arg(short, long, default_value = "qute/bench")]
+    topic: String,
+    #[arg(short, long, default_value = "1", value_parser = parse_qos)]
+    qos: QoS,
+    /// Number of messages to publish.
+    #[arg(short = 'n', long, default_value_t = 10_000)]
+    count: usize,
+    /// Payload size in bytes.
+    #[arg(short, long, default_value_t = 64)]
+    size: usize,
+    /// Number of messages published at the same time.
+    #[arg(short, long, default_value_t = 16)]
+    concurrency: usize,
+    /// Also subscribes to the topic and waits until all messages are received back.
+    #[arg(long)]
+    receive: bool,
+}
+
+#[derive(Args)]
+struct OutputArgs {
+    /// Prints the topic before each payload.
+    #[arg(short, long)]
+    verbose: bool,
+    /// Prints each message as a JSON object with its topic, flags and properties.
+    #[arg(long)]
+    json: bool,
+    /// Pretty prints JSON payloads and objects.
+    #[arg(long)]
+    pretty: bool,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    if cli.connection.debug {
+        tracing_subscriber::fmt()
+            .with_env_filter("qute=debug")
+            .with_writer(std::io::stderr)
+            .finish()
+            .init();
+    }
+
+    let builder = match cli.connection.builder() {
+        Ok(builder) => builder,
+        Err(error) => {
+            eprintln!("error: {error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let result = match cli.command {
+        Command::Pub(args) => commands::publish(builder, args).await,
+        Command::Sub(args) => commands::subscribe(builder, args).await,
+        Command::Request(args) => commands::request(builder, args).await,
+        Command::Bench(args) => commands::bench(builder, args).await,
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+impl ConnectionArgs {
+    fn builder(&self) -> Result<ClientBuilder<(String, u16)>, Error> {
+        let tls = self.tls || self.cafile.is_some() || self.cert.is_some();
+        let port = self.port.unwrap_or(if tls { 8883 } else { 1883 });
+
+        let mut builder = ClientBuilder::new((self.host.clone(), port));
+        builder
+            .set_client_id(
+                self.client_id
+                    .clone()
+                    .unwrap_or_else(|| format!("qute-cli-{}", std::process::id())),
+            )
+            .set_keep_alive(self.keep_alive)
+            .set_protocol(match self.mqtt_version {
+                MqttVersion::V311 => Protocol::V4,
+                MqttVersion::V5 => Protocol::V5,
+            });
+        if let Some(session_expiry) = self.session_expiry {
+            builder
+                .set_clean_session(false)
+                .set_session_expiry(session_expiry);
+        }
+        if let Some(username) = &self.username {
+            builder.set_login(Login::new(
+                username,
+                &self.password.clone().unwrap_or_default(),
+            ));
+        }
+        if tls {
+            let server_name = self.tls_server_name.as_ref().unwrap_or(&self.host);
+            let mut config = match &self.cafile {
+                Some(cafile) => TlsConfig::with_ca_file(server_name, cafile)?,
+                None => TlsConfig::new(server_name),
+            };
+            if let (Some(cert), Some(key)) = (&self.cert, &self.key) {
+                config.set_client_certificate(cert, key)?;
+            }
+            builder.set_tls(config);
+        }
+
+        Ok(builder)
+    }
+}
+
+impl MessageArgs {
+    fn payload(&self) -> std::io::Result<Bytes> {
+        match (&self.message, &self.file) {
+            (Some(message), _) => Ok(Bytes::from(message.clone())),
+            (None, Some(file)) => std::fs::read(file).map(Bytes::from),
+            (None, None) => {
+                let mut payload = Vec::new();
+                std::io::Read::read_to_end(&mut std::io::stdin(), &mut payload)?;
+                Ok(Bytes::from(payload))
+            }
+        }
+    }
+}
+
+impl PropertyArgs {
+    fn properties(&self) -> PublishProperties {
+        PublishProperties {
+            payload_format_indicator: self.utf8.then_some(1),
+            message_expiry_interval: self.message_expiry,
+            topic_alias: None,
+            response_topic: None,
+            correlation_data: None,
+            user_properties: self.user_properties.clone(),
+            subscription_identifiers: Vec::new(),
+            content_type: self.content_type.clone(),
+        }
+    }
+}
+
+fn parse_qos(qos: &str) -> Result<QoS, String> {
+    match qos {
+        "0" => Ok(QoS::AtMostOnce),
+        "1" => Ok(QoS::AtLeastOnce),
+        "2" => Ok(QoS::ExactlyOnce),
+        _ => Err("QoS must be 0, 1 or 2".to_owned()),
+    }
+}
+
+fn parse_user_property(property: &str) -> Result<(String, String), String> {
+    let (name, value) = property
+        .split_once('=')
+        .ok_or_else(|| "user property must be `name=value`".to_owned())?;
+    Ok((name.to_owned(), value.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::Cli;
+
+    #[test]
+    fn arguments_are_consistent() {
+        Cli::command().debug_assert();
+    }
+}
@@ -0,0 +1,117 @@
+use mqttbytes::v5::Publish;
+use serde_json::{json, Map, Value};
+
+use crate::OutputArgs;
+
+pub(crate) fn format(publish: &Publish, args: &OutputArgs) -> String {
+    if args.json {
+        let message = json!({
+            "topic": publish.topic,
+            "qos": publish.qos as u8,
+            "retain": publish.retain,
+            "payload": payload_value(&publish.payload),
+            "properties": properties(publish),
+        });
+        return to_string(&message, args.pretty);
+    }
+
+    let payload = match serde_json::from_slice::<Value>(&publish.payload) {
+        Ok(value) if args.pretty => to_string(&value, true),
+        _ => String::from_utf8_lossy(&publish.payload).into_owned(),
+    };
+    if args.verbose {
+        format!("{} {payload}", publish.topic)
+    } else {
+        payload
+    }
+}
+
+fn to_string(value: &Value, pretty: bool) -> String {
+    let formatted = if pretty {
+        serde_json::to_string_pretty(value)
+    } else {
+        serde_json::to_string(value)
+    };
+    formatted.expect("JSON values are always serializable.")
+}
+
+// JSON payloads are embedded as they are, other text as a string and binary payloads as an array of bytes.
+fn payload_value(payload: &[u8]) -> Value {
+    serde_json::from_slice(payload).unwrap_or_else(|_| match std::str::from_utf8(payload) {
+        Ok(text) => Value::String(text.to_owned()),
+        Err(_) => json!(payload),
+    })
+}
+
+fn properties(publish: &Publish) -> Value {
+    let mut object = Map::new();
+    let Some(properties) = &publish.properties else {
+        return Value::Object(object);
+    };
+    if let Some(indicator) = properties.payload_format_indicator {
+        object.insert("payload_format_indicator".into(), indicator.into());
+    }
+    if let Some(interval) = properties.message_expiry_interval {
+        object.insert("message_expiry_interval".into(), interval.into());
+    }
+    if let Some(content_type) = &properties.content_type {
+        object.insert("content_type".into(), content_type.as_str().into());
+    }
+    if let Some(response_topic) = &properties.response_topic {
+        object.insert("response_topic".into(), response_topic.as_str().into());
+    }
+    if let Some(data) = &properties.correlation_data {
+        object.insert(
+            "correlation_data".into(),
+            String::from_utf8_lossy(data).into(),
+        );
+    }
+    if !properties.user_properties.is_empty() {
+        object.insert("user_properties".into(), json!(properties.user_properties));
+    }
+    if !properties.subscription_identifiers.is_empty() {
+        object.insert(
+            "subscription_identifiers".into(),
+            json!(properties.subscription_identifiers),
+        );
+    }
+    Value::Object(object)
+}
+
+#[cfg(test)]
+mod tests {
+    use mqttbytes::{v5::PublishProperties, QoS};
+
+    use super::*;
+
+    #[test]
+    fn json_payloads_are_pretty_printed() {
+        let mut publish = Publish::new("sensors/1", QoS::AtLeastOnce, r#"{"temperature":21}"#);
+        publish.properties = Some(PublishProperties {
+            payload_format_indicator: None,
+            message_expiry_interval: None,
+            topic_alias: None,
+            response_topic: None,
+            correlation_data: None,
+            user_properties: vec![("unit".to_owned(), "celsius".to_owned())],
+            subscription_identifiers: Vec::new(),
+            content_type: Some("application/json".to_owned()),
+        });
+        let mut args = OutputArgs {
+            verbose: true,
+            json: false,
+            pretty: true,
+        };
+        assert_eq!(
+            format(&publish, &args),
+            "sensors/1 {\n  \"temperature\": 21\n}"
+        );
+
+        args.json = true;
+        args.pretty = false;
+        assert_eq!(
+            format(&publish, &args),
+            r#"{"payload":{"temperature":21},"properties":{"content_type":"application/json","user_properties":[["unit","celsius"]]},"qos":1,"retain":false,"topic":"sensors/1"}"#
+        );
+    }
+}
@@ -0,0 +1,380 @@
+//! MQTT 5 broker which runs inside the application, e.g. in integration tests or small single-box deployments. Enabled by the `broker` feature.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use mqttbytes::{
+    v5::{ConnAck, ConnAckProperties, Connect, ConnectReturnCode, Packet},
+    Protocol,
+};
+use tokio::{
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::mpsc,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+use crate::connection::{Connection, Incoming, Reader, Writer, DEFAULT_MAX_PACKET_SIZE};
+
+use self::{
+    session::Command,
+    state::{BrokerState, Handled},
+};
+
+mod session;
+mod state;
+
+/// Time a client has to send CONNECT after opening the connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+type SharedState = Arc<Mutex<BrokerState>>;
+type BrokerConnection = Arc<Connection<Reader, Writer>>;
+
+/// MQTT 5 broker listening on a TCP address.
+///
+/// Supports QoS 0, 1 and 2, sessions which outlive the connection for their Session Expiry Interval, retained messages, wills and shared subscriptions (`$share/{group}/{filter}`). Enhanced authentication, topic aliases and the will delay are not supported.
+///
+/// The broker stops once it is shut down or dropped.
+pub struct Broker {
+    local_addr: SocketAddr,
+    state: SharedState,
+    shutdown: CancellationToken,
+    tasks: TaskTracker,
+}
+
+impl Broker {
+    /// Starts accepting clients, e.g. on `127.0.0.1:0` to let the system pick a free port.
+    pub async fn bind(address: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(address).await?;
+        let broker = Self {
+            local_addr: listener.local_addr()?,
+            state: SharedState::default(),
+            shutdown: CancellationToken::new(),
+            tasks: TaskTracker::new(),
+        };
+        broker.tasks.spawn(accept(
+            listener,
+            broker.state.clone(),
+            broker.shutdown.clone(),
+            broker.tasks.clone(),
+        ));
+        tracing::info!(address = %broker.local_addr, "Broker is listening.");
+
+        Ok(broker)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Identifiers of the connected clients.
+    pub fn clients(&self) -> Vec<String> {
+        lock(&self.state).clients()
+    }
+
+    /// Sends DISCONNECT to all clients and waits until their connections are closed.
+    pub async fn shutdown(self) {
+        lock(&self.state).shutdown();
+        self.shutdown.cancel();
+        self.tasks.close();
+        self.tasks.wait().await;
+    }
+}
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+fn lock(state: &SharedState) -> MutexGuard<'_, BrokerState> {
+    state.lock().expect("Broker state is never poisoned.")
+}
+
+async fn accept(
+    listener: TcpListener,
+    state: SharedState,
+    shutdown: CancellationToken,
+    tasks: TaskTracker,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            () = shutdown.cancelled() => return,
+        };
+        match accepted {
+            Ok((stream, address)) => {
+                tracing::debug!(%address, "Accepted connection.");
+                tasks.spawn(serve(
+                    stream,
+                    state.clone(),
+                    shutdown.clone(),
+                    tasks.clone(),
+                ));
+            }
+            Err(error) => tracing::warn!(%error, "Unable to accept connection."),
+        }
+    }
+}
+
+// Handles a single client connection until it closes.
+async fn serve(
+    stream: TcpStream,
+    state: SharedState,
+    shutdown: CancellationToken,
+    tasks: TaskTracker,
+) {
+    let connection = Arc::new(Connection::with_stream(
+        Box::new(stream),
+        DEFAULT_MAX_PACKET_SIZE,
+        Protocol::V5,
+    ));
+
+    let connect = match tokio::time::timeout(CONNECT_TIMEOUT, connection.recv()).await {
+        Ok(Ok(Some(Incoming::Packet(Packet::Connect(connect))))) => connect,
+        Ok(received) => {
+            tracing::warn!(
+                ?received,
+                "Client did not start with CONNECT, closing the connection."
+            );
+            return;
+        }
+        Err(_) => {
+            tracing::warn!("Client did not send CONNECT in time, closing the connection.");
+            return;
+        }
+    };
+    if let Some(code) = refusal(&connect) {
+        tracing::warn!(client_id = connect.client_id, ?code, "Refusing connection.");
+        let mut connack = ConnAck::new(code, false);
+        connack.properties = Some(ConnAckProperties::new());
+        if let Ok(send) = connection.send(&Packet::ConnAck(connack)) {
+            send.await;
+        }
+        let _ = connection.shutdown().await;
+        return;
+    }
+    connection.set_max_outgoing_size(
+        connect
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.max_packet_size),
+    );
+
+    let (sender, commands) = mpsc::unbounded_channel();
+    tasks.spawn(write(connection.clone(), commands));
+    let (link, client_id) = lock(&state).connect(&connect, sender);
+    // The broker waits one and a half times the keep alive for the next packet.
+    let keep_alive = match connect.keep_alive {
+        0 => None,
+        keep_alive => Some(Duration::from_secs(keep_alive.into()) * 3 / 2),
+    };
+
+    let will = loop {
+        let received = tokio::select! {
+            received = recv(&connection, keep_alive) => received,
+            () = shutdown.cancelled() => break false,
+        };
+        let packet = match received {
+            Some(Ok(Some(Incoming::Packet(packet)))) => packet,
+            Some(Ok(Some(Incoming::Auth(_)))) => {
+                tracing::warn!(client_id, "Enhanced authentication is not supported.");
+                break true;
+            }
+            Some(Ok(None)) => break true,
+            Some(Err(error)) => {
+                tracing::warn!(
+                    client_id,
+                    ?error,
+                    "Unable to read packet, closing the connection."
+                );
+                break true;
+            }
+            None => {
+                tracing::info!(
+                    client_id,
+                    "Client did not send anything within its keep alive."
+                );
+                break true;
+            }
+        };
+        if let Handled::Disconnect { will } = lock(&state).handle(&client_id, packet) {
+            break will;
+        }
+    };
+
+    let mut state = lock(&state);
+    state.disconnected(&client_id, link);
+    if let Some(last_will) = connect.last_will.filter(|_| will) {
+        state.publish_will(&client_id, last_will);
+    }
+}
+
+// `None` if nothing arrived within the keep alive.
+async fn recv(
+    connection: &BrokerConnection,
+    keep_alive: Option<Duration>,
+) -> Option<Result<Option<Incoming>, mqttbytes::Error>> {
+    match keep_alive {
+        Some(keep_alive) => tokio::time::timeout(keep_alive, connection.recv())
+            .await
+            .ok(),
+        None => Some(connection.recv().await),
+    }
+}
+
+// Writes packets in the order they were queued by the broker.
+async fn write(connection: BrokerConnection, mut commands: mpsc::UnboundedReceiver<Command>) {
+    while let Some(command) = commands.recv().await {
+        match command {
+            Command::Send(packet) => match connection.send(&packet) {
+                Ok(send) => send.await,
+                Err(error) => tracing::warn!(%error, "Unable to send packet to client."),
+            },
+            Command::Close => break,
+        }
+    }
+    let _ = connection.shutdown().await;
+}
+
+fn refusal(connect: &Connect) -> Option<ConnectReturnCode> {
+    let properties = connect.properties.as_ref()?;
+    if properties.authentication_method.is_some() {
+        return Some(ConnectReturnCode::BadAuthenticationMethod);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::{self, Ready};
+
+    use mqttbytes::{
+        v5::{LastWill, Publish},
+        QoS,
+    };
+
+    use super::*;
+    use crate::{ClientBuilder, HandlerRouterBuilder, ReconnectPolicy};
+
+    fn forward(sender: mpsc::UnboundedSender<Publish>) -> impl Fn(Publish) -> Ready<()> + Clone {
+        move |publish| {
+            let _ = sender.send(publish);
+            future::ready(())
+        }
+    }
+
+    #[tokio::test]
+    async fn delivers_retained_messages_and_wills() {
+        let broker = Broker::bind("127.0.0.1:0").await.unwrap();
+
+        let stream = TcpStream::connect(broker.local_addr()).await.unwrap();
+        let device =
+            Connection::with_stream(Box::new(stream), DEFAULT_MAX_PACKET_SIZE, Protocol::V5);
+        let mut connect = Connect::new("device");
+        connect.last_will = Some(LastWill::new(
+            "status/device",
+            "offline",
+            QoS::AtLeastOnce,
+            false,
+        ));
+        device.send(&Packet::Connect(connect)).unwrap().await;
+        let mut retained = Publish::new("config/device", QoS::AtMostOnce, "v1");
+        retained.retain = true;
+        device.send(&Packet::Publish(retained)).unwrap().await;
+
+        let (sender, mut received) = mpsc::unbounded_channel();
+        let mut router = HandlerRouterBuilder::new();
+        router.add("config/:id", forward(sender.clone()));
+        router.add("status/:id", forward(sender));
+        let monitor = ClientBuilder::new(broker.local_addr())
+            .build(router.build())
+            .await
+            .unwrap();
+
+        let config = received.recv().await.unwrap();
+        assert_eq!(
+            (config.topic.as_str(), config.retain),
+            ("config/device", true)
+        );
+
+        // The connection closes without DISCONNECT.
+        drop(device);
+        let will = received.recv().await.unwrap();
+        assert_eq!(will.topic, "status/device");
+        assert_eq!(will.payload, "offline");
+
+        monitor.shutdown().await;
+        broker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn balances_shared_subscriptions_and_resumes_sessions() {
+        let broker = Broker::bind("127.0.0.1:0").await.unwrap();
+
+        let (sender, mut received) = mpsc::unbounded_channel();
+        let mut workers = Vec::new();
+        for client_id in ["worker-1", "worker-2"] {
+            let mut router = HandlerRouterBuilder::new();
+            let sender = sender.clone();
+            router.fallback(move |publish: Publish| {
+                let _ = sender.send((client_id, publish));
+                future::ready(())
+            });
+            let mut builder = ClientBuilder::new(broker.local_addr());
+            builder
+                .set_client_id(client_id)
+                .set_clean_session(false)
+                .set_session_expiry(60)
+                .set_reconnect_policy(ReconnectPolicy::Never);
+            let worker = builder.build(router.build()).await.unwrap();
+            worker.subscribe("$share/workers/jobs").await.unwrap();
+            workers.push(worker);
+        }
+
+        workers[0].subscribe("reports").await.unwrap();
+
+        let producer = ClientBuilder::new(broker.local_addr())
+            .build(HandlerRouterBuilder::new().build())
+            .await
+            .unwrap();
+        for _ in 0..4 {
+            producer
+                .publish("jobs", QoS::AtLeastOnce, b"job")
+                .await
+                .unwrap();
+        }
+        let mut counts = [0, 0];
+        for _ in 0..4 {
+            let (client_id, _) = received.recv().await.unwrap();
+            counts[usize::from(client_id == "worker-2")] += 1;
+        }
+        assert_eq!(counts, [2, 2]);
+
+        // Messages for an offline session are sent once it reconnects.
+        for worker in workers {
+            worker.shutdown().await;
+        }
+        producer
+            .publish("reports", QoS::AtLeastOnce, b"queued")
+            .await
+            .unwrap();
+        let mut router = HandlerRouterBuilder::new();
+        router.fallback(move |publish: Publish| {
+            let _ = sender.send(("worker-1", publish));
+            future::ready(())
+        });
+        let mut builder = ClientBuilder::new(broker.local_addr());
+        builder.set_client_id("worker-1").set_clean_session(false);
+        let worker = builder.build(router.build()).await.unwrap();
+        let (_, publish) = received.recv().await.unwrap();
+        assert_eq!(publish.payload, "queued");
+
+        worker.shutdown().await;
+        producer.shutdown().await;
+        broker.shutdown().await;
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    time::Duration,
+};
+
+use mqttbytes::{
+    v5::{Packet, PubRel, Publish, SubscribeFilter},
+    QoS,
+};
+use tokio::{sync::mpsc, time::Instant};
+
+/// Messages kept for a client which is offline or has too many unacknowledged messages.
+const MAX_QUEUED: usize = 1000;
+
+#[allow(clippy::large_enum_variant)]
+pub(super) enum Command {
+    Send(Packet),
+    /// Closes the connection after everything before it was sent.
+    Close,
+}
+
+/// Network connection currently used by a session.
+pub(super) struct Link {
+    pub id: u64,
+    pub sender: mpsc::UnboundedSender<Command>,
+}
+
+#[allow(clippy::large_enum_variant)]
+enum Inflight {
+    Publish(Publish),
+    // PUBREL was sent, waiting for PUBCOMP.
+    Released,
+}
+
+pub(super) struct Session {
+    pub link: Option<Link>,
+    // Keyed by the topic filter including the `$share/{group}/` prefix.
+    pub subscriptions: HashMap<String, SubscribeFilter>,
+    // Received QoS 2 messages which were not released yet.
+    pub pending_rel: HashSet<u16>,
+    pub expiry_interval: u32,
+    inflight: BTreeMap<u16, Inflight>,
+    queued: VecDeque<Publish>,
+    next_pkid: u16,
+    receive_maximum: u16,
+    disconnected_at: Option<Instant>,
+}
+
+impl Session {
+    pub fn new(expiry_interval: u32) -> Self {
+        Self {
+            link: None,
+            subscriptions: HashMap::new(),
+            pending_rel: HashSet::new(),
+            expiry_interval,
+            inflight: BTreeMap::new(),
+            queued: VecDeque::new(),
+            next_pkid: 0,
+            receive_maximum: u16::MAX,
+            disconnected_at: None,
+        }
+    }
+
+    pub fn send(&self, packet: Packet) {
+        if let Some(link) = &self.link {
+            // The connection is already closing if nobody receives.
+            let _ = link.sender.send(Command::Send(packet));
+        }
+    }
+
+    /// Switches to a new connection, retransmits unacknowledged messages and sends queued ones.
+    pub fn attach(&mut self, link: Link, receive_maximum: u16) {
+        self.link = Some(link);
+        self.receive_maximum = receive_maximum;
+        self.disconnected_at = None;
+
+        for (pkid, inflight) in &self.inflight {
+            let packet = match inflight {
+                Inflight::Publish(publish) => {
+                    let mut publish = publish.clone();
+                    publish.dup = true;
+                    Packet::Publish(publish)
+                }
+                Inflight::Released => Packet::PubRel(PubRel::new(*pkid)),
+            };
+            self.send(packet);
+        }
+        self.drain();
+    }
+
+    /// Returns whether the connection was still used by the session.
+    pub fn detach(&mut self, link: u64) -> bool {
+        if !matches!(&self.link, Some(current) if current.id == link) {
+            return false;
+        }
+        self.link = None;
+        self.disconnected_at = Some(Instant::now());
+        true
+    }
+
+    pub fn expired(&self, now: Instant) -> bool {
+        match self.disconnected_at {
+            Some(_) if self.expiry_interval == u32::MAX => false,
+            Some(at) => now.duration_since(at) >= Duration::from_secs(self.expiry_interval.into()),
+            None => false,
+        }
+    }
+
+    pub fn deliver(&mut self, mut publish: Publish) {
+        if publish.qos == QoS::AtMostOnce {
+            // QoS 0 messages are not kept for offline clients.
+            self.send(Packet::Publish(publish));
+            return;
+        }
+        if self.link.is_none() || self.inflight.len() >= self.receive_maximum.into() {
+            if self.queued.len() >= MAX_QUEUED {
+                tracing::warn!(
+                    topic = publish.topic,
+                    "Session queue is full, dropping the oldest message."
+                );
+                self.queued.pop_front();
+            }
+            self.queued.push_back(publish);
+            return;
+        }
+
+        publish.pkid = self.next_pkid();
+        self.inflight
+            .insert(publish.pkid, Inflight::Publish(publish.clone()));
+        self.send(Packet::Publish(publish));
+    }
+
+    pub fn puback(&mut self, pkid: u16) {
+        if matches!(self.inflight.get(&pkid), Some(Inflight::Publish(_))) {
+            self.inflight.remove(&pkid);
+            self.drain();
+        }
+    }
+
+    /// Reason codes of 0x80 and above end the exchange.
+    pub fn pubrec(&mut self, pkid: u16, failed: bool) {
+        if failed {
+            self.puback(pkid);
+            return;
+        }
+        if let Some(inflight) = self.inflight.get_mut(&pkid) {
+            *inflight = Inflight::Released;
+        }
+        self.send(Packet::PubRel(PubRel::new(pkid)));
+    }
+
+    pub fn pubcomp(&mut self, pkid: u16) {
+        if matches!(self.inflight.get(&pkid), Some(Inflight::Released)) {
+            self.inflight.remove(&pkid);
+            self.drain();
+        }
+    }
+
+    fn drain(&mut self) {
+        while self.link.is_some() && self.inflight.len() < self.receive_maximum.into() {
+            let Some(publish) = self.queued.pop_front() else {
+                return;
+            };
+            self.deliver(publish);
+        }
+    }
+
+    fn next_pkid(&mut self) -> u16 {
+        loop {
+            self.next_pkid = self.next_pkid.checked_add(1).unwrap_or(1);
+            if !self.inflight.contains_key(&self.next_pkid) {
+                return self.next_pkid;
+            }
+        }
+    }
+}
@@ -0,0 +1,400 @@
+use std::collections::{BTreeMap, HashMap};
+
+use mqttbytes::{
+    v5::{
+        ConnAck, ConnAckProperties, Connect, ConnectReturnCode, Disconnect, DisconnectReasonCode,
+        LastWill, Packet, PubAck, PubComp, PubRec, Publish, PublishProperties, RetainForwardRule,
+        SubAck, Subscribe, SubscribeFilter, SubscribeReasonCode, UnsubAck, UnsubAckReason,
+        Unsubscribe,
+    },
+    QoS,
+};
+use tokio::{sync::mpsc, time::Instant};
+
+use crate::subscribe::router::topic_matches;
+
+use super::session::{Command, Link, Session};
+
+const SHARED_PREFIX: &str = "$share/";
+
+pub(super) enum Handled {
+    Continue,
+    /// The connection closes, the will is published unless the client asked otherwise.
+    Disconnect {
+        will: bool,
+    },
+}
+
+#[derive(Default)]
+pub(super) struct BrokerState {
+    sessions: HashMap<String, Session>,
+    retained: HashMap<String, Publish>,
+    // Number of messages delivered to each shared subscription, used to pick the next member in turn.
+    shared_turns: HashMap<String, usize>,
+    next_link: u64,
+    next_client_id: u64,
+}
+
+impl BrokerState {
+    /// Creates or resumes the session and sends CONNACK. Returns the identifier of the connection and the client.
+    pub fn connect(
+        &mut self,
+        connect: &Connect,
+        sender: mpsc::UnboundedSender<Command>,
+    ) -> (u64, String) {
+        let now = Instant::now();
+        self.sessions.retain(|_, session| !session.expired(now));
+
+        let mut properties = ConnAckProperties::new();
+        let client_id = if connect.client_id.is_empty() {
+            self.next_client_id += 1;
+            let client_id = format!("qute-broker-{}", self.next_client_id);
+            properties.assigned_client_identifier = Some(client_id.clone());
+            client_id
+        } else {
+            connect.client_id.clone()
+        };
+        let expiry_interval = connect
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.session_expiry_interval)
+            .unwrap_or(0);
+        let receive_maximum = connect
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.receive_maximum)
+            .unwrap_or(u16::MAX);
+
+        let mut existing = self.sessions.remove(&client_id);
+        if let Some(link) = existing.as_mut().and_then(|session| session.link.take()) {
+            tracing::info!(
+                client_id,
+                "Client connected again, closing its previous connection."
+            );
+            let mut disconnect = Disconnect::new();
+            disconnect.reason_code = DisconnectReasonCode::SessionTakenOver;
+            let _ = link
+                .sender
+                .send(Command::Send(Packet::Disconnect(disconnect)));
+            let _ = link.sender.send(Command::Close);
+        }
+        let resumed = existing.filter(|_| !connect.clean_session);
+        let session_present = resumed.is_some();
+        let mut session = resumed.unwrap_or_else(|| Session::new(expiry_interval));
+        session.expiry_interval = expiry_interval;
+
+        self.next_link += 1;
+        let link = Link {
+            id: self.next_link,
+            sender,
+        };
+        let mut connack = ConnAck::new(ConnectReturnCode::Success, session_present);
+        connack.properties = Some(properties);
+        let _ = link.sender.send(Command::Send(Packet::ConnAck(connack)));
+        session.attach(link, receive_maximum);
+        tracing::debug!(client_id, session_present, "Client connected.");
+        self.sessions.insert(client_id.clone(), session);
+
+        (self.next_link, client_id)
+    }
+
+    pub fn handle(&mut self, client_id: &str, packet: Packet) -> Handled {
+        let Some(session) = self.sessions.get_mut(client_id) else {
+            return Handled::Disconnect { will: true };
+        };
+
+        match packet {
+            Packet::Publish(publish) => {
+                if publish.topic.is_empty() || publish.topic.contains(['+', '#']) {
+                    tracing::warn!(client_id, topic = publish.topic, "Invalid topic name.");
+                    return disconnect(session, DisconnectReasonCode::TopicNameInvalid);
+                }
+                match publish.qos {
+                    QoS::AtMostOnce => {}
+                    QoS::AtLeastOnce => session.send(Packet::PubAck(PubAck::new(publish.pkid))),
+                    QoS::ExactlyOnce => {
+                        session.send(Packet::PubRec(PubRec::new(publish.pkid)));
+                        // A retransmitted message which was already received is not routed again.
+                        if !session.pending_rel.insert(publish.pkid) {
+                            return Handled::Continue;
+                        }
+                    }
+                }
+                self.publish(Some(client_id), publish);
+            }
+            Packet::PubRel(pubrel) => {
+                session.pending_rel.remove(&pubrel.pkid);
+                session.send(Packet::PubComp(PubComp::new(pubrel.pkid)));
+            }
+            Packet::PubAck(puback) => session.puback(puback.pkid),
+            Packet::PubRec(pubrec) => session.pubrec(pubrec.pkid, pubrec.reason as u8 >= 0x80),
+            Packet::PubComp(pubcomp) => session.pubcomp(pubcomp.pkid),
+            Packet::Subscribe(subscribe) => self.subscribe(client_id, subscribe),
+            Packet::Unsubscribe(unsubscribe) => {
+                let unsuback = unsubscribe_from(session, unsubscribe);
+                session.send(Packet::UnsubAck(unsuback));
+            }
+            Packet::PingReq => session.send(Packet::PingResp),
+            Packet::Disconnect(disconnect) => {
+                // The interval cannot be set once the session ends with the connection.
+                if let Some(interval) = disconnect
+                    .properties
+                    .and_then(|properties| properties.session_expiry_interval)
+                {
+                    if session.expiry_interval != 0 {
+                        session.expiry_interval = interval;
+                    }
+                }
+                return Handled::Disconnect {
+                    will: disconnect.reason_code == DisconnectReasonCode::DisconnectWithWillMessage,
+                };
+            }
+            packet => {
+                tracing::warn!(client_id, ?packet, "Unexpected packet, disconnecting.");
+                return disconnect(session, DisconnectReasonCode::ProtocolError);
+            }
+        }
+        Handled::Continue
+    }
+
+    fn subscribe(&mut self, client_id: &str, subscribe: Subscribe) {
+        let session = self
+            .sessions
+            .get_mut(client_id)
+            .expect("Only connected clients subscribe.");
+        let mut return_codes = Vec::new();
+        let mut retained_for = Vec::new();
+        for filter in subscribe.filters {
+            let shared = shared_group(&filter.path);
+            let valid = match shared {
+                Some((group, topic_filter)) => {
+                    !group.is_empty() && !group.contains(['+', '#']) && valid_filter(topic_filter)
+                }
+                None => valid_filter(&filter.path),
+            };
+            if !valid {
+                return_codes.push(SubscribeReasonCode::TopicFilterInvalid);
+                continue;
+            }
+
+            return_codes.push(match filter.qos {
+                QoS::AtMostOnce => SubscribeReasonCode::QoS0,
+                QoS::AtLeastOnce => SubscribeReasonCode::QoS1,
+                QoS::ExactlyOnce => SubscribeReasonCode::QoS2,
+            });
+            let existed = session
+                .subscriptions
+                .insert(filter.path.clone(), filter.clone())
+                .is_some();
+            // Retained messages are not sent for shared subscriptions.
+            let send_retained = shared.is_none()
+                && match filter.retain_forward_rule {
+                    RetainForwardRule::OnEverySubscribe => true,
+                    RetainForwardRule::OnNewSubscribe => !existed,
+                    RetainForwardRule::Never => false,
+                };
+            if send_retained {
+                retained_for.push(filter);
+            }
+        }
+        session.send(Packet::SubAck(SubAck::new(subscribe.pkid, return_codes)));
+
+        for filter in retained_for {
+            let matching: Vec<_> = self
+                .retained
+                .values()
+                .filter(|retained| topic_matches(&filter.path, &retained.topic))
+                .map(|retained| copy(retained, filter.qos, true))
+                .collect();
+            for publish in matching {
+                session.deliver(publish);
+            }
+        }
+    }
+
+    /// Stores the message if it is retained and delivers it to all matching subscriptions.
+    pub fn publish(&mut self, publisher: Option<&str>, publish: Publish) {
+        if publish.retain {
+            if publish.payload.is_empty() {
+                self.retained.remove(&publish.topic);
+            } else {
+                self.retained.insert(publish.topic.clone(), publish.clone());
+            }
+        }
+
+        let now = Instant::now();
+        // Members of each shared subscription with the QoS they subscribed with.
+        let mut groups: BTreeMap<&str, Vec<(&str, QoS)>> = BTreeMap::new();
+        let mut deliveries = Vec::new();
+        for (client_id, session) in &self.sessions {
+            if session.expired(now) {
+                continue;
+            }
+            let mut granted: Option<&SubscribeFilter> = None;
+            for (path, filter) in &session.subscriptions {
+                if let Some((_, topic_filter)) = shared_group(path) {
+                    if topic_matches(topic_filter, &publish.topic) {
+                        groups
+                            .entry(path)
+                            .or_default()
+                            .push((client_id, filter.qos));
+                    }
+                    continue;
+                }
+                if !topic_matches(path, &publish.topic)
+                    || (filter.nolocal && publisher == Some(client_id.as_str()))
+                {
+                    continue;
+                }
+                if granted.is_none_or(|granted| granted.qos < filter.qos) {
+                    granted = Some(filter);
+                }
+            }
+            if let Some(filter) = granted {
+                let retain = publish.retain && filter.preserve_retain;
+                deliveries.push((client_id.clone(), copy(&publish, filter.qos, retain)));
+            }
+        }
+
+        for (group, members) in groups {
+            // Connected members are preferred, offline ones get the message once they connect.
+            let connected: Vec<_> = members
+                .iter()
+                .filter(|(client_id, _)| self.sessions[*client_id].link.is_some())
+                .collect();
+            let candidates: Vec<_> = if connected.is_empty() {
+                members.iter().collect()
+            } else {
+                connected
+            };
+            let turn = self.shared_turns.entry(group.to_owned()).or_default();
+            let (client_id, qos) = candidates[*turn % candidates.len()];
+            *turn = turn.wrapping_add(1);
+            deliveries.push((client_id.to_string(), copy(&publish, *qos, false)));
+        }
+
+        for (client_id, publish) in deliveries {
+            if let Some(session) = self.sessions.get_mut(&client_id) {
+                session.deliver(publish);
+            }
+        }
+    }
+
+    pub fn publish_will(&mut self, client_id: &str, will: LastWill) {
+        tracing::debug!(client_id, topic = will.topic, "Publishing will message.");
+        let mut publish = Publish::from_bytes(will.topic, will.qos, will.message);
+        publish.retain = will.retain;
+        publish.properties = will.properties.map(|properties| PublishProperties {
+            payload_format_indicator: properties.payload_format_indicator,
+            message_expiry_interval: properties.message_expiry_interval,
+            topic_alias: None,
+            response_topic: properties.response_topic,
+            correlation_data: properties.correlation_data,
+            user_properties: properties.user_properties,
+            subscription_identifiers: Vec::new(),
+            content_type: properties.content_type,
+        });
+        self.publish(None, publish);
+    }
+
+    /// Called once the connection is closed. Sessions without an expiry interval end with their connection.
+    pub fn disconnected(&mut self, client_id: &str, link: u64) {
+        let Some(session) = self.sessions.get_mut(client_id) else {
+            return;
+        };
+        if session.detach(link) && session.expiry_interval == 0 {
+            self.sessions.remove(client_id);
+        }
+    }
+
+    pub fn clients(&self) -> Vec<String> {
+        self.sessions
+            .iter()
+            .filter(|(_, session)| session.link.is_some())
+            .map(|(client_id, _)| client_id.clone())
+            .collect()
+    }
+
+    /// Sends DISCONNECT to all connected clients and closes their connections.
+    pub fn shutdown(&mut self) {
+        for session in self.sessions.values() {
+            if let Some(link) = &session.link {
+                let mut disconnect = Disconnect::new();
+                disconnect.reason_code = DisconnectReasonCode::ServerShuttingDown;
+                let _ = link
+                    .sender
+                    .send(Command::Send(Packet::Disconnect(disconnect)));
+                let _ = link.sender.send(Command::Close);
+            }
+        }
+    }
+}
+
+fn disconnect(session: &Session, reason_code: DisconnectReasonCode) -> Handled {
+    let mut disconnect = Disconnect::new();
+    disconnect.reason_code = reason_code;
+    session.send(Packet::Disconnect(disconnect));
+    Handled::Disconnect { will: true }
+}
+
+fn unsubscribe_from(session: &mut Session, unsubscribe: Unsubscribe) -> UnsubAck {
+    let mut unsuback = UnsubAck::new(unsubscribe.pkid);
+    for filter in unsubscribe.filters {
+        unsuback
+            .reasons
+            .push(match session.subscriptions.remove(&filter) {
+                Some(_) => UnsubAckReason::Success,
+                None => UnsubAckReason::NoSubscriptionExisted,
+            });
+    }
+    unsuback
+}
+
+// Copy of the message sent to a subscriber, with at most the QoS it subscribed with.
+fn copy(publish: &Publish, qos: QoS, retain: bool) -> Publish {
+    let mut copy = Publish::from_bytes(
+        publish.topic.clone(),
+        if publish.qos > qos { qos } else { publish.qos },
+        publish.payload.clone(),
+    );
+    copy.retain = retain;
+    copy.properties = publish.properties.clone().map(|mut properties| {
+        properties.topic_alias = None;
+        properties.subscription_identifiers = Vec::new();
+        properties
+    });
+    copy
+}
+
+// Splits `$share/{group}/{filter}` into the group and the filter.
+fn shared_group(path: &str) -> Option<(&str, &str)> {
+    path.strip_prefix(SHARED_PREFIX)?.split_once('/')
+}
+
+fn valid_filter(filter: &str) -> bool {
+    let levels: Vec<_> = filter.split('/').collect();
+    !filter.is_empty()
+        && levels
+            .iter()
+            .enumerate()
+            .all(|(index, level)| match *level {
+                "+" => true,
+                "#" => index == levels.len() - 1,
+                level => !level.contains(['+', '#']),
+            })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_subscriptions_and_filters_are_validated() {
+        assert_eq!(shared_group("$share/group/a/+"), Some(("group", "a/+")));
+        assert_eq!(shared_group("a/b"), None);
+        assert!(valid_filter("a/+/#"));
+        assert!(!valid_filter("a/#/b"));
+        assert!(!valid_filter("a/b+"));
+        assert!(!valid_filter(""));
+    }
+}
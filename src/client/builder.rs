@@ -1,19 +1,59 @@
+use std::{fmt, future::Future, sync::Arc, time::Duration};
+
 use bytes::Bytes;
-use mqttbytes::v5::{Connect, ConnectProperties, LastWill, Login};
-use tokio::net::{TcpStream, ToSocketAddrs};
+use mqttbytes::{
+    v5::{Connect, ConnectProperties, LastWill, Login},
+    Protocol,
+};
+use tokio::net::ToSocketAddrs;
+
+use crate::{
+    auth::Authenticator,
+    connection::DEFAULT_MAX_PACKET_SIZE,
+    handlers::{
+        offline::{self, OverflowPolicy},
+        publish::FlowControl,
+    },
+    session::{MemoryStore, SessionStore, SharedSessionStore},
+    Client, Error, HandlerRouter,
+};
 
-use crate::{Client, HandlerRouter};
+use super::{
+    reconnect::{self, Endpoints},
+    ClientOptions, EndpointStrategy, Hooks, ReconnectPolicy, ServerDisconnect, Will,
+};
 
 pub struct ClientBuilder<Address: ToSocketAddrs> {
-    address: Address,
+    pub(super) address: Address,
+    endpoints: Vec<String>,
+    endpoint_strategy: EndpointStrategy,
+    connect_timeout: Duration,
     client_id: Option<String>,
     keep_alive: Option<u16>,
     clean_session: Option<bool>,
+    session_expiry: Option<u32>,
     last_will: Option<LastWill>,
     login: Option<Login>,
 
     authentication_method_and_data: Option<(String, Bytes)>,
     user_properties: Vec<(String, String)>,
+
+    session_store: Option<Box<dyn SessionStore>>,
+    flow_control: FlowControl,
+    receive_maximum: Option<u16>,
+    max_packet_size: Option<u32>,
+    topic_alias_maximum: Option<u16>,
+    outgoing_topic_aliases: bool,
+    handler_concurrency_limit: Option<usize>,
+    authenticator: Option<Box<dyn Authenticator>>,
+    reconnect_policy: ReconnectPolicy,
+    hooks: Hooks,
+    offline_queue_capacity: usize,
+    overflow_policy: OverflowPolicy,
+    persist_offline_queue: bool,
+    protocol: Protocol,
+    #[cfg(feature = "tls")]
+    tls: Option<super::TlsConfig>,
 }
 
 impl<Address> ClientBuilder<Address>
@@ -23,16 +63,70 @@ where
     pub fn new(address: Address) -> Self {
         Self {
             address,
+            endpoints: Vec::new(),
+            endpoint_strategy: EndpointStrategy::default(),
+            connect_timeout: reconnect::DEFAULT_CONNECT_TIMEOUT,
             client_id: None,
             keep_alive: None,
             clean_session: None,
+            session_expiry: None,
             last_will: None,
             login: None,
             authentication_method_and_data: None,
             user_properties: Vec::new(),
+            session_store: None,
+            flow_control: FlowControl::default(),
+            receive_maximum: None,
+            max_packet_size: None,
+            topic_alias_maximum: None,
+            outgoing_topic_aliases: false,
+            handler_concurrency_limit: None,
+            authenticator: None,
+            reconnect_policy: ReconnectPolicy::default(),
+            hooks: Hooks::default(),
+            offline_queue_capacity: offline::DEFAULT_CAPACITY,
+            overflow_policy: OverflowPolicy::default(),
+            persist_offline_queue: false,
+            protocol: Protocol::V5,
+            #[cfg(feature = "tls")]
+            tls: None,
         }
     }
 
+    /// Adds another broker of a cluster as `host:port`, the address the builder was created with is the first endpoint.
+    ///
+    /// The client connects to the first reachable endpoint in the order given by [`ClientBuilder::set_endpoint_strategy`], both initially and when reconnecting.
+    pub fn add_endpoint(&mut self, address: impl Into<String>) -> &mut Self {
+        self.endpoints.push(address.into());
+        self
+    }
+
+    pub fn set_endpoint_strategy(&mut self, strategy: EndpointStrategy) -> &mut Self {
+        self.endpoint_strategy = strategy;
+        self
+    }
+
+    /// How long opening a connection to an endpoint may take, including the TLS handshake. Defaults to 10 seconds.
+    pub fn set_connect_timeout(&mut self, connect_timeout: Duration) -> &mut Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Connects to the broker over TLS, also after a redirect.
+    #[cfg(feature = "tls")]
+    pub fn set_tls(&mut self, tls: super::TlsConfig) -> &mut Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// MQTT version spoken to the broker, [`Protocol::V4`] is MQTT 3.1.1. Defaults to [`Protocol::V5`].
+    ///
+    /// Properties and reason codes are not sent with 3.1.1, e.g. a DISCONNECT always discards the will. Enhanced authentication fails with [`Error::UnsupportedByProtocol`](crate::Error::UnsupportedByProtocol).
+    pub fn set_protocol(&mut self, protocol: Protocol) -> &mut Self {
+        self.protocol = protocol;
+        self
+    }
+
     pub fn set_client_id(&mut self, client_id: impl Into<String>) -> &mut Self {
         self.client_id = Some(client_id.into());
         self
@@ -48,6 +142,19 @@ where
         self
     }
 
+    /// Number of seconds the broker keeps the session after the connection is closed. `u32::MAX` means the session never expires.
+    pub fn set_session_expiry(&mut self, session_expiry: u32) -> &mut Self {
+        self.session_expiry = Some(session_expiry);
+        self
+    }
+
+    /// Message the broker publishes if the connection closes unexpectedly.
+    pub fn set_will(&mut self, will: Will) -> &mut Self {
+        self.last_will = Some(will.into());
+        self
+    }
+
+    /// Like [`ClientBuilder::set_will`] for a will already encoded as a packet.
     pub fn set_last_will(&mut self, last_will: LastWill) -> &mut Self {
         self.last_will = Some(last_will);
         self
@@ -63,6 +170,12 @@ where
         self
     }
 
+    /// Enhanced authentication, takes precedence over [`set_authentication_method_and_data`](Self::set_authentication_method_and_data).
+    pub fn set_authenticator(&mut self, authenticator: impl Authenticator) -> &mut Self {
+        self.authenticator = Some(Box::new(authenticator));
+        self
+    }
+
     pub fn set_user_properties(
         &mut self,
         properties: impl IntoIterator<Item = (String, String)>,
@@ -71,11 +184,151 @@ where
         self
     }
 
-    pub async fn build(self, publish_router: HandlerRouter) -> Client {
-        let stream = TcpStream::connect(self.address).await.unwrap();
+    /// Store used to persist in-flight messages, use together with `set_clean_session(false)` and `set_session_expiry`. Defaults to [`MemoryStore`].
+    pub fn set_session_store(&mut self, store: impl SessionStore) -> &mut Self {
+        self.session_store = Some(Box::new(store));
+        self
+    }
+
+    pub fn set_flow_control(&mut self, flow_control: FlowControl) -> &mut Self {
+        self.flow_control = flow_control;
+        self
+    }
+
+    /// Maximum number of unacknowledged QoS 1 and 2 messages the broker may send at once. Must not be zero.
+    ///
+    /// The broker is disconnected if it exceeds this limit.
+    pub fn set_receive_maximum(&mut self, receive_maximum: u16) -> &mut Self {
+        self.receive_maximum = Some(receive_maximum);
+        self
+    }
+
+    /// Maximum size in bytes of packets the broker may send. Defaults to 1 MiB.
+    ///
+    /// The broker is disconnected if it sends a larger packet.
+    pub fn set_max_packet_size(&mut self, max_packet_size: u32) -> &mut Self {
+        self.max_packet_size = Some(max_packet_size);
+        self
+    }
+
+    /// Number of topic aliases the broker may use in messages it sends. Defaults to 0, i.e. no aliases.
+    pub fn set_topic_alias_maximum(&mut self, topic_alias_maximum: u16) -> &mut Self {
+        self.topic_alias_maximum = Some(topic_alias_maximum);
+        self
+    }
+
+    /// Replace topics of published messages by aliases, up to the number of aliases the broker allows.
+    ///
+    /// Once all aliases are taken, the least recently used one is reassigned to the next new topic.
+    pub fn set_outgoing_topic_aliases(&mut self, enabled: bool) -> &mut Self {
+        self.outgoing_topic_aliases = enabled;
+        self
+    }
+
+    /// Maximum number of messages handled at once. Must not be zero.
+    ///
+    /// Further messages wait until a handler finishes while the connection keeps being read, the broker's sending is bounded by [`set_receive_maximum`](Self::set_receive_maximum).
+    /// Once 1024 messages wait, the connection is not read until one of them is handled, so handlers waiting for acknowledgements of their own messages stall until then.
+    pub fn set_handler_concurrency_limit(&mut self, limit: usize) -> &mut Self {
+        self.handler_concurrency_limit = Some(limit);
+        self
+    }
+
+    /// Whether to connect again after the connection is lost. Defaults to [`ReconnectPolicy::Never`].
+    ///
+    /// Redirects to another server sent by the broker in its DISCONNECT are followed.
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) -> &mut Self {
+        self.reconnect_policy = policy;
+        self
+    }
+
+    /// Number of messages published while the client is offline which are kept until it connects. Defaults to 1000 with [`OverflowPolicy::Error`].
+    ///
+    /// A capacity of zero disables the queue, messages are then written to the connection even if it is closed.
+    pub fn set_offline_queue(&mut self, capacity: usize, overflow: OverflowPolicy) -> &mut Self {
+        self.offline_queue_capacity = capacity;
+        self.overflow_policy = overflow;
+        self
+    }
+
+    /// Keep queued messages in the session store so that they are sent even after the process restarts.
+    ///
+    /// Every queued and sent message updates the store. [`FileStore`](crate::FileStore) rewrites its whole file on each
+    /// update, so filling a large queue with it is quadratic in the queue length.
+    pub fn set_persist_offline_queue(&mut self, persist: bool) -> &mut Self {
+        self.persist_offline_queue = persist;
+        self
+    }
+
+    /// Runs after every successful (re)connect, e.g. to publish a birth message.
+    pub fn on_connect<F, Fut>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(Client) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.hooks.on_connect = Some(Arc::new(move |client| Box::pin(hook(client))));
+        self
+    }
+
+    /// Runs whenever the connection closes, with the broker's DISCONNECT if it sent one.
+    pub fn on_disconnect<F, Fut>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(Option<ServerDisconnect>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.hooks.on_disconnect = Some(Arc::new(move |disconnect| Box::pin(hook(disconnect))));
+        self
+    }
+
+    /// Connects to the broker, failing if a limit is zero, the authenticator cannot start or none of the endpoints can be
+    /// reached.
+    ///
+    /// The address is resolved again on every attempt, so reconnecting follows DNS changes.
+    pub async fn build(self, publish_router: HandlerRouter) -> Result<Client, Error>
+    where
+        Address: Clone + fmt::Debug + Send + Sync + 'static,
+    {
+        let endpoints = Endpoints::new(self.address.clone());
+        self.build_with(publish_router, endpoints).await
+    }
+
+    #[cfg(feature = "testing")]
+    pub(crate) fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Connects through the given endpoints instead of the builder's address.
+    pub(crate) async fn build_with(
+        self,
+        publish_router: HandlerRouter,
+        mut endpoints: Endpoints,
+    ) -> Result<Client, Error> {
+        if self.receive_maximum == Some(0) {
+            return Err(Error::InvalidConfig(
+                "receive maximum must not be zero".to_owned(),
+            ));
+        }
+        if self.handler_concurrency_limit == Some(0) {
+            return Err(Error::InvalidConfig(
+                "handler concurrency limit must not be zero".to_owned(),
+            ));
+        }
+        publish_router.validate()?;
+
+        for address in self.endpoints {
+            endpoints.add(address);
+        }
+        endpoints.set_strategy(self.endpoint_strategy);
+        endpoints.set_connect_timeout(self.connect_timeout);
+        #[cfg(feature = "tls")]
+        if let Some(tls) = self.tls {
+            endpoints.set_tls(tls);
+        }
+        let stream = endpoints.connect().await.map_err(Error::Connect)?;
 
         let client_id = self.client_id.unwrap_or_else(|| "qute".to_owned());
         let mut connect = Connect::new(client_id);
+        connect.protocol = self.protocol;
 
         if let Some(keep_alive) = self.keep_alive {
             connect.keep_alive = keep_alive;
@@ -86,11 +339,13 @@ where
         connect.last_will = self.last_will;
         connect.login = self.login;
 
+        let max_packet_size = self.max_packet_size.unwrap_or(DEFAULT_MAX_PACKET_SIZE);
+
         let mut properties = ConnectProperties {
-            session_expiry_interval: None,  // defaults to 0
-            receive_maximum: None,          // defaults to 65,535
-            max_packet_size: None,          // defaults to no limit
-            topic_alias_max: None,          // defaults to 0, i.e. no aliases allowed
+            session_expiry_interval: self.session_expiry, // defaults to 0
+            receive_maximum: self.receive_maximum,        // defaults to 65,535
+            max_packet_size: Some(max_packet_size),
+            topic_alias_max: self.topic_alias_maximum, // defaults to 0, i.e. no aliases allowed
             request_response_info: Some(1), // Allow response information from the server in CONNACK
             request_problem_info: Some(1),  // Allow request problem information on all packets
             user_properties: self.user_properties,
@@ -98,6 +353,14 @@ where
             authentication_data: None,
         };
 
+        let mut authenticator = self.authenticator;
+        if self.protocol == Protocol::V4
+            && (authenticator.is_some() || self.authentication_method_and_data.is_some())
+        {
+            tracing::error!("Enhanced authentication requires MQTT 5, connecting without it.");
+            authenticator = None;
+        }
+
         if let Some((method, data)) = self.authentication_method_and_data {
             properties.authentication_method = Some(method);
             properties.authentication_data = Some(data);
@@ -105,6 +368,32 @@ where
 
         connect.properties = Some(properties);
 
-        Client::connect(stream, publish_router, connect).await
+        let store = self
+            .session_store
+            .unwrap_or_else(|| Box::new(MemoryStore::new()));
+
+        let options = ClientOptions {
+            store: SharedSessionStore::new(store),
+            flow_control: self.flow_control,
+            // A 3.1.1 broker does not know about the limit and must not be disconnected for exceeding it.
+            receive_maximum: match self.protocol {
+                Protocol::V4 => u16::MAX,
+                Protocol::V5 => self.receive_maximum.unwrap_or(u16::MAX),
+            },
+            max_packet_size,
+            topic_alias_maximum: self.topic_alias_maximum.unwrap_or(0),
+            outgoing_topic_aliases: self.outgoing_topic_aliases,
+            handler_concurrency_limit: self.handler_concurrency_limit,
+            authenticator,
+            reconnect_policy: self.reconnect_policy,
+            hooks: self.hooks,
+            keep_alive: connect.keep_alive,
+            offline_queue_capacity: self.offline_queue_capacity,
+            overflow_policy: self.overflow_policy,
+            persist_offline_queue: self.persist_offline_queue,
+            protocol: self.protocol,
+        };
+
+        Client::connect(stream, endpoints, publish_router, connect, options).await
     }
 }
@@ -0,0 +1,125 @@
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use futures_core::Stream;
+use mqttbytes::v5::{ConnectReturnCode, Disconnect, DisconnectReasonCode};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_util::sync::ReusableBoxFuture;
+
+use crate::Error;
+
+pub(crate) const CAPACITY: usize = 16;
+
+/// Changes of the connection to the broker, see [`Client::events`](super::Client::events).
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum ConnectionEvent {
+    /// The broker accepted the connection, sent after every (re)connect.
+    Connected { session_present: bool },
+    /// The broker refused the connection in its CONNACK.
+    ConnectionRefused { code: ConnectReturnCode },
+    /// The broker sent a DISCONNECT and is closing the connection.
+    ServerDisconnect(ServerDisconnect),
+    /// The broker did not answer a ping in time, the connection is closed.
+    KeepAliveTimeout,
+    /// The connection closed without the client asking for it.
+    ConnectionLost,
+    /// The client waits before its attempt to connect again, starting at 0.
+    Reconnecting { attempt: u32 },
+    /// The client disconnected and will not connect again.
+    Disconnected,
+}
+
+/// State of the connection to the broker, see [`Client::state`](super::Client::state).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// CONNECT was sent and the client waits for CONNACK.
+    Connecting,
+    Connected,
+    /// The connection was lost and the client reconnects if its [`ReconnectPolicy`](super::ReconnectPolicy) allows it.
+    Reconnecting,
+    Disconnected,
+}
+
+/// Contents of a DISCONNECT sent by the broker.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerDisconnect {
+    pub reason_code: DisconnectReasonCode,
+    pub reason_string: Option<String>,
+    /// Another broker to use, sent together with [`DisconnectReasonCode::UseAnotherServer`] or [`DisconnectReasonCode::ServerMoved`].
+    pub server_reference: Option<String>,
+    pub session_expiry_interval: Option<u32>,
+    pub user_properties: Vec<(String, String)>,
+}
+
+impl ServerDisconnect {
+    /// Error with which pending operations fail.
+    pub(crate) fn error(&self) -> Error {
+        Error::Disconnected {
+            reason_code: self.reason_code,
+            reason_string: self.reason_string.clone(),
+        }
+    }
+}
+
+impl From<Disconnect> for ServerDisconnect {
+    fn from(disconnect: Disconnect) -> Self {
+        let mut server_disconnect = Self {
+            reason_code: disconnect.reason_code,
+            reason_string: None,
+            server_reference: None,
+            session_expiry_interval: None,
+            user_properties: Vec::new(),
+        };
+        if let Some(properties) = disconnect.properties {
+            server_disconnect.reason_string = properties.reason_string;
+            server_disconnect.server_reference = properties.server_reference;
+            server_disconnect.session_expiry_interval = properties.session_expiry_interval;
+            server_disconnect.user_properties = properties.user_properties;
+        }
+        server_disconnect
+    }
+}
+
+type Recv = (
+    Result<ConnectionEvent, RecvError>,
+    broadcast::Receiver<ConnectionEvent>,
+);
+
+async fn recv(mut receiver: broadcast::Receiver<ConnectionEvent>) -> Recv {
+    let result = receiver.recv().await;
+    (result, receiver)
+}
+
+pub(crate) struct ConnectionEvents {
+    inner: ReusableBoxFuture<'static, Recv>,
+}
+
+impl ConnectionEvents {
+    pub(crate) fn new(receiver: broadcast::Receiver<ConnectionEvent>) -> Self {
+        Self {
+            inner: ReusableBoxFuture::new(recv(receiver)),
+        }
+    }
+}
+
+impl Stream for ConnectionEvents {
+    type Item = ConnectionEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            let (result, receiver) = ready!(this.inner.poll(cx));
+            this.inner.set(recv(receiver));
+            match result {
+                Ok(event) => return Poll::Ready(Some(event)),
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "Connection events were not consumed fast enough.");
+                }
+                Err(RecvError::Closed) => return Poll::Ready(None),
+            }
+        }
+    }
+}
@@ -1,68 +1,173 @@
-use std::sync::Arc;
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
 
+use futures_core::Stream;
 use mqttbytes::{
-    v5::{Connect, Disconnect, Packet, Publish, Subscribe, SubscribeFilter},
-    QoS,
+    v5::{
+        Connect, Disconnect, DisconnectProperties, DisconnectReasonCode, Packet, Publish,
+        PublishProperties, RetainForwardRule, Subscribe, SubscribeFilter,
+    },
+    Protocol, QoS,
 };
-use regex::Regex;
-use tokio::net::{
-    tcp::{OwnedReadHalf, OwnedWriteHalf},
-    TcpStream,
+use tokio::{
+    sync::{mpsc, Notify, Semaphore},
+    time::Instant,
 };
-use tokio_util::task::TaskTracker;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
 use crate::{
-    connection::Connection,
+    auth::Authenticator,
+    client::{event::ConnectionEvents, reconnect::Endpoints},
+    connection::{self, BoxedTransport, Connection, Incoming, Reader, Writer},
+    handlers::{
+        offline::OverflowPolicy,
+        publish::{FlowControl, PendingAcks},
+    },
     router::{Publisher, Router, Subscriber},
-    subscribe::router::HandlerRouter,
+    session::SharedSessionStore,
+    subscribe::router::{filter_to_route, route_to_filter, HandlerRouter, MessageOrder},
+    Ack, Error, Handler,
 };
 
 pub use builder::ClientBuilder;
+pub use config::{ClientConfig, OfflineQueueConfig, ReconnectConfig, TlsFiles, WillConfig};
+pub use event::{ConnectionEvent, ConnectionState, ServerDisconnect};
+pub use reconnect::{EndpointStrategy, ReconnectPolicy};
+pub use stream::SubscriptionStream;
+#[cfg(feature = "tls")]
+pub use tls::TlsConfig;
+pub use will::Will;
 
 mod builder;
+mod config;
+pub(crate) mod event;
+pub(crate) mod reconnect;
+mod stream;
+#[cfg(feature = "tls")]
+mod tls;
+mod will;
+
+pub(crate) struct ClientOptions {
+    pub store: SharedSessionStore,
+    pub flow_control: FlowControl,
+    pub receive_maximum: u16,
+    pub max_packet_size: u32,
+    pub topic_alias_maximum: u16,
+    pub outgoing_topic_aliases: bool,
+    pub handler_concurrency_limit: Option<usize>,
+    pub authenticator: Option<Box<dyn Authenticator>>,
+    pub reconnect_policy: ReconnectPolicy,
+    pub hooks: Hooks,
+    pub keep_alive: u16,
+    pub offline_queue_capacity: usize,
+    pub overflow_policy: OverflowPolicy,
+    pub persist_offline_queue: bool,
+    pub protocol: Protocol,
+}
+
+type ConnectHook = Arc<dyn Fn(Client) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+type DisconnectHook =
+    Arc<dyn Fn(Option<ServerDisconnect>) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    pub on_connect: Option<ConnectHook>,
+    pub on_disconnect: Option<DisconnectHook>,
+}
+
+impl Hooks {
+    // Also sends the messages queued while the client was offline.
+    fn connected(&self, client: &Client) {
+        client.tracker.spawn({
+            let publisher = client.router.publisher();
+            let tracker = client.tracker.clone();
+            async move { publisher.flush_offline(&tracker).await }
+        });
+        if let Some(on_connect) = &self.on_connect {
+            client.tracker.spawn(on_connect(client.clone()));
+        }
+    }
+
+    async fn disconnected(&self, disconnect: Option<ServerDisconnect>) {
+        if let Some(on_disconnect) = &self.on_disconnect {
+            on_disconnect(disconnect).await;
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Client {
-    router: Router<OwnedReadHalf, OwnedWriteHalf>,
+    router: Router<Reader, Writer>,
     tracker: TaskTracker,
 }
 
 impl Client {
     async fn connect(
-        tcp_stream: TcpStream,
+        stream: BoxedTransport,
+        endpoints: Endpoints,
         publish_router: HandlerRouter,
         connect: Connect,
-    ) -> Self {
-        let connection = Arc::new(Connection::with_stream(tcp_stream));
-
-        let single_wildcard = Regex::new(":[^/]*").unwrap();
-        let multiple_wildcard = Regex::new(r"\*[^/]*$").unwrap();
+        options: ClientOptions,
+    ) -> Result<Self, Error> {
+        let connection = Arc::new(Connection::with_stream(
+            stream,
+            options.max_packet_size,
+            options.protocol,
+        ));
 
         let to_subscribe: Vec<_> = publish_router
             .get_routes()
-            .into_iter()
-            .map(|route| {
-                let replaced = single_wildcard.replace_all(&route, "+");
-                let replaced = multiple_wildcard.replace(&replaced, "#");
-                replaced.into_owned()
-            })
+            .iter()
+            .map(|route| route_to_filter(route))
             .collect();
-        let router = Router::new(connection.clone(), publish_router);
+        let handler_limit = options
+            .handler_concurrency_limit
+            .map(|limit| Arc::new(Semaphore::new(limit)));
+        let waiting = Arc::new(Semaphore::new(MAX_WAITING_MESSAGES));
+        let reconnect_policy = options.reconnect_policy.clone();
+        let hooks = options.hooks.clone();
+        let router = Router::new(connection, publish_router, options);
         let tracker = TaskTracker::new();
+        let mut authenticated = connect.clone();
+        router.auth.lock().await.connect(&mut authenticated)?;
 
         tokio::spawn({
             let router = router.clone();
             let tracker = tracker.clone();
+            let connect = connect.clone();
+            let hooks = hooks.clone();
+            let mut endpoints = endpoints;
             async move {
-                while let Some(packet) = connection.recv().await.unwrap() {
-                    tracker.spawn({
-                        let router = router.clone();
-                        async move {
-                            router.route_received(packet).await;
-                        }
-                    });
+                loop {
+                    receive(&router, &tracker, &handler_limit, &waiting).await;
+                    tracing::debug!("Connection closed.");
+
+                    if router.connect.lock().await.closed_by_client() {
+                        hooks.disconnected(None).await;
+                        break;
+                    }
+                    router.event(ConnectionEvent::ConnectionLost);
+                    if router.connect.lock().await.refused() {
+                        endpoints.refused();
+                    }
+                    let disconnect = router.connection_lost().await;
+                    if let Some(disconnect) = &disconnect {
+                        endpoints.redirect(disconnect);
+                    }
+                    hooks.disconnected(disconnect).await;
+
+                    let client = Client {
+                        router: router.clone(),
+                        tracker: tracker.clone(),
+                    };
+                    if !reconnect(&client, &mut endpoints, &reconnect_policy, &connect, &hooks)
+                        .await
+                    {
+                        break;
+                    }
                 }
-                tracing::debug!("Connection closed.");
+                router.connect.lock().await.closed();
+                router.offline.lock().await.closed();
+                router.event(ConnectionEvent::Disconnected);
 
                 tracker.close();
                 tracker.wait().await;
@@ -71,41 +176,1977 @@ impl Client {
             }
         });
 
-        let connect = Packet::Connect(connect);
-        router.route_sent(connect).await;
+        router.route_sent(Packet::Connect(authenticated)).await;
+
+        let connect = router.connect.lock().await;
+        let (connected, session_present) = (connect.is_connected(), connect.session_present());
+        drop(connect);
 
-        let subscribe = Packet::Subscribe(Subscribe::new_many(
+        let mut subscribe = Subscribe::new_many(
             to_subscribe
                 .into_iter()
                 .map(|topic| SubscribeFilter::new(topic, QoS::ExactlyOnce)),
-        ));
-        router.route_sent(subscribe).await;
+        );
+        if session_present {
+            // The resumed session may lack routes added since it was created, but subscriptions are not persisted so
+            // it is unknown which. Subscribing again keeps existing subscriptions and their retained messages are not resent.
+            for filter in &mut subscribe.filters {
+                filter.set_retain_forward_rule(RetainForwardRule::OnNewSubscribe);
+            }
+        }
+        if !connected {
+            tracing::warn!("Broker did not accept the connection.");
+            // Subscribed to once the client reconnects.
+            router.subscribe.lock().await.remember(&subscribe);
+        } else if !subscribe.filters.is_empty() {
+            if let Err(error) = router.subscriber().send_subscribe(subscribe).await {
+                tracing::error!(%error, "Unable to subscribe to routes.");
+            }
+        }
 
-        Self { router, tracker }
+        let client = Self { router, tracker };
+        if connected {
+            hooks.connected(&client);
+        }
+        Ok(client)
     }
 
-    pub async fn publish(&self, topic: &str, qos: QoS, payload: &[u8]) {
-        let packet = Packet::Publish(Publish::new(topic, qos, payload));
+    #[cfg(feature = "testing")]
+    pub(crate) fn router(&self) -> &Router<Reader, Writer> {
+        &self.router
+    }
 
-        self.router.route_sent(packet).await;
+    pub async fn state(&self) -> ConnectionState {
+        self.router.connect.lock().await.state()
     }
 
-    pub async fn subscribe(&self, topic: &str) {
-        let packet = Packet::Subscribe(Subscribe::new(topic, QoS::ExactlyOnce));
+    /// Changes of the connection to the broker. Only events which happen after this is called are yielded.
+    pub fn events(&self) -> impl Stream<Item = ConnectionEvent> {
+        ConnectionEvents::new(self.router.events.subscribe())
+    }
 
-        self.router.route_sent(packet).await;
+    /// Resolves once the message is sent, or acknowledged for QoS 1 and 2. Messages published while the client is offline are queued, see [`ClientBuilder::set_offline_queue`].
+    ///
+    /// Fails with [`Error::Rejected`] if the broker refuses the message, and with [`Error::DeliveryUnknown`] if the connection is lost before it is acknowledged.
+    pub async fn publish(&self, topic: &str, qos: QoS, payload: &[u8]) -> Result<(), Error> {
+        self.router.publisher().publish(topic, qos, payload).await
+    }
+
+    /// Like [`Client::publish`], e.g. to set the Message Expiry Interval after which a queued message is discarded.
+    pub async fn publish_with_properties(
+        &self,
+        topic: &str,
+        qos: QoS,
+        payload: &[u8],
+        properties: PublishProperties,
+    ) -> Result<(), Error> {
+        self.router
+            .publisher()
+            .publish_with_properties(topic, qos, payload, properties)
+            .await
+    }
+
+    /// Like [`Client::publish`] for a prepared message, e.g. with the retain flag set. The packet identifier is assigned by the client.
+    pub async fn publish_message(&self, publish: Publish) -> Result<(), Error> {
+        self.router.publisher().publish_message(publish).await
+    }
+
+    /// Runs the authentication exchange of the configured [`Authenticator`] again.
+    pub async fn reauthenticate(&self) -> Result<(), Error> {
+        self.router.reauthenticate().await
+    }
+
+    /// Fails with [`Error::ConnectionClosed`] while the client is not connected.
+    pub async fn subscribe(&self, topic: &str) -> Result<(), Error> {
+        self.subscribe_with_qos(topic, QoS::ExactlyOnce).await
+    }
+
+    async fn subscribe_with_qos(&self, topic: &str, qos: QoS) -> Result<(), Error> {
+        self.router
+            .subscriber()
+            .send_subscribe(Subscribe::new(topic, qos))
+            .await
+    }
+
+    pub async fn unsubscribe(&self, topic: &str) -> Result<(), Error> {
+        self.router.subscriber().unsubscribe(topic).await
     }
 
-    pub async fn shutdown(mut self) {
-        let packet = Packet::Disconnect(Disconnect::new());
+    /// Adds a route to the running client and subscribes to its topic. The route is removed again when the returned guard is dropped.
+    pub async fn add_route<const ASYNC: bool, M: Send + 'static>(
+        &self,
+        route: &str,
+        handler: impl Handler<ASYNC, M, ()> + 'static,
+    ) -> Result<RouteGuard, Error> {
+        self.add_route_with_qos(route, handler, QoS::ExactlyOnce, None)
+            .await
+    }
+
+    async fn add_route_with_qos<const ASYNC: bool, M: Send + 'static>(
+        &self,
+        route: &str,
+        handler: impl Handler<ASYNC, M, ()> + 'static,
+        qos: QoS,
+        order: Option<MessageOrder>,
+    ) -> Result<RouteGuard, Error> {
+        self.router
+            .received_publish
+            .lock()
+            .await
+            .add_route(route, handler, order)?;
+        let guard = RouteGuard {
+            client: Some(self.clone()),
+            route: route.to_owned(),
+        };
+        // The guard removes the route again if the subscription fails.
+        self.subscribe_with_qos(&route_to_filter(route), qos)
+            .await?;
+
+        Ok(guard)
+    }
+
+    /// Subscribes to an MQTT topic filter and delivers matching messages through the returned stream.
+    ///
+    /// Messages are acknowledged only after the stream yields them and messages on the same topic are yielded in the order they were received.
+    /// Dropping the stream unsubscribes from the filter, messages it did not yield yet are not acknowledged and the broker redelivers them after reconnecting.
+    ///
+    /// Filters containing `:` or `*` cannot be subscribed this way.
+    pub async fn subscribe_stream(
+        &self,
+        filter: &str,
+        qos: QoS,
+    ) -> Result<SubscriptionStream, Error> {
+        let route = filter_to_route(filter)?;
+        let (sender, receiver) = mpsc::channel(stream::CAPACITY);
+
+        // The stream acknowledges a message once it yields it, messages dropped with the stream are redelivered.
+        let handler = move |publish: Publish, ack: Ack| {
+            let sender = sender.clone();
+            async move {
+                let _ = sender.send((publish, ack)).await;
+            }
+        };
+
+        let guard = self
+            .add_route_with_qos(&route, handler, qos, Some(MessageOrder::PerTopic))
+            .await?;
+
+        Ok(SubscriptionStream::new(receiver, guard))
+    }
+
+    /// Removes a route added by [`Client::add_route`] and unsubscribes from its topic.
+    pub async fn remove_route(&self, route: &str) -> Result<(), Error> {
+        let removed = self
+            .router
+            .received_publish
+            .lock()
+            .await
+            .remove_route(route);
+
+        if removed {
+            self.unsubscribe(&route_to_filter(route)).await
+        } else {
+            tracing::debug!(route, "Route to be removed does not exist.");
+            Ok(())
+        }
+    }
+
+    pub async fn shutdown(self) {
+        self.disconnect(Disconnect::new()).await;
+    }
+
+    /// Disconnects so that the broker publishes the will set with [`ClientBuilder::set_will`], e.g. when stopping because of a fatal error.
+    pub async fn shutdown_with_will(self) {
+        if self.router.connection.protocol() == Protocol::V4 {
+            // MQTT 3.1.1 has no reason codes, the will is published only if the connection closes without DISCONNECT.
+            self.router.shutdown.cancel();
+            self.router.connect.lock().await.closed();
+            self.router.shutdown().await;
+            self.tracker.wait().await;
+            return;
+        }
+
+        let mut disconnect = Disconnect::new();
+        disconnect.reason_code = DisconnectReasonCode::DisconnectWithWillMessage;
+        self.disconnect(disconnect).await;
+    }
+
+    /// Disconnects and changes how long the broker keeps the session, in seconds.
+    ///
+    /// The interval can only be changed if a non-zero interval was set with [`ClientBuilder::set_session_expiry`].
+    pub async fn shutdown_with_session_expiry(self, session_expiry_interval: u32) {
+        let mut disconnect = Disconnect::new();
+        disconnect.properties = Some(DisconnectProperties {
+            session_expiry_interval: Some(session_expiry_interval),
+            reason_string: None,
+            user_properties: Vec::new(),
+            server_reference: None,
+        });
+
+        self.disconnect(disconnect).await;
+    }
+
+    /// Shuts down after running handlers finish and sent messages are acknowledged, waiting at most `timeout`.
+    ///
+    /// Received messages are no longer handled and [`Client::publish`] fails with [`Error::ShuttingDown`]. Handlers can react to the shutdown by extracting a [`CancellationToken`].
+    pub async fn shutdown_graceful(self, timeout: Duration) -> ShutdownReport {
+        self.shutdown_graceful_with(timeout, DisconnectReasonCode::NormalDisconnection)
+            .await
+    }
+
+    /// Like [`Client::shutdown_graceful`] but sends DISCONNECT with the given reason code.
+    ///
+    /// The broker publishes the will message only for [`DisconnectReasonCode::DisconnectWithWillMessage`] and error codes.
+    /// Over MQTT 3.1.1 any other code than [`DisconnectReasonCode::NormalDisconnection`] closes the connection without DISCONNECT so that the will is published.
+    pub async fn shutdown_graceful_with(
+        self,
+        timeout: Duration,
+        reason_code: DisconnectReasonCode,
+    ) -> ShutdownReport {
+        let deadline = Instant::now() + timeout;
+        self.router.shutdown.cancel();
+
+        let handlers = self.router.handlers.clone();
+        handlers.close();
+        let _ = tokio::time::timeout_at(deadline, handlers.wait()).await;
+        let _ = tokio::time::timeout_at(deadline, self.router.acknowledged()).await;
+
+        let report = ShutdownReport {
+            unfinished_handlers: handlers.len(),
+            unacknowledged_messages: self.router.sent_publish.lock().await.unfinished(),
+            queued_messages: self.router.offline.lock().await.len(),
+        };
+        if !report.is_clean() {
+            tracing::warn!(?report, "Graceful shutdown timed out.");
+        }
+
+        if self.router.connection.protocol() == Protocol::V4
+            && reason_code != DisconnectReasonCode::NormalDisconnection
+        {
+            // MQTT 3.1.1 has no reason codes, the will is published only if the connection closes without DISCONNECT.
+            self.router.connect.lock().await.closed();
+        } else {
+            let mut disconnect = Disconnect::new();
+            disconnect.reason_code = reason_code;
+            let packet = Packet::Disconnect(disconnect);
+            self.router.route_sent(packet).await;
+        }
+        self.router.shutdown().await;
+        // Handlers which did not finish would keep the client running forever.
+        let _ = tokio::time::timeout_at(deadline, self.tracker.wait()).await;
+
+        report
+    }
+
+    async fn disconnect(self, disconnect: Disconnect) {
+        self.router.shutdown.cancel();
+        let packet = Packet::Disconnect(disconnect);
         self.router.route_sent(packet).await;
         self.router.shutdown().await;
         self.tracker.wait().await;
     }
 }
 
+// Messages waiting for their turn or a handler before the connection is no longer read.
+const MAX_WAITING_MESSAGES: usize = 1024;
+
+// Routes received packets until the connection closes.
+async fn receive(
+    router: &Router<Reader, Writer>,
+    tracker: &TaskTracker,
+    handler_limit: &Option<Arc<Semaphore>>,
+    waiting: &Arc<Semaphore>,
+) {
+    let timed_out = Arc::new(Notify::new());
+    let pinger = tokio::spawn(keep_alive(router.clone(), timed_out.clone()));
+
+    loop {
+        let received = tokio::select! {
+            received = router.connection.recv() => received,
+            () = timed_out.notified() => {
+                router.shutdown().await;
+                break;
+            }
+        };
+        let mut packet = match received {
+            Ok(Some(Incoming::Packet(packet))) => packet,
+            Ok(Some(Incoming::Auth(auth))) => {
+                tracker.spawn({
+                    let router = router.clone();
+                    async move { router.route_auth(auth).await }
+                });
+                continue;
+            }
+            Ok(None) => break,
+            Err(mqttbytes::Error::PayloadSizeLimitExceeded(size)) => {
+                tracing::error!(
+                    size,
+                    "Received packet exceeds the maximum packet size, disconnecting."
+                );
+                router
+                    .disconnect_with(DisconnectReasonCode::PacketTooLarge)
+                    .await;
+                break;
+            }
+            Err(error) => {
+                tracing::error!(?error, "Unable to read packet, closing the connection.");
+                router.shutdown().await;
+                break;
+            }
+        };
+
+        // Handled right away so that the reason or a refusal is known once the connection closes.
+        if let Packet::Disconnect(_) | Packet::ConnAck(_) = packet {
+            router.route_received(packet).await;
+            continue;
+        }
+
+        let mut limits = Vec::new();
+        let mut turn = None;
+        if let Packet::Publish(publish) = &mut packet {
+            let mut received_publish = router.received_publish.lock().await;
+            if let Err(reason_code) = received_publish.resolve_topic_alias(publish) {
+                drop(received_publish);
+                router.disconnect_with(reason_code).await;
+                break;
+            }
+            // Unacknowledged messages are redelivered by the broker if the session is kept.
+            if router.shutdown.is_cancelled() {
+                tracing::debug!(
+                    topic = publish.topic,
+                    "Shutting down, message is not handled."
+                );
+                continue;
+            }
+            let admission = received_publish.admit(&publish.topic);
+            limits.extend(handler_limit.iter().cloned().chain(admission.limit));
+            turn = admission.turn;
+        }
+        // Reading stops while too many messages wait for their turn or a handler.
+        let backlog = if turn.is_some() || !limits.is_empty() {
+            Some(
+                waiting
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("Semaphore is never closed."),
+            )
+        } else {
+            None
+        };
+
+        let is_publish = matches!(packet, Packet::Publish(_));
+        let task = {
+            let router = router.clone();
+            async move {
+                // Messages which must be handled in order wait for the previous one.
+                if let Some(turn) = &mut turn {
+                    turn.wait().await;
+                }
+                // Waiting here rather than in the receive loop keeps acknowledgements of our own
+                // messages flowing, unless too many messages wait.
+                let mut permits = Vec::with_capacity(limits.len());
+                for limit in limits {
+                    permits.push(
+                        limit
+                            .acquire_owned()
+                            .await
+                            .expect("Semaphore is never closed."),
+                    );
+                }
+                drop(backlog);
+                router.route_received(packet).await;
+                drop((permits, turn));
+            }
+        };
+        if is_publish {
+            tracker.spawn(router.handlers.track_future(task));
+        } else {
+            tracker.spawn(task);
+        }
+    }
+
+    pinger.abort();
+}
+
+// Sends PINGREQ in the keep-alive interval and notifies if PINGRESP does not come within the same interval.
+async fn keep_alive(router: Router<Reader, Writer>, timed_out: Arc<Notify>) {
+    loop {
+        let Some(interval) = router.connect.lock().await.keep_alive() else {
+            return;
+        };
+        tokio::time::sleep(interval).await;
+        if !router.connect.lock().await.is_connected() {
+            continue;
+        }
+
+        let ping = router.route_sent(Packet::PingReq);
+        if tokio::time::timeout(interval, ping).await.is_err() {
+            tracing::warn!(
+                ?interval,
+                "Broker did not answer ping, closing the connection."
+            );
+            router.event(ConnectionEvent::KeepAliveTimeout);
+            timed_out.notify_one();
+            return;
+        }
+    }
+}
+
+// Returns whether a new connection was established.
+async fn reconnect(
+    client: &Client,
+    endpoints: &mut Endpoints,
+    policy: &ReconnectPolicy,
+    connect: &Connect,
+    hooks: &Hooks,
+) -> bool {
+    let router = &client.router;
+    for attempt in 0.. {
+        let Some(delay) = policy.delay(attempt) else {
+            tracing::info!("Connection lost, not reconnecting.");
+            return false;
+        };
+        router.event(ConnectionEvent::Reconnecting { attempt });
+        tokio::time::sleep(delay).await;
+        if router.connect.lock().await.closed_by_client() {
+            return false;
+        }
+
+        let stream = match endpoints.connect().await {
+            Ok(stream) => stream,
+            Err(error) => {
+                tracing::warn!(%error, attempt, "Unable to reconnect.");
+                continue;
+            }
+        };
+
+        let mut connect = connect.clone();
+        if let Err(error) = router.auth.lock().await.connect(&mut connect) {
+            tracing::warn!(%error, attempt, "Unable to start authentication.");
+            continue;
+        }
+        let mut packet = Packet::Connect(connect);
+        let connack = router.prepare_packet(&mut packet).await;
+        let (reader, writer) = connection::split(stream);
+        if router
+            .connection
+            .replace(reader, writer, &packet)
+            .await
+            .is_err()
+        {
+            router.connect.lock().await.connection_lost();
+            endpoints.refused();
+            continue;
+        }
+        tracing::info!(attempt, "Reconnected.");
+
+        client.tracker.spawn({
+            let client = client.clone();
+            let hooks = hooks.clone();
+            async move {
+                connack.await;
+
+                let connect = client.router.connect.lock().await;
+                if !connect.is_connected() {
+                    return;
+                }
+                let session_present = connect.session_present();
+                drop(connect);
+
+                if !session_present {
+                    let resubscribe = client.router.subscribe.lock().await.resubscribe();
+                    if let Some(subscribe) = resubscribe {
+                        let subscriber = client.router.subscriber();
+                        if let Err(error) = subscriber.send_subscribe(subscribe).await {
+                            tracing::error!(%error, "Unable to subscribe again after reconnecting.");
+                        }
+                    }
+                }
+                hooks.connected(&client);
+            }
+        });
+        return true;
+    }
+
+    false
+}
+
+/// What was abandoned because [`Client::shutdown_graceful`] timed out.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Handlers which were still running.
+    pub unfinished_handlers: usize,
+    /// QoS 1 and 2 messages the broker did not acknowledge, kept in the session store.
+    pub unacknowledged_messages: usize,
+    /// Messages published while the client was offline which were never sent.
+    pub queued_messages: usize,
+}
+
+impl ShutdownReport {
+    /// Whether nothing was abandoned.
+    pub fn is_clean(&self) -> bool {
+        self.unfinished_handlers == 0
+            && self.unacknowledged_messages == 0
+            && self.queued_messages == 0
+    }
+}
+
 #[derive(Clone)]
 pub struct ClientState {
     pub(crate) publisher: Publisher,
     pub(crate) subscriber: Subscriber,
+    pub(crate) pending_acks: PendingAcks,
+    pub(crate) shutdown: CancellationToken,
+}
+
+/// Keeps a route added by [`Client::add_route`] alive.
+#[must_use = "dropping the guard removes the route"]
+pub struct RouteGuard {
+    client: Option<Client>,
+    route: String,
+}
+
+impl RouteGuard {
+    pub fn route(&self) -> &str {
+        &self.route
+    }
+
+    /// Removes the route and waits until the broker acknowledges the unsubscription.
+    pub async fn remove(mut self) -> Result<(), Error> {
+        match self.client.take() {
+            Some(client) => client.remove_route(&self.route).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for RouteGuard {
+    fn drop(&mut self) {
+        let Some(client) = self.client.take() else {
+            return;
+        };
+
+        let route = std::mem::take(&mut self.route);
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(async move {
+                    if let Err(error) = client.remove_route(&route).await {
+                        tracing::warn!(route, %error, "Unable to remove route.");
+                    }
+                });
+            }
+            Err(_) => {
+                tracing::warn!(
+                    route,
+                    "Route guard dropped outside of a runtime, route was not removed."
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::poll_fn,
+        pin::{pin, Pin},
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use bytes::Bytes;
+    use mqttbytes::v5::{
+        ConnAck, ConnAckProperties, ConnectReturnCode, PubAck, PubRel, SubAck, SubscribeReasonCode,
+        UnsubAck, UnsubAckReason,
+    };
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        auth::packet::{Auth, AuthReason},
+        connection::DEFAULT_MAX_PACKET_SIZE,
+        Ack, HandlerRouterBuilder, MemoryStore, RouteOptions, SessionStore,
+    };
+
+    async fn accept(listener: &TcpListener) -> Connection<Reader, Writer> {
+        accept_with(listener, Protocol::V5).await
+    }
+
+    // Accepts a client whose router has a single route and grants its subscription.
+    async fn accept_subscription(listener: &TcpListener) -> Connection<Reader, Writer> {
+        let connection = accept(listener).await;
+        let Some(Incoming::Packet(Packet::Subscribe(subscribe))) = connection.recv().await.unwrap()
+        else {
+            panic!("expected SUBSCRIBE");
+        };
+        let suback = SubAck::new(subscribe.pkid, vec![SubscribeReasonCode::QoS2]);
+        connection.send(&Packet::SubAck(suback)).unwrap().await;
+        connection
+    }
+
+    async fn accept_with(listener: &TcpListener, protocol: Protocol) -> Connection<Reader, Writer> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let connection =
+            Connection::with_stream(Box::new(stream), DEFAULT_MAX_PACKET_SIZE, protocol);
+
+        let Some(Incoming::Packet(Packet::Connect(_))) = connection.recv().await.unwrap() else {
+            panic!("expected CONNECT");
+        };
+        let mut connack = ConnAck::new(ConnectReturnCode::Success, false);
+        // `mqttbytes` cannot read a CONNACK without properties.
+        connack.properties = Some(ConnAckProperties::new());
+        connection.send(&Packet::ConnAck(connack)).unwrap().await;
+        connection
+    }
+
+    #[tokio::test]
+    async fn refused_connack_redirects_to_referenced_server() {
+        let refusing = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let other = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let other_address = other.local_addr().unwrap();
+
+        let mut builder = ClientBuilder::new(refusing.local_addr().unwrap());
+        builder.set_reconnect_policy(ReconnectPolicy::Backoff {
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        });
+        let refuse = async {
+            let (stream, _) = refusing.accept().await.unwrap();
+            let connection =
+                Connection::with_stream(Box::new(stream), DEFAULT_MAX_PACKET_SIZE, Protocol::V5);
+            let Some(Incoming::Packet(Packet::Connect(_))) = connection.recv().await.unwrap()
+            else {
+                panic!("expected CONNECT");
+            };
+            let mut connack = ConnAck::new(ConnectReturnCode::UseAnotherServer, false);
+            let mut properties = ConnAckProperties::new();
+            properties.server_reference = Some(other_address.to_string());
+            connack.properties = Some(properties);
+            connection.send(&Packet::ConnAck(connack)).unwrap().await;
+            connection.shutdown().await.unwrap();
+        };
+        let (client, ()) = tokio::join!(builder.build(HandlerRouterBuilder::new().build()), refuse);
+        let client = client.unwrap();
+
+        let connection = tokio::time::timeout(Duration::from_secs(1), accept(&other))
+            .await
+            .expect("redirected to the referenced server");
+        let (_, packet) = tokio::join!(client.shutdown(), async move {
+            connection.recv().await.unwrap()
+        });
+        assert!(matches!(
+            packet,
+            Some(Incoming::Packet(Packet::Disconnect(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn refused_connack_does_not_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut builder = ClientBuilder::new(listener.local_addr().unwrap());
+        let connects = Arc::new(AtomicUsize::new(0));
+        builder.on_connect({
+            let connects = connects.clone();
+            move |_| {
+                connects.fetch_add(1, Ordering::SeqCst);
+                async {}
+            }
+        });
+        let refuse = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let connection =
+                Connection::with_stream(Box::new(stream), DEFAULT_MAX_PACKET_SIZE, Protocol::V5);
+            let Some(Incoming::Packet(Packet::Connect(_))) = connection.recv().await.unwrap()
+            else {
+                panic!("expected CONNECT");
+            };
+            let mut connack = ConnAck::new(ConnectReturnCode::NotAuthorized, false);
+            connack.properties = Some(ConnAckProperties::new());
+            connection.send(&Packet::ConnAck(connack)).unwrap().await;
+            connection
+        };
+        let (client, connection) =
+            tokio::join!(builder.build(HandlerRouterBuilder::new().build()), refuse);
+        let client = client.unwrap();
+        let mut events = pin!(client.events());
+
+        assert_ne!(client.state().await, ConnectionState::Connected);
+        connection.shutdown().await.unwrap();
+        while poll_fn(|cx| events.as_mut().poll_next(cx)).await
+            != Some(ConnectionEvent::Disconnected)
+        {}
+        assert_eq!(client.state().await, ConnectionState::Disconnected);
+        assert_eq!(connects.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn follows_server_moved_redirect_and_reports_events() {
+        let old = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let new = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let new_address = new.local_addr().unwrap();
+
+        let mut builder = ClientBuilder::new(old.local_addr().unwrap());
+        builder.set_reconnect_policy(ReconnectPolicy::Backoff {
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        });
+        let (connected_sender, mut connected) = mpsc::unbounded_channel();
+        builder.on_connect(move |_| {
+            let _ = connected_sender.send(());
+            async {}
+        });
+        let (client, connection) = tokio::join!(
+            builder.build(HandlerRouterBuilder::new().build()),
+            accept(&old)
+        );
+        let client = client.unwrap();
+        let mut events = pin!(client.events());
+
+        let moved = tokio::spawn(async move {
+            let Some(Incoming::Packet(Packet::Publish(_))) = connection.recv().await.unwrap()
+            else {
+                panic!("expected PUBLISH");
+            };
+            let mut disconnect = Disconnect::new();
+            disconnect.reason_code = DisconnectReasonCode::ServerMoved;
+            disconnect.properties = Some(DisconnectProperties {
+                session_expiry_interval: None,
+                reason_string: Some("moved".to_owned()),
+                user_properties: Vec::new(),
+                server_reference: Some(new_address.to_string()),
+            });
+            connection
+                .send(&Packet::Disconnect(disconnect))
+                .unwrap()
+                .await;
+            connection.shutdown().await.unwrap();
+        });
+
+        let result = client.publish("topic", QoS::AtLeastOnce, b"payload").await;
+        let Err(Error::DeliveryUnknown(error)) = result else {
+            panic!("expected unknown delivery");
+        };
+        assert!(matches!(
+            *error,
+            Error::Disconnected {
+                reason_code: DisconnectReasonCode::ServerMoved,
+                reason_string: Some(_),
+            }
+        ));
+
+        moved.await.unwrap();
+        let connection = accept(&new).await;
+
+        let mut received = Vec::new();
+        while received.last()
+            != Some(&ConnectionEvent::Connected {
+                session_present: false,
+            })
+        {
+            received.push(poll_fn(|cx| events.as_mut().poll_next(cx)).await.unwrap());
+        }
+        let [ConnectionEvent::ServerDisconnect(disconnect), ConnectionEvent::ConnectionLost, ConnectionEvent::Reconnecting { attempt: 0 }, _] =
+            &received[..]
+        else {
+            panic!("unexpected events {received:?}");
+        };
+        assert_eq!(disconnect.server_reference, Some(new_address.to_string()));
+        assert_eq!(client.state().await, ConnectionState::Connected);
+        for _ in 0..2 {
+            connected.recv().await.unwrap();
+        }
+
+        let (_, packet) = tokio::join!(client.shutdown(), async move {
+            // Closes the connection after DISCONNECT like a broker would.
+            connection.recv().await.unwrap()
+        });
+        assert!(matches!(
+            packet,
+            Some(Incoming::Packet(Packet::Disconnect(_)))
+        ));
+        assert_eq!(
+            poll_fn(|cx| events.as_mut().poll_next(cx)).await,
+            Some(ConnectionEvent::Disconnected)
+        );
+    }
+
+    #[tokio::test]
+    async fn added_route_is_subscribed_until_removed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (client, connection) = tokio::join!(
+            ClientBuilder::new(listener.local_addr().unwrap())
+                .build(HandlerRouterBuilder::new().build()),
+            accept(&listener)
+        );
+        let client = client.unwrap();
+
+        let broker = tokio::spawn(async move {
+            let Some(Incoming::Packet(Packet::Subscribe(subscribe))) =
+                connection.recv().await.unwrap()
+            else {
+                panic!("expected SUBSCRIBE");
+            };
+            assert_eq!(subscribe.filters[0].path, "devices/+/#");
+            let suback = SubAck::new(subscribe.pkid, vec![SubscribeReasonCode::QoS2]);
+            connection.send(&Packet::SubAck(suback)).unwrap().await;
+            let publish = Publish::new("devices/1/temperature", QoS::AtMostOnce, "21");
+            connection.send(&Packet::Publish(publish)).unwrap().await;
+
+            let Some(Incoming::Packet(Packet::Unsubscribe(unsubscribe))) =
+                connection.recv().await.unwrap()
+            else {
+                panic!("expected UNSUBSCRIBE");
+            };
+            assert_eq!(unsubscribe.filters, ["devices/+/#"]);
+            let mut unsuback = UnsubAck::new(unsubscribe.pkid);
+            unsuback.reasons.push(UnsubAckReason::Success);
+            connection.send(&Packet::UnsubAck(unsuback)).unwrap().await;
+            connection
+        });
+
+        let (sender, mut received) = mpsc::unbounded_channel();
+        let guard = client
+            .add_route("devices/:id/*rest", move |publish: Publish| {
+                let _ = sender.send(publish.topic);
+                async {}
+            })
+            .await
+            .unwrap();
+        assert_eq!(received.recv().await.unwrap(), "devices/1/temperature");
+        guard.remove().await.unwrap();
+
+        let connection = broker.await.unwrap();
+        let (_, packet) = tokio::join!(client.shutdown(), async move {
+            connection.recv().await.unwrap()
+        });
+        assert!(matches!(
+            packet,
+            Some(Incoming::Packet(Packet::Disconnect(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn stream_yields_messages_in_order_and_unsubscribes_when_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (client, connection) = tokio::join!(
+            ClientBuilder::new(listener.local_addr().unwrap())
+                .build(HandlerRouterBuilder::new().build()),
+            accept(&listener)
+        );
+        let client = client.unwrap();
+
+        let broker = tokio::spawn(async move {
+            let Some(Incoming::Packet(Packet::Subscribe(subscribe))) =
+                connection.recv().await.unwrap()
+            else {
+                panic!("expected SUBSCRIBE");
+            };
+            assert_eq!(subscribe.filters[0].path, "sensors/+");
+            let suback = SubAck::new(subscribe.pkid, vec![SubscribeReasonCode::QoS1]);
+            connection.send(&Packet::SubAck(suback)).unwrap().await;
+            for (pkid, payload) in [(1, "first"), (2, "second")] {
+                let mut publish = Publish::new("sensors/a", QoS::AtLeastOnce, payload);
+                publish.pkid = pkid;
+                connection.send(&Packet::Publish(publish)).unwrap().await;
+            }
+
+            let mut acked = Vec::new();
+            let unsubscribe = loop {
+                match connection.recv().await.unwrap() {
+                    Some(Incoming::Packet(Packet::PubAck(puback))) => acked.push(puback.pkid),
+                    Some(Incoming::Packet(Packet::Unsubscribe(unsubscribe))) => break unsubscribe,
+                    packet => panic!("unexpected packet {packet:?}"),
+                }
+            };
+            assert_eq!(acked, [1, 2]);
+            assert_eq!(unsubscribe.filters, ["sensors/+"]);
+            let mut unsuback = UnsubAck::new(unsubscribe.pkid);
+            unsuback.reasons.push(UnsubAckReason::Success);
+            connection.send(&Packet::UnsubAck(unsuback)).unwrap().await;
+            connection
+        });
+
+        let mut stream = client
+            .subscribe_stream("sensors/+", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        let mut payloads = Vec::new();
+        for _ in 0..2 {
+            let publish = poll_fn(|cx| Pin::new(&mut stream).poll_next(cx))
+                .await
+                .unwrap();
+            payloads.push(publish.payload);
+        }
+        assert_eq!(payloads, ["first", "second"]);
+        // Let the handler of the second message acknowledge it before unsubscribing.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(stream);
+
+        let connection = broker.await.unwrap();
+        let (_, packet) = tokio::join!(client.shutdown(), async move {
+            connection.recv().await.unwrap()
+        });
+        assert!(matches!(
+            packet,
+            Some(Incoming::Packet(Packet::Disconnect(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn messages_buffered_in_a_dropped_stream_are_not_acknowledged() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (client, connection) = tokio::join!(
+            ClientBuilder::new(listener.local_addr().unwrap())
+                .build(HandlerRouterBuilder::new().build()),
+            accept(&listener)
+        );
+        let client = client.unwrap();
+
+        let broker = tokio::spawn(async move {
+            let Some(Incoming::Packet(Packet::Subscribe(subscribe))) =
+                connection.recv().await.unwrap()
+            else {
+                panic!("expected SUBSCRIBE");
+            };
+            let suback = SubAck::new(subscribe.pkid, vec![SubscribeReasonCode::QoS1]);
+            connection.send(&Packet::SubAck(suback)).unwrap().await;
+            for (pkid, payload) in [(1, "yielded"), (2, "buffered")] {
+                let mut publish = Publish::new("sensors/a", QoS::AtLeastOnce, payload);
+                publish.pkid = pkid;
+                connection.send(&Packet::Publish(publish)).unwrap().await;
+            }
+
+            let mut acked = Vec::new();
+            let unsubscribe = loop {
+                match connection.recv().await.unwrap() {
+                    Some(Incoming::Packet(Packet::PubAck(puback))) => acked.push(puback.pkid),
+                    Some(Incoming::Packet(Packet::Unsubscribe(unsubscribe))) => break unsubscribe,
+                    packet => panic!("unexpected packet {packet:?}"),
+                }
+            };
+            assert_eq!(acked, [1]);
+            let mut unsuback = UnsubAck::new(unsubscribe.pkid);
+            unsuback.reasons.push(UnsubAckReason::Success);
+            connection.send(&Packet::UnsubAck(unsuback)).unwrap().await;
+            connection
+        });
+
+        let mut stream = client
+            .subscribe_stream("sensors/+", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        let publish = poll_fn(|cx| Pin::new(&mut stream).poll_next(cx))
+            .await
+            .unwrap();
+        assert_eq!(publish.payload, "yielded");
+        // Let the second message reach the stream's buffer.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(stream);
+
+        let connection = broker.await.unwrap();
+        let (_, packet) = tokio::join!(client.shutdown(), async move {
+            connection.recv().await.unwrap()
+        });
+        assert!(matches!(
+            packet,
+            Some(Incoming::Packet(Packet::Disconnect(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn subscribing_while_reconnecting_fails() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut builder = ClientBuilder::new(listener.local_addr().unwrap());
+        builder.set_reconnect_policy(ReconnectPolicy::Backoff {
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        });
+        let (client, connection) = tokio::join!(
+            builder.build(HandlerRouterBuilder::new().build()),
+            accept(&listener)
+        );
+        let client = client.unwrap();
+        let mut events = pin!(client.events());
+
+        connection.shutdown().await.unwrap();
+        while poll_fn(|cx| events.as_mut().poll_next(cx)).await
+            != Some(ConnectionEvent::Reconnecting { attempt: 0 })
+        {}
+        let subscribed = tokio::time::timeout(Duration::from_secs(1), client.subscribe("topic"))
+            .await
+            .expect("failed without waiting for the connection");
+        assert!(matches!(subscribed, Err(Error::ConnectionClosed)));
+
+        let connection = accept(&listener).await;
+        let (_, packet) = tokio::join!(client.shutdown(), async move {
+            connection.recv().await.unwrap()
+        });
+        assert!(matches!(
+            packet,
+            Some(Incoming::Packet(Packet::Disconnect(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn routes_cannot_be_added_for_filters_with_route_syntax() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (client, _connection) = tokio::join!(
+            ClientBuilder::new(listener.local_addr().unwrap())
+                .build(HandlerRouterBuilder::new().build()),
+            accept(&listener)
+        );
+        let client = client.unwrap();
+
+        let result = client
+            .subscribe_stream("sensors/:id", QoS::AtMostOnce)
+            .await;
+        assert!(matches!(result, Err(Error::UnroutableFilter(_))));
+    }
+
+    #[tokio::test]
+    async fn extracted_ack_replaces_automatic_acknowledgement() {
+        let (acks, mut received) = mpsc::unbounded_channel();
+        let mut router = HandlerRouterBuilder::new();
+        router.add("manual", move |ack: Ack| {
+            let _ = acks.send(ack);
+            async {}
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (client, connection) = tokio::join!(
+            ClientBuilder::new(listener.local_addr().unwrap()).build(router.build()),
+            accept_subscription(&listener)
+        );
+        let client = client.unwrap();
+
+        let mut publish = Publish::new("manual", QoS::AtLeastOnce, "");
+        publish.pkid = 1;
+        connection.send(&Packet::Publish(publish)).unwrap().await;
+        let ack = received.recv().await.unwrap();
+        let nothing_sent =
+            tokio::time::timeout(Duration::from_millis(100), connection.recv()).await;
+        assert!(nothing_sent.is_err());
+        ack.ack();
+        let Some(Incoming::Packet(Packet::PubAck(puback))) = connection.recv().await.unwrap()
+        else {
+            panic!("expected PUBACK");
+        };
+        assert_eq!(puback.pkid, 1);
+
+        let mut publish = Publish::new("manual", QoS::AtLeastOnce, "");
+        publish.pkid = 2;
+        connection.send(&Packet::Publish(publish)).unwrap().await;
+        drop(received.recv().await.unwrap());
+        let nothing_sent =
+            tokio::time::timeout(Duration::from_millis(100), connection.recv()).await;
+        assert!(nothing_sent.is_err());
+
+        let (_, packet) = tokio::join!(client.shutdown(), async move {
+            connection.recv().await.unwrap()
+        });
+        assert!(matches!(
+            packet,
+            Some(Incoming::Packet(Packet::Disconnect(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn duplicate_exactly_once_message_is_handled_once() {
+        let (handled, mut received) = mpsc::unbounded_channel();
+        let mut router = HandlerRouterBuilder::new();
+        router.add("exactly-once", move |publish: Publish| {
+            let handled = handled.clone();
+            async move {
+                // Still handling when the duplicate arrives.
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let _ = handled.send(publish.pkid);
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (client, connection) = tokio::join!(
+            ClientBuilder::new(listener.local_addr().unwrap()).build(router.build()),
+            accept_subscription(&listener)
+        );
+        let client = client.unwrap();
+
+        let mut publish = Publish::new("exactly-once", QoS::ExactlyOnce, "");
+        publish.pkid = 1;
+        connection
+            .send(&Packet::Publish(publish.clone()))
+            .unwrap()
+            .await;
+        publish.dup = true;
+        connection.send(&Packet::Publish(publish)).unwrap().await;
+
+        let Some(Incoming::Packet(Packet::PubRec(pubrec))) = connection.recv().await.unwrap()
+        else {
+            panic!("expected PUBREC");
+        };
+        assert_eq!(pubrec.pkid, 1);
+        connection
+            .send(&Packet::PubRel(PubRel::new(1)))
+            .unwrap()
+            .await;
+        let Some(Incoming::Packet(Packet::PubComp(pubcomp))) = connection.recv().await.unwrap()
+        else {
+            panic!("expected PUBCOMP");
+        };
+        assert_eq!(pubcomp.pkid, 1);
+
+        let (_, packet) = tokio::join!(client.shutdown(), async move {
+            connection.recv().await.unwrap()
+        });
+        assert!(matches!(
+            packet,
+            Some(Incoming::Packet(Packet::Disconnect(_)))
+        ));
+        assert_eq!(received.recv().await, Some(1));
+        assert_eq!(received.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn routes_are_subscribed_when_session_is_resumed() {
+        let mut router = HandlerRouterBuilder::new();
+        router.add("added/since/session", |_: Publish| async {});
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let broker = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let connection =
+                Connection::with_stream(Box::new(stream), DEFAULT_MAX_PACKET_SIZE, Protocol::V5);
+            let Some(Incoming::Packet(Packet::Connect(_))) = connection.recv().await.unwrap()
+            else {
+                panic!("expected CONNECT");
+            };
+            let mut connack = ConnAck::new(ConnectReturnCode::Success, true);
+            connack.properties = Some(ConnAckProperties::new());
+            connection.send(&Packet::ConnAck(connack)).unwrap().await;
+
+            let Some(Incoming::Packet(Packet::Subscribe(subscribe))) =
+                connection.recv().await.unwrap()
+            else {
+                panic!("expected SUBSCRIBE");
+            };
+            let suback = SubAck::new(subscribe.pkid, vec![SubscribeReasonCode::QoS2]);
+            connection.send(&Packet::SubAck(suback)).unwrap().await;
+            (connection, subscribe)
+        };
+        let (client, (connection, subscribe)) = tokio::join!(
+            ClientBuilder::new(listener.local_addr().unwrap()).build(router.build()),
+            broker
+        );
+        let client = client.unwrap();
+
+        let [filter] = &subscribe.filters[..] else {
+            panic!("expected one filter");
+        };
+        assert_eq!(filter.path, "added/since/session");
+        assert!(matches!(
+            filter.retain_forward_rule,
+            RetainForwardRule::OnNewSubscribe
+        ));
+
+        let (_, packet) = tokio::join!(client.shutdown(), async move {
+            connection.recv().await.unwrap()
+        });
+        assert!(matches!(
+            packet,
+            Some(Incoming::Packet(Packet::Disconnect(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn queued_messages_are_sent_in_order_after_reconnecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut builder = ClientBuilder::new(listener.local_addr().unwrap());
+        builder.set_reconnect_policy(ReconnectPolicy::Backoff {
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        });
+        let (client, connection) = tokio::join!(
+            builder.build(HandlerRouterBuilder::new().build()),
+            accept(&listener)
+        );
+        let client = client.unwrap();
+        let mut events = pin!(client.events());
+
+        connection.shutdown().await.unwrap();
+        while poll_fn(|cx| events.as_mut().poll_next(cx)).await
+            != Some(ConnectionEvent::Reconnecting { attempt: 0 })
+        {}
+
+        let topics = ["first", "second", "third"];
+        let mut published = Vec::new();
+        for (queued, topic) in topics.into_iter().enumerate() {
+            published.push(tokio::spawn({
+                let client = client.clone();
+                async move { client.publish(topic, QoS::AtLeastOnce, b"").await }
+            }));
+            // Each message is queued before the next one is published.
+            while client.router.offline.lock().await.len() <= queued {
+                tokio::task::yield_now().await;
+            }
+        }
+
+        let connection = accept(&listener).await;
+        for topic in topics {
+            let Some(Incoming::Packet(Packet::Publish(publish))) = connection.recv().await.unwrap()
+            else {
+                panic!("expected PUBLISH");
+            };
+            assert_eq!(publish.topic, topic);
+            connection
+                .send(&Packet::PubAck(PubAck::new(publish.pkid)))
+                .unwrap()
+                .await;
+        }
+        for published in published {
+            published.await.unwrap().unwrap();
+        }
+
+        let (_, packet) = tokio::join!(client.shutdown(), async move {
+            connection.recv().await.unwrap()
+        });
+        assert!(matches!(
+            packet,
+            Some(Incoming::Packet(Packet::Disconnect(_)))
+        ));
+    }
+
+    // Sends two messages whose handlers publish at QoS 1, returns how many of them ran at once.
+    async fn most_concurrent_handlers(
+        handler_limit: Option<usize>,
+        options: RouteOptions,
+    ) -> usize {
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let mut router = HandlerRouterBuilder::new();
+        router.add_with_options(
+            "limited",
+            {
+                let (running, most) = (running.clone(), most.clone());
+                move |publisher: Publisher| {
+                    let (running, most) = (running.clone(), most.clone());
+                    async move {
+                        most.fetch_max(
+                            running.fetch_add(1, Ordering::SeqCst) + 1,
+                            Ordering::SeqCst,
+                        );
+                        // Never finishes if the limit stops the client from reading the PUBACK.
+                        publisher
+                            .publish("reply", QoS::AtLeastOnce, b"")
+                            .await
+                            .unwrap();
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    }
+                }
+            },
+            options,
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut builder = ClientBuilder::new(listener.local_addr().unwrap());
+        if let Some(limit) = handler_limit {
+            builder.set_handler_concurrency_limit(limit);
+        }
+        let (client, connection) = tokio::join!(
+            builder.build(router.build()),
+            accept_subscription(&listener)
+        );
+        let client = client.unwrap();
+
+        for _ in 0..2 {
+            let publish = Publish::new("limited", QoS::AtMostOnce, "");
+            connection.send(&Packet::Publish(publish)).unwrap().await;
+        }
+        for _ in 0..2 {
+            let Some(Incoming::Packet(Packet::Publish(publish))) = connection.recv().await.unwrap()
+            else {
+                panic!("expected PUBLISH");
+            };
+            connection
+                .send(&Packet::PubAck(PubAck::new(publish.pkid)))
+                .unwrap()
+                .await;
+        }
+
+        let (_, packet) = tokio::join!(client.shutdown(), async move {
+            connection.recv().await.unwrap()
+        });
+        assert!(matches!(
+            packet,
+            Some(Incoming::Packet(Packet::Disconnect(_)))
+        ));
+        most.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn handler_concurrency_limit_does_not_block_acknowledgements() {
+        assert_eq!(
+            most_concurrent_handlers(Some(1), RouteOptions::new()).await,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn route_concurrency_limit_does_not_block_acknowledgements() {
+        let options = RouteOptions::new().concurrency_limit(1);
+        assert_eq!(most_concurrent_handlers(None, options).await, 1);
+    }
+
+    // Sends a message on each topic, the first one is handled slowly. Returns the topics in the order their handlers finished.
+    async fn completion_order(order: MessageOrder, topics: [&str; 3]) -> Vec<String> {
+        let (finished, mut received) = mpsc::unbounded_channel();
+        let mut router = HandlerRouterBuilder::new();
+        router.order(order);
+        router.add("devices/:device/*rest", move |publish: Publish| {
+            let finished = finished.clone();
+            async move {
+                if &publish.payload[..] == b"slow" {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+                let _ = finished.send(publish.topic);
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (client, connection) = tokio::join!(
+            ClientBuilder::new(listener.local_addr().unwrap()).build(router.build()),
+            accept_subscription(&listener)
+        );
+        let client = client.unwrap();
+
+        for (index, topic) in topics.into_iter().enumerate() {
+            let payload = if index == 0 { "slow" } else { "fast" };
+            let publish = Publish::new(topic, QoS::AtMostOnce, payload);
+            connection.send(&Packet::Publish(publish)).unwrap().await;
+        }
+        let mut completed = Vec::new();
+        for _ in 0..3 {
+            completed.push(received.recv().await.unwrap());
+        }
+
+        let (_, packet) = tokio::join!(client.shutdown(), async move {
+            connection.recv().await.unwrap()
+        });
+        assert!(matches!(
+            packet,
+            Some(Incoming::Packet(Packet::Disconnect(_)))
+        ));
+        completed
+    }
+
+    #[tokio::test]
+    async fn messages_on_the_same_topic_are_handled_in_order() {
+        let completed = completion_order(
+            MessageOrder::PerTopic,
+            [
+                "devices/a/temperature",
+                "devices/a/temperature",
+                "devices/b/temperature",
+            ],
+        )
+        .await;
+        assert_eq!(
+            completed,
+            [
+                "devices/b/temperature",
+                "devices/a/temperature",
+                "devices/a/temperature"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_with_the_same_parameter_are_handled_in_order() {
+        let completed = completion_order(
+            MessageOrder::PerParameter("device".to_owned()),
+            [
+                "devices/a/temperature",
+                "devices/a/humidity",
+                "devices/b/temperature",
+            ],
+        )
+        .await;
+        assert_eq!(
+            completed,
+            [
+                "devices/b/temperature",
+                "devices/a/temperature",
+                "devices/a/humidity"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn too_many_waiting_messages_pause_reading() {
+        let gate = CancellationToken::new();
+        let mut router = HandlerRouterBuilder::new();
+        router.add_with_options(
+            "blocked",
+            {
+                let gate = gate.clone();
+                move |_: Publish| {
+                    let gate = gate.clone();
+                    async move { gate.cancelled().await }
+                }
+            },
+            RouteOptions::default().concurrency_limit(1),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (client, connection) = tokio::join!(
+            ClientBuilder::new(listener.local_addr().unwrap()).build(router.build()),
+            accept_subscription(&listener)
+        );
+        let client = client.unwrap();
+        let mut published = tokio::spawn({
+            let client = client.clone();
+            async move { client.publish("topic", QoS::AtLeastOnce, b"").await }
+        });
+        let Some(Incoming::Packet(Packet::Publish(publish))) = connection.recv().await.unwrap()
+        else {
+            panic!("expected PUBLISH");
+        };
+
+        // One message is handled and the rest fill the backlog, the last one is not read.
+        for _ in 0..MAX_WAITING_MESSAGES + 2 {
+            let blocked = Publish::new("blocked", QoS::AtMostOnce, "");
+            connection.send(&Packet::Publish(blocked)).unwrap().await;
+        }
+        connection
+            .send(&Packet::PubAck(PubAck::new(publish.pkid)))
+            .unwrap()
+            .await;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), &mut published)
+                .await
+                .is_err()
+        );
+
+        gate.cancel();
+        published.await.unwrap().unwrap();
+        let (_, packet) = tokio::join!(client.shutdown(), async move {
+            connection.recv().await.unwrap()
+        });
+        assert!(matches!(
+            packet,
+            Some(Incoming::Packet(Packet::Disconnect(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn zero_concurrency_limits_are_rejected() {
+        let mut builder = ClientBuilder::new("127.0.0.1:1");
+        builder.set_handler_concurrency_limit(0);
+        let client = builder.build(HandlerRouterBuilder::new().build()).await;
+        assert!(matches!(client, Err(Error::InvalidConfig(_))));
+
+        let mut router = HandlerRouterBuilder::new();
+        router.add_with_options(
+            "limited",
+            |_: Publish| async {},
+            RouteOptions::new().concurrency_limit(0),
+        );
+        let client = ClientBuilder::new("127.0.0.1:1")
+            .build(router.build())
+            .await;
+        assert!(matches!(client, Err(Error::InvalidConfig(_))));
+    }
+
+    #[tokio::test]
+    async fn exceeding_receive_maximum_disconnects() {
+        let mut router = HandlerRouterBuilder::new();
+        router.add("unacknowledged", |shutdown: CancellationToken| async move {
+            shutdown.cancelled().await;
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut builder = ClientBuilder::new(listener.local_addr().unwrap());
+        builder.set_receive_maximum(1);
+        let (client, connection) = tokio::join!(
+            builder.build(router.build()),
+            accept_subscription(&listener)
+        );
+        let _client = client.unwrap();
+
+        for pkid in 1..=2 {
+            let mut publish = Publish::new("unacknowledged", QoS::AtLeastOnce, "");
+            publish.pkid = pkid;
+            connection.send(&Packet::Publish(publish)).unwrap().await;
+        }
+        let Some(Incoming::Packet(Packet::Disconnect(disconnect))) =
+            connection.recv().await.unwrap()
+        else {
+            panic!("expected DISCONNECT");
+        };
+        assert_eq!(
+            disconnect.reason_code,
+            DisconnectReasonCode::ReceiveMaximumExceeded
+        );
+    }
+
+    #[tokio::test]
+    async fn dropped_ack_frees_its_receive_maximum_slot() {
+        let mut router = HandlerRouterBuilder::new();
+        // The second message is handled only after the first one is fully processed.
+        router.order(MessageOrder::PerTopic);
+        router.add("topic", |publish: Publish, ack: Ack| async move {
+            if &publish.payload[..] == b"acknowledged" {
+                ack.ack();
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut builder = ClientBuilder::new(listener.local_addr().unwrap());
+        builder.set_receive_maximum(1);
+        let (client, connection) = tokio::join!(
+            builder.build(router.build()),
+            accept_subscription(&listener)
+        );
+        let client = client.unwrap();
+
+        for (pkid, payload) in [(1, "dropped"), (2, "acknowledged")] {
+            let mut publish = Publish::new("topic", QoS::AtLeastOnce, payload);
+            publish.pkid = pkid;
+            connection.send(&Packet::Publish(publish)).unwrap().await;
+        }
+        let Some(Incoming::Packet(Packet::PubAck(puback))) = connection.recv().await.unwrap()
+        else {
+            panic!("expected PUBACK");
+        };
+        assert_eq!(puback.pkid, 2);
+
+        let (_, packet) = tokio::join!(client.shutdown(), async move {
+            connection.recv().await.unwrap()
+        });
+        assert!(matches!(
+            packet,
+            Some(Incoming::Packet(Packet::Disconnect(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn messages_of_a_discarded_session_do_not_count_against_receive_maximum() {
+        let (started_sender, mut started) = mpsc::unbounded_channel();
+        let mut router = HandlerRouterBuilder::new();
+        router.add("held", move |shutdown: CancellationToken| {
+            let _ = started_sender.send(());
+            async move { shutdown.cancelled().await }
+        });
+        router.add("free", |_: Publish| async {});
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut builder = ClientBuilder::new(listener.local_addr().unwrap());
+        builder
+            .set_receive_maximum(1)
+            .set_reconnect_policy(ReconnectPolicy::Backoff {
+                initial_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(10),
+            });
+        let (client, connection) = tokio::join!(
+            builder.build(router.build()),
+            accept_subscription(&listener)
+        );
+        let client = client.unwrap();
+
+        let mut publish = Publish::new("held", QoS::AtLeastOnce, "");
+        publish.pkid = 1;
+        connection.send(&Packet::Publish(publish)).unwrap().await;
+        started.recv().await.unwrap();
+        connection.shutdown().await.unwrap();
+
+        // The broker did not keep the session, so the held message is never redelivered.
+        let connection = accept_subscription(&listener).await;
+        let mut publish = Publish::new("free", QoS::AtLeastOnce, "");
+        publish.pkid = 2;
+        connection.send(&Packet::Publish(publish)).unwrap().await;
+        let Some(Incoming::Packet(Packet::PubAck(puback))) = connection.recv().await.unwrap()
+        else {
+            panic!("expected PUBACK");
+        };
+        assert_eq!(puback.pkid, 2);
+
+        // The held message is not acknowledged on the new session.
+        let (_, packet) = tokio::join!(
+            client.shutdown_graceful(Duration::from_secs(1)),
+            async move { connection.recv().await.unwrap() }
+        );
+        assert!(matches!(
+            packet,
+            Some(Incoming::Packet(Packet::Disconnect(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn stored_message_too_large_for_the_broker_is_dropped() {
+        let mut store = MemoryStore::new();
+        let mut publish = Publish::new("large", QoS::AtLeastOnce, vec![0; 1024]);
+        publish.pkid = 1;
+        store.add_outgoing(&publish).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let broker = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let connection =
+                Connection::with_stream(Box::new(stream), DEFAULT_MAX_PACKET_SIZE, Protocol::V5);
+            let Some(Incoming::Packet(Packet::Connect(_))) = connection.recv().await.unwrap()
+            else {
+                panic!("expected CONNECT");
+            };
+            let mut connack = ConnAck::new(ConnectReturnCode::Success, true);
+            let mut properties = ConnAckProperties::new();
+            properties.max_packet_size = Some(128);
+            connack.properties = Some(properties);
+            connection.send(&Packet::ConnAck(connack)).unwrap().await;
+            connection
+        };
+        let mut builder = ClientBuilder::new(listener.local_addr().unwrap());
+        builder.set_clean_session(false).set_session_store(store);
+        let (client, connection) =
+            tokio::join!(builder.build(HandlerRouterBuilder::new().build()), broker);
+        let client = client.unwrap();
+
+        let (report, packet) = tokio::join!(
+            client.shutdown_graceful(Duration::from_millis(100)),
+            async move { connection.recv().await.unwrap() }
+        );
+        assert_eq!(report.unacknowledged_messages, 0);
+        assert!(matches!(
+            packet,
+            Some(Incoming::Packet(Packet::Disconnect(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn refused_connack_keeps_stored_messages() {
+        let mut store = MemoryStore::new();
+        let mut publish = Publish::new("stored", QoS::AtLeastOnce, "payload");
+        publish.pkid = 1;
+        store.add_outgoing(&publish).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let answer = |code, session_present| {
+            let listener = &listener;
+            async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let connection = Connection::with_stream(
+                    Box::new(stream),
+                    DEFAULT_MAX_PACKET_SIZE,
+                    Protocol::V5,
+                );
+                let Some(Incoming::Packet(Packet::Connect(_))) = connection.recv().await.unwrap()
+                else {
+                    panic!("expected CONNECT");
+                };
+                let mut connack = ConnAck::new(code, session_present);
+                connack.properties = Some(ConnAckProperties::new());
+                connection.send(&Packet::ConnAck(connack)).unwrap().await;
+                connection
+            }
+        };
+        let mut builder = ClientBuilder::new(listener.local_addr().unwrap());
+        builder
+            .set_clean_session(false)
+            .set_session_store(store)
+            .set_reconnect_policy(ReconnectPolicy::Backoff {
+                initial_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(10),
+            });
+        let (client, refusing) = tokio::join!(
+            builder.build(HandlerRouterBuilder::new().build()),
+            answer(ConnectReturnCode::NotAuthorized, false)
+        );
+        let client = client.unwrap();
+        refusing.shutdown().await.unwrap();
+
+        let connection = answer(ConnectReturnCode::Success, true).await;
+        let Some(Incoming::Packet(Packet::Publish(retransmitted))) =
+            connection.recv().await.unwrap()
+        else {
+            panic!("expected PUBLISH");
+        };
+        assert_eq!((retransmitted.pkid, retransmitted.dup), (1, true));
+        connection
+            .send(&Packet::PubAck(PubAck::new(1)))
+            .unwrap()
+            .await;
+
+        let (_, packet) = tokio::join!(client.shutdown(), async move {
+            connection.recv().await.unwrap()
+        });
+        assert!(matches!(
+            packet,
+            Some(Incoming::Packet(Packet::Disconnect(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn message_which_cannot_be_sent_releases_its_slot() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let broker = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let connection =
+                Connection::with_stream(Box::new(stream), DEFAULT_MAX_PACKET_SIZE, Protocol::V5);
+            let Some(Incoming::Packet(Packet::Connect(_))) = connection.recv().await.unwrap()
+            else {
+                panic!("expected CONNECT");
+            };
+            let mut connack = ConnAck::new(ConnectReturnCode::Success, false);
+            let mut properties = ConnAckProperties::new();
+            properties.receive_max = Some(1);
+            properties.topic_alias_max = Some(1);
+            // Fits a message with 20 bytes of payload, but not once the topic alias is added.
+            properties.max_packet_size = Some(28);
+            connack.properties = Some(properties);
+            connection.send(&Packet::ConnAck(connack)).unwrap().await;
+            connection
+        };
+        let mut builder = ClientBuilder::new(listener.local_addr().unwrap());
+        builder
+            .set_outgoing_topic_aliases(true)
+            .set_flow_control(FlowControl::FailFast);
+        let (client, connection) =
+            tokio::join!(builder.build(HandlerRouterBuilder::new().build()), broker);
+        let client = client.unwrap();
+
+        assert!(matches!(
+            client.publish("t", QoS::AtLeastOnce, &[0; 20]).await,
+            Err(Error::PacketTooLarge { .. })
+        ));
+        for topic in ["t", ""] {
+            let (published, ()) =
+                tokio::join!(client.publish("t", QoS::AtLeastOnce, &[0; 10]), async {
+                    let Some(Incoming::Packet(Packet::Publish(publish))) =
+                        connection.recv().await.unwrap()
+                    else {
+                        panic!("expected PUBLISH");
+                    };
+                    assert_eq!(publish.topic, topic);
+                    connection
+                        .send(&Packet::PubAck(PubAck::new(publish.pkid)))
+                        .unwrap()
+                        .await;
+                });
+            published.unwrap();
+        }
+
+        let (_, packet) = tokio::join!(client.shutdown(), async move {
+            connection.recv().await.unwrap()
+        });
+        assert!(matches!(
+            packet,
+            Some(Incoming::Packet(Packet::Disconnect(_)))
+        ));
+    }
+
+    // Fails to start if asked to, answers challenges with more data than the broker accepts.
+    struct TestAuthenticator {
+        fails: bool,
+    }
+
+    impl Authenticator for TestAuthenticator {
+        fn method(&self) -> &str {
+            "test"
+        }
+
+        fn start(&mut self) -> Result<Option<Bytes>, Error> {
+            if self.fails {
+                return Err(Error::Authentication("no credentials".to_owned()));
+            }
+            Ok(None)
+        }
+
+        fn challenge(&mut self, _data: Option<Bytes>) -> Result<Option<Bytes>, Error> {
+            Ok(Some(Bytes::from(vec![0; 1024])))
+        }
+    }
+
+    #[tokio::test]
+    async fn authenticator_which_cannot_start_fails_build() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut builder = ClientBuilder::new(listener.local_addr().unwrap());
+        builder.set_authenticator(TestAuthenticator { fails: true });
+        let (client, _) = tokio::join!(
+            builder.build(HandlerRouterBuilder::new().build()),
+            listener.accept()
+        );
+        assert!(matches!(client, Err(Error::Authentication(_))));
+    }
+
+    #[tokio::test]
+    async fn auth_which_cannot_be_sent_disconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let broker = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let connection =
+                Connection::with_stream(Box::new(stream), DEFAULT_MAX_PACKET_SIZE, Protocol::V5);
+            let Some(Incoming::Packet(Packet::Connect(_))) = connection.recv().await.unwrap()
+            else {
+                panic!("expected CONNECT");
+            };
+            let mut connack = ConnAck::new(ConnectReturnCode::Success, false);
+            let mut properties = ConnAckProperties::new();
+            properties.max_packet_size = Some(128);
+            connack.properties = Some(properties);
+            connection.send(&Packet::ConnAck(connack)).unwrap().await;
+            connection
+        };
+        let mut builder = ClientBuilder::new(listener.local_addr().unwrap());
+        builder.set_authenticator(TestAuthenticator { fails: false });
+        let (client, connection) =
+            tokio::join!(builder.build(HandlerRouterBuilder::new().build()), broker);
+        let _client = client.unwrap();
+
+        let challenge = Auth::new(AuthReason::ContinueAuthentication, "test".to_owned(), None);
+        connection.send_auth(&challenge).unwrap().await;
+        let Some(Incoming::Packet(Packet::Disconnect(disconnect))) =
+            connection.recv().await.unwrap()
+        else {
+            panic!("expected DISCONNECT");
+        };
+        assert_eq!(
+            disconnect.reason_code,
+            DisconnectReasonCode::ImplementationSpecificError
+        );
+    }
+
+    #[tokio::test]
+    async fn unanswered_ping_closes_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let mut builder = ClientBuilder::new(listener.local_addr().unwrap());
+        builder.set_keep_alive(1u16);
+        let (client, connection) = tokio::join!(
+            builder.build(HandlerRouterBuilder::new().build()),
+            accept(&listener)
+        );
+        let client = client.unwrap();
+        let mut events = pin!(client.events());
+
+        let Some(Incoming::Packet(Packet::PingReq)) = connection.recv().await.unwrap() else {
+            panic!("expected PINGREQ");
+        };
+        for expected in [
+            ConnectionEvent::KeepAliveTimeout,
+            ConnectionEvent::ConnectionLost,
+            ConnectionEvent::Disconnected,
+        ] {
+            let event = poll_fn(|cx| events.as_mut().poll_next(cx)).await;
+            assert_eq!(event, Some(expected));
+        }
+        assert_eq!(client.state().await, ConnectionState::Disconnected);
+    }
+
+    #[tokio::test]
+    async fn publishes_and_subscribes_over_mqtt_3_1_1() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let mut builder = ClientBuilder::new(listener.local_addr().unwrap());
+        builder.set_protocol(Protocol::V4);
+        let (client, connection) = tokio::join!(
+            builder.build(HandlerRouterBuilder::new().build()),
+            accept_with(&listener, Protocol::V4)
+        );
+        let client = client.unwrap();
+
+        let broker = tokio::spawn(async move {
+            let Some(Incoming::Packet(Packet::Subscribe(subscribe))) =
+                connection.recv().await.unwrap()
+            else {
+                panic!("expected SUBSCRIBE");
+            };
+            let suback = SubAck::new(subscribe.pkid, vec![SubscribeReasonCode::QoS1]);
+            connection.send(&Packet::SubAck(suback)).unwrap().await;
+
+            let Some(Incoming::Packet(Packet::Publish(publish))) = connection.recv().await.unwrap()
+            else {
+                panic!("expected PUBLISH");
+            };
+            connection
+                .send(&Packet::PubAck(PubAck::new(publish.pkid)))
+                .unwrap()
+                .await;
+            connection.recv().await.unwrap()
+        });
+
+        client.subscribe("topic").await.unwrap();
+        client
+            .publish("topic", QoS::AtLeastOnce, b"payload")
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.reauthenticate().await,
+            Err(Error::UnsupportedByProtocol(_))
+        ));
+        client.shutdown().await;
+        assert!(matches!(
+            broker.await.unwrap(),
+            Some(Incoming::Packet(Packet::Disconnect(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown_reports_unacknowledged_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let (client, connection) = tokio::join!(
+            ClientBuilder::new(listener.local_addr().unwrap())
+                .build(HandlerRouterBuilder::new().build()),
+            accept(&listener)
+        );
+        let client = client.unwrap();
+        let publisher = client.clone();
+        let publish = tokio::spawn(async move {
+            publisher
+                .publish("topic", QoS::AtLeastOnce, b"payload")
+                .await
+        });
+        let Some(Incoming::Packet(Packet::Publish(_))) = connection.recv().await.unwrap() else {
+            panic!("expected PUBLISH");
+        };
+
+        let (report, packet) = tokio::join!(
+            client.clone().shutdown_graceful_with(
+                Duration::from_millis(50),
+                DisconnectReasonCode::DisconnectWithWillMessage
+            ),
+            // The broker never acknowledges the message.
+            connection.recv()
+        );
+        assert_eq!(
+            report,
+            ShutdownReport {
+                unfinished_handlers: 0,
+                unacknowledged_messages: 1,
+                queued_messages: 0,
+            }
+        );
+        let Some(Incoming::Packet(Packet::Disconnect(disconnect))) = packet.unwrap() else {
+            panic!("expected DISCONNECT");
+        };
+        assert_eq!(
+            disconnect.reason_code,
+            DisconnectReasonCode::DisconnectWithWillMessage
+        );
+        assert!(matches!(
+            client.publish("topic", QoS::AtMostOnce, b"payload").await,
+            Err(Error::ShuttingDown)
+        ));
+        publish.abort();
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown_with_will_over_mqtt_3_1_1_does_not_disconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let mut builder = ClientBuilder::new(listener.local_addr().unwrap());
+        builder.set_protocol(Protocol::V4);
+        let (client, connection) = tokio::join!(
+            builder.build(HandlerRouterBuilder::new().build()),
+            accept_with(&listener, Protocol::V4)
+        );
+        let client = client.unwrap();
+
+        let (report, packet) = tokio::join!(
+            client.shutdown_graceful_with(
+                Duration::from_millis(50),
+                DisconnectReasonCode::DisconnectWithWillMessage
+            ),
+            connection.recv()
+        );
+        assert!(report.is_clean());
+        assert!(matches!(packet, Ok(None)));
+    }
 }
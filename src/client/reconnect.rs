@@ -0,0 +1,449 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use mqttbytes::v5::DisconnectReasonCode;
+use serde::Deserialize;
+use tokio::net::{lookup_host, TcpStream, ToSocketAddrs};
+
+use crate::connection::BoxedTransport;
+
+use super::ServerDisconnect;
+
+/// Opens connections to something other than a TCP address, e.g. an in-memory broker.
+pub(crate) type Connector =
+    Arc<dyn Fn() -> Pin<Box<dyn Future<Output = io::Result<BoxedTransport>> + Send>> + Send + Sync>;
+
+// Resolves the address the client was built with.
+type Lookup = Arc<
+    dyn Fn() -> Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send>> + Send + Sync,
+>;
+
+/// Whether and how often the client connects again after it loses the connection to the broker.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ReconnectPolicy {
+    #[default]
+    Never,
+    /// Waits `initial_delay` before the first attempt and doubles the delay after every failed attempt, up to `max_delay`.
+    Backoff {
+        initial_delay: Duration,
+        max_delay: Duration,
+    },
+}
+
+impl ReconnectPolicy {
+    /// Delay before the given attempt, starting at 0, or `None` if the client should not reconnect.
+    pub(crate) fn delay(&self, attempt: u32) -> Option<Duration> {
+        match self {
+            Self::Never => None,
+            Self::Backoff {
+                initial_delay,
+                max_delay,
+            } => {
+                let factor = 2u32.saturating_pow(attempt);
+                Some(initial_delay.saturating_mul(factor).min(*max_delay))
+            }
+        }
+    }
+}
+
+/// Order in which the client tries the endpoints of a broker cluster, see [`ClientBuilder::add_endpoint`](super::ClientBuilder::add_endpoint).
+///
+/// Endpoints which recently could not be reached are tried after all the others regardless of the strategy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndpointStrategy {
+    /// Starts with the first endpoint and falls back to the next ones in order.
+    #[default]
+    Failover,
+    /// Starts with the endpoint after the one connected to last, spreading reconnecting clients across the cluster.
+    RoundRobin,
+    /// Tries the endpoints in a random order.
+    Random,
+}
+
+/// How long an unreachable endpoint is tried last, doubled with every consecutive failure.
+const UNHEALTHY_DELAY: Duration = Duration::from_secs(1);
+const MAX_UNHEALTHY_DELAY: Duration = Duration::from_secs(60);
+
+pub(crate) const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Addresses of the broker, changed by redirects from the broker.
+pub(crate) struct Endpoints {
+    endpoints: Vec<Endpoint>,
+    strategy: EndpointStrategy,
+    // Index of the endpoint connected to last.
+    current: Option<usize>,
+    // Port of the server connected to last, used for redirects to a host without a port.
+    port: Option<u16>,
+    connector: Option<Connector>,
+    redirect: Option<Redirect>,
+    connect_timeout: Duration,
+    #[cfg(feature = "tls")]
+    tls: Option<super::TlsConfig>,
+}
+
+struct Redirect {
+    reference: String,
+    permanent: bool,
+}
+
+struct Endpoint {
+    target: Target,
+    failures: u32,
+    unhealthy_until: Option<Instant>,
+}
+
+enum Target {
+    /// Address the client was built with, resolved on every attempt. The certificate is verified for the name in the TLS configuration.
+    Address { lookup: Lookup, name: String },
+    /// `host:port` resolved on every attempt, the certificate is verified for the host.
+    Host(String),
+}
+
+impl Endpoint {
+    fn new(target: Target) -> Self {
+        Self {
+            target,
+            failures: 0,
+            unhealthy_until: None,
+        }
+    }
+
+    fn failed(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+        let delay = UNHEALTHY_DELAY
+            .saturating_mul(2u32.saturating_pow(self.failures - 1))
+            .min(MAX_UNHEALTHY_DELAY);
+        self.unhealthy_until = Some(Instant::now() + delay);
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Address { name, .. } => f.write_str(name),
+            Self::Host(reference) => f.write_str(reference),
+        }
+    }
+}
+
+impl Endpoints {
+    pub(crate) fn new<Address>(address: Address) -> Self
+    where
+        Address: ToSocketAddrs + Clone + fmt::Debug + Send + Sync + 'static,
+    {
+        let name = format!("{address:?}");
+        let lookup: Lookup = Arc::new(move || {
+            let address = address.clone();
+            Box::pin(async move { Ok(lookup_host(address).await?.collect()) })
+        });
+        let mut endpoints = Self::empty();
+        endpoints
+            .endpoints
+            .push(Endpoint::new(Target::Address { lookup, name }));
+        endpoints
+    }
+
+    #[cfg(feature = "testing")]
+    pub(crate) fn with_connector(connector: Connector) -> Self {
+        let mut endpoints = Self::empty();
+        endpoints.connector = Some(connector);
+        endpoints
+    }
+
+    fn empty() -> Self {
+        Self {
+            endpoints: Vec::new(),
+            strategy: EndpointStrategy::default(),
+            current: None,
+            port: None,
+            connector: None,
+            redirect: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Adds a `host:port` endpoint tried after the existing ones.
+    pub(crate) fn add(&mut self, reference: String) {
+        self.endpoints.push(Endpoint::new(Target::Host(reference)));
+    }
+
+    pub(crate) fn set_strategy(&mut self, strategy: EndpointStrategy) {
+        self.strategy = strategy;
+    }
+
+    pub(crate) fn set_connect_timeout(&mut self, connect_timeout: Duration) {
+        self.connect_timeout = connect_timeout;
+    }
+
+    #[cfg(feature = "tls")]
+    pub(crate) fn set_tls(&mut self, tls: super::TlsConfig) {
+        self.tls = Some(tls);
+    }
+
+    /// Makes the next attempt connect to the server referenced by the broker, if any.
+    pub(crate) fn redirect(&mut self, disconnect: &ServerDisconnect) {
+        let permanent = match disconnect.reason_code {
+            DisconnectReasonCode::UseAnotherServer => false,
+            DisconnectReasonCode::ServerMoved => true,
+            _ => return,
+        };
+        // The reference may list several servers separated by spaces.
+        let Some(reference) = disconnect
+            .server_reference
+            .as_deref()
+            .and_then(|reference| reference.split_whitespace().next())
+        else {
+            tracing::warn!(reason_code = ?disconnect.reason_code, "Broker redirected the client without a server reference.");
+            return;
+        };
+
+        let reference = match self.port {
+            Some(port) if !reference.contains(':') => format!("{reference}:{port}"),
+            _ => reference.to_owned(),
+        };
+        tracing::info!(reference, permanent, "Broker redirected the client.");
+        self.redirect = Some(Redirect {
+            reference,
+            permanent,
+        });
+    }
+
+    /// Records that the broker at the current endpoint refused the connection, so that it is tried last for a while.
+    pub(crate) fn refused(&mut self) {
+        if let Some(endpoint) = self.current.and_then(|index| self.endpoints.get_mut(index)) {
+            endpoint.failed();
+        }
+    }
+
+    /// Connects to the first endpoint which can be reached, in the order given by the strategy.
+    pub(crate) async fn connect(&mut self) -> io::Result<BoxedTransport> {
+        if let Some(redirect) = self.redirect.take() {
+            let target = Target::Host(redirect.reference);
+            let (stream, peer) = self.open(&target).await?;
+            self.port = Some(peer.port());
+            if redirect.permanent {
+                // The server moved, the endpoint connected to last is replaced by the new one.
+                let index = self.current.unwrap_or(0);
+                match self.endpoints.get_mut(index) {
+                    Some(endpoint) => *endpoint = Endpoint::new(target),
+                    None => self.endpoints.push(Endpoint::new(target)),
+                }
+                self.current = Some(index.min(self.endpoints.len() - 1));
+                self.connector = None;
+            }
+            return Ok(stream);
+        }
+
+        if let Some(connector) = &self.connector {
+            return connector().await;
+        }
+
+        let mut last_error = None;
+        for index in self.order() {
+            let endpoint = &self.endpoints[index];
+            match self.open(&endpoint.target).await {
+                Ok((stream, peer)) => {
+                    let endpoint = &mut self.endpoints[index];
+                    endpoint.failures = 0;
+                    endpoint.unhealthy_until = None;
+                    self.current = Some(index);
+                    self.port = Some(peer.port());
+                    return Ok(stream);
+                }
+                Err(error) => {
+                    let endpoint = &mut self.endpoints[index];
+                    tracing::warn!(%error, endpoint = %endpoint.target, "Unable to connect to endpoint.");
+                    endpoint.failed();
+                    last_error = Some(error);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no endpoints to connect to")
+        }))
+    }
+
+    // Indices of the endpoints in the order they are tried.
+    fn order(&self) -> Vec<usize> {
+        let count = self.endpoints.len();
+        let mut order: Vec<_> = match self.strategy {
+            EndpointStrategy::Failover => (0..count).collect(),
+            EndpointStrategy::RoundRobin => {
+                let start = self.current.map_or(0, |current| current + 1);
+                (0..count).map(|offset| (start + offset) % count).collect()
+            }
+            EndpointStrategy::Random => {
+                let mut order: Vec<_> = (0..count).collect();
+                for index in (1..count).rev() {
+                    // Hashers are randomly seeded, which is random enough to spread clients.
+                    let random = RandomState::new().build_hasher().finish();
+                    order.swap(index, (random % (index as u64 + 1)) as usize);
+                }
+                order
+            }
+        };
+        // The sort is stable, healthy endpoints keep their order and unhealthy ones are tried in the order they recover.
+        let now = Instant::now();
+        order.sort_by_key(|&index| {
+            self.endpoints[index]
+                .unhealthy_until
+                .filter(|&until| until > now)
+        });
+        order
+    }
+
+    // Returns the connection and the address of the server, the TLS handshake counts towards the timeout.
+    async fn open(&self, target: &Target) -> io::Result<(BoxedTransport, SocketAddr)> {
+        let open = async {
+            let (stream, server_name) = match target {
+                Target::Address { lookup, .. } => {
+                    let addresses = lookup().await?;
+                    (TcpStream::connect(&*addresses).await?, None)
+                }
+                Target::Host(reference) => {
+                    let stream = TcpStream::connect(reference.as_str()).await?;
+                    let host = match reference.rsplit_once(':') {
+                        Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+                        None => reference,
+                    };
+                    (stream, Some(host))
+                }
+            };
+            let peer = stream.peer_addr()?;
+            Ok((self.secure(stream, server_name).await?, peer))
+        };
+
+        tokio::time::timeout(self.connect_timeout, open)
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("connecting to {target} timed out"),
+                ))
+            })
+    }
+
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+    async fn secure(
+        &self,
+        stream: TcpStream,
+        server_name: Option<&str>,
+    ) -> io::Result<BoxedTransport> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            return Ok(Box::new(tls.connect(stream, server_name).await?));
+        }
+        Ok(Box::new(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqttbytes::v5::{Disconnect, DisconnectProperties};
+
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_maximum() {
+        let policy = ReconnectPolicy::Backoff {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+        };
+
+        let delays: Vec<_> = (0..5)
+            .map(|attempt| policy.delay(attempt).unwrap())
+            .collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5].map(Duration::from_secs));
+        assert_eq!(policy.delay(u32::MAX), Some(Duration::from_secs(5)));
+        assert_eq!(ReconnectPolicy::Never.delay(0), None);
+    }
+
+    #[tokio::test]
+    async fn server_moved_replaces_addresses() {
+        let old = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let new = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let new_address = new.local_addr().unwrap();
+
+        let mut endpoints = Endpoints::new(old.local_addr().unwrap());
+        let mut disconnect = Disconnect::new();
+        disconnect.reason_code = DisconnectReasonCode::ServerMoved;
+        disconnect.properties = Some(DisconnectProperties {
+            session_expiry_interval: None,
+            reason_string: None,
+            user_properties: Vec::new(),
+            server_reference: Some(format!("{new_address} 192.0.2.1:1883")),
+        });
+        endpoints.redirect(&disconnect.into());
+
+        for _ in 0..2 {
+            let _stream = endpoints.connect().await.unwrap();
+            tokio::time::timeout(Duration::from_secs(1), new.accept())
+                .await
+                .expect("connected to the new address")
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_endpoints_are_tried_last() {
+        let dead = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead_address = dead.local_addr().unwrap();
+        drop(dead);
+        let first = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let mut endpoints = Endpoints::new(dead_address);
+        endpoints.add(first.local_addr().unwrap().to_string());
+        endpoints.add(second.local_addr().unwrap().to_string());
+
+        async fn accepted(listener: &tokio::net::TcpListener) {
+            tokio::time::timeout(Duration::from_secs(1), listener.accept())
+                .await
+                .expect("connected to the endpoint")
+                .unwrap();
+        }
+
+        let _stream = endpoints.connect().await.unwrap();
+        accepted(&first).await;
+        assert_eq!(endpoints.order(), [1, 2, 0]);
+
+        endpoints.set_strategy(EndpointStrategy::RoundRobin);
+        let _stream = endpoints.connect().await.unwrap();
+        accepted(&second).await;
+        // Starts again at the unreachable endpoint, which is skipped.
+        let _stream = endpoints.connect().await.unwrap();
+        accepted(&first).await;
+
+        endpoints.set_strategy(EndpointStrategy::Random);
+        let mut order = endpoints.order();
+        assert_eq!(order.pop(), Some(0));
+        order.sort();
+        assert_eq!(order, [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn connecting_times_out() {
+        let lookup: Lookup = Arc::new(|| Box::pin(std::future::pending()));
+        let mut endpoints = Endpoints::empty();
+        endpoints.endpoints.push(Endpoint::new(Target::Address {
+            lookup,
+            name: "unresponsive".to_owned(),
+        }));
+        endpoints.set_connect_timeout(Duration::from_millis(10));
+
+        let error = endpoints.connect().await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+}
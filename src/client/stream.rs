@@ -0,0 +1,44 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_core::Stream;
+use mqttbytes::v5::Publish;
+use tokio::sync::mpsc;
+
+use super::RouteGuard;
+use crate::Ack;
+
+pub(super) const CAPACITY: usize = 16;
+
+/// Stream of messages returned by [`Client::subscribe_stream`](super::Client::subscribe_stream).
+pub struct SubscriptionStream {
+    receiver: mpsc::Receiver<(Publish, Ack)>,
+    _guard: RouteGuard,
+}
+
+impl SubscriptionStream {
+    pub(super) fn new(receiver: mpsc::Receiver<(Publish, Ack)>, guard: RouteGuard) -> Self {
+        Self {
+            receiver,
+            _guard: guard,
+        }
+    }
+}
+
+impl Stream for SubscriptionStream {
+    type Item = Publish;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match this.receiver.poll_recv(cx) {
+            Poll::Ready(Some((publish, ack))) => {
+                ack.ack();
+                Poll::Ready(Some(publish))
+            }
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
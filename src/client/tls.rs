@@ -0,0 +1,179 @@
+use std::{io, path::Path, sync::Arc};
+
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{
+        self,
+        crypto::ring,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+        ClientConfig, RootCertStore,
+    },
+    TlsConnector,
+};
+
+use crate::Error;
+
+/// Encrypts the connection to the broker, see [`ClientBuilder::set_tls`](super::ClientBuilder::set_tls). Enabled by the `tls` feature.
+///
+/// The broker's certificate is verified for the server name, by default against the Mozilla root certificates.
+#[derive(Clone)]
+pub struct TlsConfig {
+    server_name: String,
+    roots: Arc<RootCertStore>,
+    config: Arc<ClientConfig>,
+}
+
+impl TlsConfig {
+    pub fn new(server_name: impl Into<String>) -> Self {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let config = client_config(roots.clone(), None)
+            .expect("Configuration without a client certificate is valid.");
+        Self {
+            server_name: server_name.into(),
+            roots: Arc::new(roots),
+            config: Arc::new(config),
+        }
+    }
+
+    /// Trusts only the certificate authorities in the PEM file, e.g. for a broker with a self-signed certificate.
+    pub fn with_ca_file(
+        server_name: impl Into<String>,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, Error> {
+        let mut roots = RootCertStore::empty();
+        for certificate in read_certificates(path.as_ref())? {
+            roots
+                .add(certificate)
+                .map_err(|error| Error::Tls(error.to_string()))?;
+        }
+        let config =
+            client_config(roots.clone(), None).map_err(|error| Error::Tls(error.to_string()))?;
+        Ok(Self {
+            server_name: server_name.into(),
+            roots: Arc::new(roots),
+            config: Arc::new(config),
+        })
+    }
+
+    /// Authenticates the client with the certificate chain and private key in the PEM files.
+    pub fn set_client_certificate(
+        &mut self,
+        certificate: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+    ) -> Result<&mut Self, Error> {
+        let chain = read_certificates(certificate.as_ref())?;
+        let key = PrivateKeyDer::from_pem_file(key.as_ref())
+            .map_err(|error| Error::Tls(format!("{}: {error}", key.as_ref().display())))?;
+        let config = client_config(RootCertStore::clone(&self.roots), Some((chain, key)))
+            .map_err(|error| Error::Tls(error.to_string()))?;
+        self.config = Arc::new(config);
+        Ok(self)
+    }
+
+    /// Starts the TLS session, `server_name` overrides the configured name, e.g. after a redirect.
+    pub(crate) async fn connect(
+        &self,
+        stream: TcpStream,
+        server_name: Option<&str>,
+    ) -> io::Result<TlsStream<TcpStream>> {
+        let server_name = ServerName::try_from(server_name.unwrap_or(&self.server_name))
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?
+            .to_owned();
+        TlsConnector::from(self.config.clone())
+            .connect(server_name, stream)
+            .await
+    }
+}
+
+fn client_config(
+    roots: RootCertStore,
+    client_certificate: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+) -> Result<ClientConfig, rustls::Error> {
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+    match client_certificate {
+        Some((chain, key)) => builder.with_client_auth_cert(chain, key),
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|error| Error::Tls(format!("{}: {error}", path.display())))?;
+    if certificates.is_empty() {
+        return Err(Error::Tls(format!(
+            "{}: no certificates found",
+            path.display()
+        )));
+    }
+    Ok(certificates)
+}
+
+#[cfg(test)]
+mod tests {
+    use mqttbytes::{
+        v5::{ConnAck, ConnAckProperties, ConnectReturnCode, Packet},
+        Protocol,
+    };
+    use tokio::net::TcpListener;
+    use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+    use super::*;
+    use crate::{
+        connection::{Connection, Incoming, DEFAULT_MAX_PACKET_SIZE},
+        ClientBuilder, HandlerRouterBuilder,
+    };
+
+    #[tokio::test]
+    async fn connects_to_broker_with_self_signed_certificate() {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let path = std::env::temp_dir().join(format!("qute-ca-{}.pem", std::process::id()));
+        std::fs::write(&path, certified.cert.pem()).unwrap();
+        let tls = TlsConfig::with_ca_file("localhost", &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![certified.cert.der().clone()],
+                PrivateKeyDer::Pkcs8(certified.key_pair.serialize_der().into()),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let broker = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+            let connection =
+                Connection::with_stream(Box::new(stream), DEFAULT_MAX_PACKET_SIZE, Protocol::V5);
+            let Some(Incoming::Packet(Packet::Connect(_))) = connection.recv().await.unwrap()
+            else {
+                panic!("expected CONNECT");
+            };
+            let mut connack = ConnAck::new(ConnectReturnCode::Success, false);
+            connack.properties = Some(ConnAckProperties::new());
+            connection.send(&Packet::ConnAck(connack)).unwrap().await;
+            connection
+        };
+        let mut builder = ClientBuilder::new(listener.local_addr().unwrap());
+        builder.set_tls(tls);
+        let (client, connection) =
+            tokio::join!(builder.build(HandlerRouterBuilder::new().build()), broker);
+        let client = client.unwrap();
+
+        let disconnect = tokio::spawn(async move { connection.recv().await.unwrap() });
+        client.shutdown().await;
+        assert!(matches!(
+            disconnect.await.unwrap(),
+            Some(Incoming::Packet(Packet::Disconnect(_)))
+        ));
+    }
+}
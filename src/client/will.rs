@@ -0,0 +1,221 @@
+use bytes::Bytes;
+use mqttbytes::{
+    v5::{LastWill, WillProperties},
+    QoS,
+};
+use serde::Serialize;
+
+use crate::Error;
+
+/// Message the broker publishes when the connection closes unexpectedly, see [`ClientBuilder::set_will`](super::ClientBuilder::set_will).
+///
+/// [`Client::shutdown`](super::Client::shutdown) disarms the will, while [`Client::shutdown_with_will`](super::Client::shutdown_with_will) makes the broker publish it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Will {
+    topic: String,
+    payload: Bytes,
+    qos: QoS,
+    retain: bool,
+    delay_interval: Option<u32>,
+    payload_format_utf8: bool,
+    message_expiry: Option<u32>,
+    content_type: Option<String>,
+    response_topic: Option<String>,
+    correlation_data: Option<Bytes>,
+    user_properties: Vec<(String, String)>,
+}
+
+impl Will {
+    /// Will published with QoS 0 and without the retain flag unless set otherwise.
+    pub fn new(topic: impl Into<String>, payload: impl Into<Bytes>) -> Self {
+        Self {
+            topic: topic.into(),
+            payload: payload.into(),
+            qos: QoS::AtMostOnce,
+            retain: false,
+            delay_interval: None,
+            payload_format_utf8: false,
+            message_expiry: None,
+            content_type: None,
+            response_topic: None,
+            correlation_data: None,
+            user_properties: Vec::new(),
+        }
+    }
+
+    /// Will with the payload serialized to JSON, marked as UTF-8 with the content type `application/json`.
+    pub fn json<T: Serialize>(topic: impl Into<String>, payload: &T) -> Result<Self, Error> {
+        let payload = serde_json::to_vec(payload).map_err(Error::Serialization)?;
+        let mut will = Self::new(topic, payload);
+        will.set_payload_format_utf8(true)
+            .set_content_type("application/json");
+        Ok(will)
+    }
+
+    pub fn set_qos(&mut self, qos: QoS) -> &mut Self {
+        self.qos = qos;
+        self
+    }
+
+    pub fn set_retain(&mut self, retain: bool) -> &mut Self {
+        self.retain = retain;
+        self
+    }
+
+    /// Seconds the broker waits before publishing the will, it is not published if the client reconnects in time.
+    ///
+    /// The will is published at the latest when the session expires.
+    pub fn set_delay_interval(&mut self, delay_interval: u32) -> &mut Self {
+        self.delay_interval = Some(delay_interval);
+        self
+    }
+
+    /// Marks the payload as UTF-8 text.
+    pub fn set_payload_format_utf8(&mut self, utf8: bool) -> &mut Self {
+        self.payload_format_utf8 = utf8;
+        self
+    }
+
+    /// Seconds after which the broker no longer delivers the published will.
+    pub fn set_message_expiry(&mut self, message_expiry: u32) -> &mut Self {
+        self.message_expiry = Some(message_expiry);
+        self
+    }
+
+    pub fn set_content_type(&mut self, content_type: impl Into<String>) -> &mut Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    pub fn set_response_topic(&mut self, response_topic: impl Into<String>) -> &mut Self {
+        self.response_topic = Some(response_topic.into());
+        self
+    }
+
+    pub fn set_correlation_data(&mut self, correlation_data: impl Into<Bytes>) -> &mut Self {
+        self.correlation_data = Some(correlation_data.into());
+        self
+    }
+
+    pub fn add_user_property(
+        &mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> &mut Self {
+        self.user_properties.push((name.into(), value.into()));
+        self
+    }
+}
+
+impl From<Will> for LastWill {
+    fn from(will: Will) -> Self {
+        let has_properties = will.delay_interval.is_some()
+            || will.payload_format_utf8
+            || will.message_expiry.is_some()
+            || will.content_type.is_some()
+            || will.response_topic.is_some()
+            || will.correlation_data.is_some()
+            || !will.user_properties.is_empty();
+        let properties = has_properties.then(|| WillProperties {
+            delay_interval: will.delay_interval,
+            payload_format_indicator: will.payload_format_utf8.then_some(1),
+            message_expiry_interval: will.message_expiry,
+            content_type: will.content_type,
+            response_topic: will.response_topic,
+            correlation_data: will.correlation_data,
+            user_properties: will.user_properties,
+        });
+
+        LastWill {
+            topic: will.topic,
+            message: will.payload,
+            qos: will.qos,
+            retain: will.retain,
+            properties,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqttbytes::{
+        v5::{ConnAck, ConnAckProperties, ConnectReturnCode, DisconnectReasonCode, Packet},
+        Protocol,
+    };
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        connection::{Connection, Incoming, DEFAULT_MAX_PACKET_SIZE},
+        ClientBuilder, HandlerRouterBuilder,
+    };
+
+    #[tokio::test]
+    async fn will_properties_are_sent_and_will_is_triggered_on_shutdown() {
+        let mut will = Will::json("status/sensor-1", &json!({ "online": false })).unwrap();
+        will.set_qos(QoS::AtLeastOnce)
+            .set_retain(true)
+            .set_delay_interval(5)
+            .set_message_expiry(60)
+            .set_response_topic("status/ack")
+            .add_user_property("reason", "crash");
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut builder = ClientBuilder::new(listener.local_addr().unwrap());
+        builder.set_will(will);
+        let broker = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let connection =
+                Connection::with_stream(Box::new(stream), DEFAULT_MAX_PACKET_SIZE, Protocol::V5);
+            let Some(Incoming::Packet(Packet::Connect(connect))) = connection.recv().await.unwrap()
+            else {
+                panic!("expected CONNECT");
+            };
+            let mut connack = ConnAck::new(ConnectReturnCode::Success, false);
+            connack.properties = Some(ConnAckProperties::new());
+            connection.send(&Packet::ConnAck(connack)).unwrap().await;
+            (connection, connect)
+        };
+        let (client, (connection, connect)) =
+            tokio::join!(builder.build(HandlerRouterBuilder::new().build()), broker);
+        let client = client.unwrap();
+
+        let will = connect.last_will.unwrap();
+        assert_eq!(
+            (
+                will.topic.as_str(),
+                &will.message[..],
+                will.qos,
+                will.retain
+            ),
+            (
+                "status/sensor-1",
+                &br#"{"online":false}"#[..],
+                QoS::AtLeastOnce,
+                true
+            )
+        );
+        let properties = will.properties.unwrap();
+        assert_eq!(properties.delay_interval, Some(5));
+        assert_eq!(properties.payload_format_indicator, Some(1));
+        assert_eq!(properties.message_expiry_interval, Some(60));
+        assert_eq!(properties.content_type.as_deref(), Some("application/json"));
+        assert_eq!(properties.response_topic.as_deref(), Some("status/ack"));
+        assert_eq!(
+            properties.user_properties,
+            [("reason".to_owned(), "crash".to_owned())]
+        );
+
+        let disconnect = tokio::spawn(async move { connection.recv().await.unwrap() });
+        client.shutdown_with_will().await;
+        let Some(Incoming::Packet(Packet::Disconnect(disconnect))) = disconnect.await.unwrap()
+        else {
+            panic!("expected DISCONNECT");
+        };
+        assert_eq!(
+            disconnect.reason_code,
+            DisconnectReasonCode::DisconnectWithWillMessage
+        );
+    }
+}
@@ -2,42 +2,182 @@ use std::{
     future::Future,
     ops::DerefMut,
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{self, AtomicUsize},
+        Arc,
+    },
     task::{ready, Context, Poll},
 };
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use mqttbytes::{v5::Packet, FixedHeader};
+use mqttbytes::{
+    v5::{Disconnect, Packet, Publish},
+    Protocol, QoS,
+};
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
-    },
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
     sync::{Mutex, OwnedMutexGuard},
 };
 
-const MAX_SIZE: usize = 1024;
+use crate::{
+    auth::packet::{Auth, AUTH_PACKET_TYPE},
+    v4, Error,
+};
+
+/// Maximum size of received packets unless configured otherwise.
+pub(crate) const DEFAULT_MAX_PACKET_SIZE: u32 = 1024 * 1024;
+
+const DISCONNECT_HEADER: u8 = 0xE0;
+
+/// Byte stream to the broker, usually a TCP connection.
+pub(crate) trait Transport: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T> Transport for T where T: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+pub(crate) type BoxedTransport = Box<dyn Transport>;
+pub(crate) type Reader = ReadHalf<BoxedTransport>;
+pub(crate) type Writer = WriteHalf<BoxedTransport>;
 
 pub(crate) struct Connection<R, W> {
     reader: Mutex<(R, BytesMut)>,
     writer: Arc<Mutex<W>>,
+    max_incoming_size: usize,
+    // Set from the broker's CONNACK, unlimited until then.
+    max_outgoing_size: AtomicUsize,
+    protocol: Protocol,
 }
 
 impl<R, W> Connection<R, W> {
-    pub fn new(reader: R, writer: W) -> Self {
+    pub fn new(reader: R, writer: W, max_packet_size: u32, protocol: Protocol) -> Self {
         Self {
             reader: Mutex::new((reader, BytesMut::new())),
             writer: Arc::new(Mutex::new(writer)),
+            max_incoming_size: max_packet_size as usize,
+            max_outgoing_size: AtomicUsize::new(usize::MAX),
+            protocol,
         }
     }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn set_max_outgoing_size(&self, max_packet_size: Option<u32>) {
+        let size = max_packet_size.map_or(usize::MAX, |size| size as usize);
+        self.max_outgoing_size
+            .store(size, atomic::Ordering::Relaxed);
+    }
+
+    /// Checks that the PUBLISH fits into the broker's maximum packet size before a packet identifier is assigned to it.
+    pub fn check_publish_size(&self, publish: &Publish) -> Result<(), Error> {
+        let mut remaining_len = publish.len();
+        if publish.qos != QoS::AtMostOnce && publish.pkid == 0 {
+            remaining_len += 2;
+        }
+        self.check_size(packet_size(remaining_len))
+    }
+
+    fn check_size(&self, size: usize) -> Result<(), Error> {
+        let maximum = self.max_outgoing_size.load(atomic::Ordering::Relaxed);
+        if size > maximum {
+            return Err(Error::PacketTooLarge { size, maximum });
+        }
+        Ok(())
+    }
+}
+
+impl Connection<Reader, Writer> {
+    pub fn with_stream(stream: BoxedTransport, max_packet_size: u32, protocol: Protocol) -> Self {
+        let (reader, writer) = split(stream);
+        Self::new(reader, writer, max_packet_size, protocol)
+    }
+}
+
+pub(crate) fn split(stream: BoxedTransport) -> (Reader, Writer) {
+    tokio::io::split(stream)
 }
 
-impl Connection<OwnedReadHalf, OwnedWriteHalf> {
-    pub fn with_stream(stream: TcpStream) -> Self {
-        let (reader, writer) = stream.into_split();
-        Self::new(reader, writer)
+fn encode(packet: &Packet, buf: &mut BytesMut, protocol: Protocol) -> Result<(), mqttbytes::Error> {
+    if protocol == Protocol::V4 {
+        v4::write(packet, buf)?;
+        return Ok(());
     }
+
+    match packet {
+        Packet::Connect(packet) => packet.write(buf)?,
+        Packet::ConnAck(packet) => packet.write(buf)?,
+        Packet::Publish(packet) => packet.write(buf)?,
+        Packet::PubAck(packet) => packet.write(buf)?,
+        Packet::PubRec(packet) => packet.write(buf)?,
+        Packet::PubRel(packet) => packet.write(buf)?,
+        Packet::PubComp(packet) => packet.write(buf)?,
+        Packet::Subscribe(packet) => packet.write(buf)?,
+        Packet::SubAck(packet) => packet.write(buf)?,
+        Packet::Unsubscribe(packet) => packet.write(buf)?,
+        Packet::UnsubAck(packet) => packet.write(buf)?,
+        Packet::Disconnect(packet) => packet.write(buf)?,
+        Packet::PingReq => {
+            buf.put_u8(0b_1100_0000);
+            buf.put_u8(0);
+            2
+        }
+        Packet::PingResp => {
+            buf.put_u8(0b_1101_0000);
+            buf.put_u8(0);
+            2
+        }
+    };
+
+    Ok(())
+}
+
+/// Packets received from the broker.
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum Incoming {
+    Packet(Packet),
+    Auth(Auth),
+}
+
+fn read(
+    buf: &mut BytesMut,
+    max_size: usize,
+    protocol: Protocol,
+) -> Result<Incoming, mqttbytes::Error> {
+    if protocol == Protocol::V4 {
+        return v4::read(buf, max_size).map(Incoming::Packet);
+    }
+
+    if buf[0] >> 4 == AUTH_PACKET_TYPE {
+        let fixed_header = mqttbytes::check(buf.iter(), max_size)?;
+        let frame = buf.split_to(fixed_header.frame_length()).freeze();
+        return Auth::read(frame).map(Incoming::Auth);
+    }
+
+    // `mqttbytes` requires a reason code and properties although both may be omitted.
+    if buf.starts_with(&[DISCONNECT_HEADER, 0]) {
+        buf.advance(2);
+        return Ok(Incoming::Packet(Packet::Disconnect(Disconnect::new())));
+    }
+    if buf.len() >= 3 && buf.starts_with(&[DISCONNECT_HEADER, 1]) {
+        let mut disconnect = Disconnect::new();
+        disconnect.reason_code = buf[2].try_into()?;
+        buf.advance(3);
+        return Ok(Incoming::Packet(Packet::Disconnect(disconnect)));
+    }
+
+    mqttbytes::v5::read(buf, max_size).map(Incoming::Packet)
+}
+
+// Size of the fixed header and the rest of the packet.
+fn packet_size(remaining_len: usize) -> usize {
+    let remaining_len_len = match remaining_len {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => 4,
+    };
+    1 + remaining_len_len + remaining_len
 }
 
 impl<R, W> Connection<R, W>
@@ -45,51 +185,40 @@ where
     R: AsyncRead + Unpin,
     W: AsyncWrite + Unpin,
 {
-    pub fn send(&self, packet: &Packet) -> Result<SendFuture<W>, mqttbytes::Error> {
+    pub fn send(&self, packet: &Packet) -> Result<SendFuture<W>, Error> {
         let mut buf = BytesMut::new();
 
-        match packet {
-            Packet::Connect(packet) => packet.write(&mut buf)?,
-            Packet::ConnAck(packet) => packet.write(&mut buf)?,
-            Packet::Publish(packet) => packet.write(&mut buf)?,
-            Packet::PubAck(packet) => packet.write(&mut buf)?,
-            Packet::PubRec(packet) => packet.write(&mut buf)?,
-            Packet::PubRel(packet) => packet.write(&mut buf)?,
-            Packet::PubComp(packet) => packet.write(&mut buf)?,
-            Packet::Subscribe(packet) => packet.write(&mut buf)?,
-            Packet::SubAck(packet) => packet.write(&mut buf)?,
-            Packet::Unsubscribe(packet) => packet.write(&mut buf)?,
-            Packet::UnsubAck(packet) => packet.write(&mut buf)?,
-            Packet::Disconnect(packet) => packet.write(&mut buf)?,
-            Packet::PingReq => {
-                buf.put_u8(0b_1100_0000);
-                buf.put_u8(0);
-                2
-            }
-            Packet::PingResp => {
-                buf.put_u8(0b_1101_0000);
-                buf.put_u8(0);
-                2
-            }
-        };
+        encode(packet, &mut buf, self.protocol).map_err(Error::Encoding)?;
+        self.check_size(buf.len())?;
 
         let buf = buf.freeze();
 
         Ok(SendFuture::new(self.writer.clone(), buf))
     }
 
-    pub async fn recv(&self) -> Result<Option<Packet>, mqttbytes::Error> {
+    pub fn send_auth(&self, auth: &Auth) -> Result<SendFuture<W>, Error> {
+        if self.protocol == Protocol::V4 {
+            return Err(Error::UnsupportedByProtocol("enhanced authentication"));
+        }
+        let mut buf = BytesMut::new();
+
+        auth.write(&mut buf).map_err(Error::Encoding)?;
+        self.check_size(buf.len())?;
+
+        Ok(SendFuture::new(self.writer.clone(), buf.freeze()))
+    }
+
+    pub async fn recv(&self) -> Result<Option<Incoming>, mqttbytes::Error> {
         let mut guard = self.reader.lock().await;
         let (reader, buf) = &mut *guard;
 
         loop {
             if !buf.is_empty() {
-                match mqttbytes::v5::read(buf, MAX_SIZE) {
+                match read(buf, self.max_incoming_size, self.protocol) {
                     Err(mqttbytes::Error::InsufficientBytes(len)) => {
-                        let packet_type =
-                            FixedHeader::new(*buf.iter().next().unwrap(), 0, 0).packet_type()?;
+                        let packet_type = buf[0] >> 4;
                         tracing::debug!(
-                            ?packet_type,
+                            packet_type,
                             required_bytes = len,
                             "Insufficient bytes, more are required."
                         );
@@ -106,7 +235,14 @@ where
             }
 
             tracing::debug!(buffer.length = buf.len(), "Waiting for more data.");
-            if reader.read_buf(buf).await.unwrap() == 0 {
+            let read = match reader.read_buf(buf).await {
+                Ok(read) => read,
+                Err(error) => {
+                    tracing::warn!(%error, "Unable to read from the connection.");
+                    return Ok(None);
+                }
+            };
+            if read == 0 {
                 if buf.is_empty() {
                     tracing::debug!("No more data will be available in connection.");
                     return Ok(None);
@@ -117,6 +253,26 @@ where
         }
     }
 
+    /// Switches to a new network connection and sends CONNECT before anything else is written to it.
+    pub async fn replace(&self, reader: R, writer: W, connect: &Packet) -> Result<(), Error> {
+        let mut buf = BytesMut::new();
+        encode(connect, &mut buf, self.protocol).map_err(Error::Encoding)?;
+
+        let mut guard = self.writer.lock().await;
+        *guard = writer;
+        *self.reader.lock().await = (reader, BytesMut::new());
+        self.set_max_outgoing_size(None);
+
+        let written = match guard.write_all(&buf).await {
+            Ok(()) => guard.flush().await,
+            Err(error) => Err(error),
+        };
+        written.map_err(|error| {
+            tracing::warn!(%error, "Unable to send CONNECT.");
+            Error::ConnectionClosed
+        })
+    }
+
     pub async fn shutdown(&self) -> Result<(), std::io::Error> {
         self.writer.lock().await.shutdown().await
     }
@@ -164,11 +320,20 @@ where
                 }
                 SendFutureState::Sending { writer } => {
                     if this.bytes.is_empty() {
+                        // Buffering transports such as TLS only write once flushed.
+                        if let Err(error) = ready!(Pin::new(writer.deref_mut()).poll_flush(cx)) {
+                            tracing::warn!(%error, "Unable to send packet, connection is closed.");
+                        }
                         return Poll::Ready(());
                     }
-                    let cnt =
-                        ready!(Pin::new(writer.deref_mut()).poll_write(cx, &this.bytes)).unwrap();
-                    this.bytes.advance(cnt);
+                    match ready!(Pin::new(writer.deref_mut()).poll_write(cx, &this.bytes)) {
+                        Ok(cnt) => this.bytes.advance(cnt),
+                        Err(error) => {
+                            // Unacknowledged messages are retransmitted after reconnecting.
+                            tracing::warn!(%error, "Unable to send packet, connection is closed.");
+                            return Poll::Ready(());
+                        }
+                    }
                 }
             }
         }
@@ -183,6 +348,33 @@ mod tests {
 
     #[allow(dead_code)]
     fn assert_send() {
-        is_send::<SendFuture<OwnedWriteHalf>>();
+        is_send::<SendFuture<Writer>>();
+    }
+
+    #[test]
+    fn packet_size_matches_encoded_publish() {
+        for payload_len in [0, 100, 20_000, 3_000_000] {
+            let mut publish = Publish::new("topic", QoS::AtLeastOnce, vec![0; payload_len]);
+            publish.pkid = 1;
+            let mut buf = BytesMut::new();
+            encode(&Packet::Publish(publish.clone()), &mut buf, Protocol::V5).unwrap();
+            assert_eq!(packet_size(publish.len()), buf.len());
+        }
+    }
+
+    #[test]
+    fn publish_over_broker_maximum_is_rejected() {
+        let connection = Connection::new((), (), DEFAULT_MAX_PACKET_SIZE, Protocol::V5);
+        let publish = Publish::new("topic", QoS::AtLeastOnce, vec![0; 100]);
+        assert!(connection.check_publish_size(&publish).is_ok());
+
+        connection.set_max_outgoing_size(Some(100));
+        assert!(matches!(
+            connection.check_publish_size(&publish),
+            Err(Error::PacketTooLarge {
+                size: 112,
+                maximum: 100
+            })
+        ));
     }
 }
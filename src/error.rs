@@ -0,0 +1,54 @@
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("route `{route}` cannot be added")]
+    InvalidRoute {
+        route: String,
+        #[source]
+        source: matchit::InsertError,
+    },
+    #[error("topic filter `{0}` contains `:` or `*` and cannot be routed")]
+    UnroutableFilter(String),
+    #[error("broker's Receive Maximum reached, no more QoS 1 or 2 messages can be sent until some are acknowledged")]
+    ReceiveMaximumExceeded,
+    #[error("packet of {size} bytes exceeds the maximum packet size of {maximum} bytes")]
+    PacketTooLarge { size: usize, maximum: usize },
+    #[error("packet cannot be encoded: {0}")]
+    Encoding(mqttbytes::Error),
+    #[error("payload cannot be serialized: {0}")]
+    Serialization(serde_json::Error),
+    #[error("authentication failed: {0}")]
+    Authentication(String),
+    #[error("disconnected by the broker with reason {reason_code:?}")]
+    Disconnected {
+        reason_code: mqttbytes::v5::DisconnectReasonCode,
+        reason_string: Option<String>,
+    },
+    #[error("unable to connect to the broker: {0}")]
+    Connect(#[source] std::io::Error),
+    #[error("rejected by the broker with reason code {reason_code:#04x}")]
+    Rejected {
+        reason_code: u8,
+        reason_string: Option<String>,
+    },
+    #[error("connection was closed")]
+    ConnectionClosed,
+    /// The connection was lost before the message was acknowledged. The message is kept and sent again if the broker
+    /// resumes the session, so it may still be delivered and retrying would publish it twice.
+    #[error("connection was lost before the message was acknowledged, it is sent again if the session is resumed")]
+    DeliveryUnknown(#[source] Box<Error>),
+    #[error("client is shutting down")]
+    ShuttingDown,
+    #[error("offline queue is full")]
+    QueueFull,
+    #[error("message was dropped from the full offline queue")]
+    MessageDropped,
+    #[error("message expired before it could be sent")]
+    MessageExpired,
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[cfg(feature = "tls")]
+    #[error("TLS configuration is invalid: {0}")]
+    Tls(String),
+    #[error("{0} requires MQTT 5")]
+    UnsupportedByProtocol(&'static str),
+}
@@ -0,0 +1,129 @@
+use mqttbytes::v5::{ConnAck, Connect, ConnectReturnCode, DisconnectReasonCode};
+use tokio::sync::oneshot;
+
+use crate::{
+    auth::{
+        packet::{Auth, AuthReason},
+        Authenticator,
+    },
+    Error,
+};
+
+pub(crate) struct AuthHandler {
+    authenticator: Option<Box<dyn Authenticator>>,
+    // Notified when the re-authentication started by the client finishes.
+    reauthentication: Option<oneshot::Sender<Result<(), Error>>>,
+}
+
+impl AuthHandler {
+    pub(crate) fn new(authenticator: Option<Box<dyn Authenticator>>) -> Self {
+        Self {
+            authenticator,
+            reauthentication: None,
+        }
+    }
+
+    /// Adds the Authentication Method and Data to CONNECT, fails if the exchange cannot be started.
+    pub fn connect(&mut self, connect: &mut Connect) -> Result<(), Error> {
+        let Some(authenticator) = &mut self.authenticator else {
+            return Ok(());
+        };
+        let Some(properties) = &mut connect.properties else {
+            return Ok(());
+        };
+
+        properties.authentication_method = Some(authenticator.method().to_owned());
+        properties.authentication_data = authenticator.start()?;
+        Ok(())
+    }
+
+    pub fn connack(&mut self, connack: &ConnAck) -> Result<(), DisconnectReasonCode> {
+        let Some(authenticator) = &mut self.authenticator else {
+            return Ok(());
+        };
+        if connack.code != ConnectReturnCode::Success {
+            return Ok(());
+        }
+
+        let data = connack
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.authentication_data.clone());
+        authenticator.finish(data).map_err(|error| {
+            tracing::error!(%error, "Unable to verify the broker's authentication.");
+            DisconnectReasonCode::NotAuthorized
+        })
+    }
+
+    /// Returns the AUTH packet to send back to the broker.
+    pub fn auth(&mut self, auth: Auth) -> Result<Option<Auth>, DisconnectReasonCode> {
+        let Some(authenticator) = &mut self.authenticator else {
+            tracing::error!("AUTH received but no authenticator is configured.");
+            return Err(DisconnectReasonCode::ProtocolError);
+        };
+        if auth.method.as_deref() != Some(authenticator.method()) {
+            tracing::error!(method = ?auth.method, "AUTH received with a different authentication method.");
+            return Err(DisconnectReasonCode::ProtocolError);
+        }
+        let method = authenticator.method().to_owned();
+
+        let result = match auth.reason {
+            AuthReason::ContinueAuthentication => authenticator
+                .challenge(auth.data)
+                .map(|data| Some(Auth::new(AuthReason::ContinueAuthentication, method, data))),
+            AuthReason::Success => authenticator.finish(auth.data).map(|()| None),
+            // Brokers should not ask for re-authentication but it is harmless to oblige.
+            AuthReason::ReAuthenticate => authenticator
+                .start()
+                .map(|data| Some(Auth::new(AuthReason::ReAuthenticate, method, data))),
+        };
+
+        match result {
+            Ok(response) => {
+                if auth.reason == AuthReason::Success {
+                    if let Some(sender) = self.reauthentication.take() {
+                        let _ = sender.send(Ok(()));
+                    }
+                }
+                Ok(response)
+            }
+            Err(error) => {
+                tracing::error!(%error, "Authentication failed.");
+                if let Some(sender) = self.reauthentication.take() {
+                    let _ = sender.send(Err(error));
+                }
+                Err(DisconnectReasonCode::NotAuthorized)
+            }
+        }
+    }
+
+    /// Returns the AUTH packet starting re-authentication and a receiver notified when it finishes.
+    pub fn reauthenticate(
+        &mut self,
+    ) -> Result<(Auth, oneshot::Receiver<Result<(), Error>>), Error> {
+        let Some(authenticator) = &mut self.authenticator else {
+            return Err(Error::Authentication(
+                "no authenticator is configured".to_owned(),
+            ));
+        };
+
+        let data = authenticator.start()?;
+        let auth = Auth::new(
+            AuthReason::ReAuthenticate,
+            authenticator.method().to_owned(),
+            data,
+        );
+
+        let (sender, receiver) = oneshot::channel();
+        self.reauthentication = Some(sender);
+        Ok((auth, receiver))
+    }
+
+    pub fn disconnected(&mut self) {
+        if let Some(sender) = self.reauthentication.take() {
+            let _ = sender.send(Err(Error::Authentication(
+                "disconnected before re-authentication finished".to_owned(),
+            )));
+        }
+    }
+}
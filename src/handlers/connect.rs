@@ -2,14 +2,27 @@ use std::{
     future::{self, ready, Future},
     pin::Pin,
     sync::Arc,
+    time::Duration,
 };
 
-use mqttbytes::v5::{ConnAck, Connect, Disconnect, Packet};
+use mqttbytes::v5::{
+    ConnAck, ConnAckProperties, Connect, ConnectReturnCode, Disconnect, DisconnectReasonCode,
+    Packet,
+};
 use tokio::sync::Notify;
 
+use crate::client::{ConnectionState, ServerDisconnect};
+
 pub(crate) struct ConnectHandler {
     state: ConnectState,
     ping_notify: Arc<Notify>,
+    session_expiry_interval: u32,
+    session_present: bool,
+    keep_alive: u16,
+    // DISCONNECT received from the broker on the current connection.
+    server_disconnect: Option<ServerDisconnect>,
+    // The broker refused the last CONNECT.
+    refused: bool,
 }
 
 #[derive(Debug)]
@@ -17,58 +30,190 @@ enum ConnectState {
     Disconnected,
     ConnectSent(Arc<Notify>),
     Connected,
+    // Closed without the client asking for it.
+    ConnectionLost,
 }
 
 impl ConnectHandler {
-    pub(crate) fn new() -> ConnectHandler {
+    pub(crate) fn new(keep_alive: u16) -> ConnectHandler {
         Self {
             state: ConnectState::Disconnected,
             ping_notify: Arc::new(Notify::new()),
+            session_expiry_interval: 0,
+            session_present: false,
+            keep_alive,
+            server_disconnect: None,
+            refused: false,
+        }
+    }
+
+    pub fn session_present(&self) -> bool {
+        self.session_present
+    }
+
+    pub fn is_connected(&self) -> bool {
+        matches!(self.state, ConnectState::Connected)
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        match self.state {
+            ConnectState::Disconnected => ConnectionState::Disconnected,
+            ConnectState::ConnectSent(_) => ConnectionState::Connecting,
+            ConnectState::Connected => ConnectionState::Connected,
+            ConnectState::ConnectionLost => ConnectionState::Reconnecting,
+        }
+    }
+
+    /// Interval in which the broker expects a packet from the client, `None` if keep-alive is disabled.
+    pub fn keep_alive(&self) -> Option<Duration> {
+        (self.keep_alive != 0).then(|| Duration::from_secs(self.keep_alive.into()))
+    }
+
+    /// Whether the client sent DISCONNECT, in which case it must not reconnect.
+    pub fn closed_by_client(&self) -> bool {
+        matches!(self.state, ConnectState::Disconnected)
+    }
+
+    /// Whether the broker refused the last CONNECT.
+    pub fn refused(&self) -> bool {
+        self.refused
+    }
+
+    pub fn server_disconnect(&mut self, disconnect: ServerDisconnect) {
+        self.server_disconnect = Some(disconnect);
+    }
+
+    /// Called when the client will not connect again.
+    pub fn closed(&mut self) {
+        if let ConnectState::ConnectSent(notify) = &self.state {
+            notify.notify_one();
         }
+        self.state = ConnectState::Disconnected;
     }
 
-    pub fn connect(&mut self, _connect: &mut Connect) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    /// Called when the connection closes unexpectedly, returns the DISCONNECT sent by the broker if there was one.
+    pub fn connection_lost(&mut self) -> Option<ServerDisconnect> {
+        // Lets the client continue instead of waiting for a CONNACK that never comes.
+        if let ConnectState::ConnectSent(notify) = &self.state {
+            notify.notify_one();
+        }
+        self.state = ConnectState::ConnectionLost;
+        self.server_disconnect.take()
+    }
+
+    pub fn connect(&mut self, connect: &mut Connect) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        self.session_expiry_interval = connect
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.session_expiry_interval)
+            .unwrap_or(0);
+        self.keep_alive = connect.keep_alive;
+
         let notify = Arc::new(Notify::new());
         self.state = ConnectState::ConnectSent(notify.clone());
+        self.server_disconnect = None;
+        self.refused = false;
 
         Box::pin(async move {
             notify.notified().await;
         })
     }
 
-    pub fn connack(&mut self, _connack: ConnAck) -> Vec<Packet> {
+    pub fn connack(&mut self, connack: ConnAck) -> Vec<Packet> {
+        self.session_present = connack.session_present;
+        self.refused = connack.code != ConnectReturnCode::Success;
+        // The broker may point to another server when refusing the connection, just like with a DISCONNECT.
+        let redirect = match connack.code {
+            ConnectReturnCode::UseAnotherServer => Some(DisconnectReasonCode::UseAnotherServer),
+            ConnectReturnCode::ServerMoved => Some(DisconnectReasonCode::ServerMoved),
+            _ => None,
+        };
+        if let Some(reason_code) = redirect {
+            let properties = connack
+                .properties
+                .clone()
+                .unwrap_or_else(ConnAckProperties::new);
+            self.server_disconnect = Some(ServerDisconnect {
+                reason_code,
+                reason_string: properties.reason_string,
+                server_reference: properties.server_reference,
+                session_expiry_interval: properties.session_expiry_interval,
+                user_properties: properties.user_properties,
+            });
+        }
+        // The broker may override the requested interval.
+        if let Some(interval) = connack
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.session_expiry_interval)
+        {
+            self.session_expiry_interval = interval;
+        }
+        if let Some(keep_alive) = connack
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.server_keep_alive)
+        {
+            self.keep_alive = keep_alive;
+        }
+
         match &self.state {
             ConnectState::ConnectSent(notify) => {
                 tracing::debug!("Notifying of CONNACK.");
                 notify.notify_one();
-                self.state = ConnectState::Connected;
+                // The broker closes the connection after refusing it.
+                self.state = if self.refused {
+                    ConnectState::ConnectionLost
+                } else {
+                    ConnectState::Connected
+                };
+            }
+            // The client may shut down while reconnecting.
+            ConnectState::Disconnected => {
+                tracing::debug!("CONNACK received after disconnecting, ignoring it.");
+                return Vec::new();
             }
             _ => {
                 tracing::error!(state = ?self.state, "CONNACK received when not expecting it.");
                 panic!("Unexpected CONNACK.");
             }
         }
-        self.state = ConnectState::Connected;
         Vec::new()
     }
 
-    pub fn disconnect(&mut self, _disconnect: &mut Disconnect) -> Pin<Box<future::Ready<()>>> {
+    pub fn disconnect(&mut self, disconnect: &mut Disconnect) -> Pin<Box<future::Ready<()>>> {
+        if let Some(properties) = &mut disconnect.properties {
+            // Setting a non-zero interval on DISCONNECT is a protocol error if the session was not meant to outlive the connection.
+            if self.session_expiry_interval == 0
+                && properties
+                    .session_expiry_interval
+                    .is_some_and(|interval| interval != 0)
+            {
+                tracing::warn!(
+                    "Session expiry cannot be set on DISCONNECT when it was zero on CONNECT, ignoring it."
+                );
+                properties.session_expiry_interval = None;
+            }
+        }
+
+        // Nobody should wait for a CONNACK which will be ignored.
+        if let ConnectState::ConnectSent(notify) = &self.state {
+            notify.notify_one();
+        }
         self.state = ConnectState::Disconnected;
         Box::pin(ready(()))
     }
 
     pub(crate) fn ping(&self) -> Pin<Box<dyn Future<Output = ()> + Send>> {
-        tracing::info!("Ping!");
-        Box::pin({
-            let notify = self.ping_notify.clone();
-            async move {
-                notify.notified().await;
-            }
-        })
+        tracing::debug!("Ping!");
+        // Registered right away so that a quick PINGRESP is not missed.
+        let mut notified = Box::pin(self.ping_notify.clone().notified_owned());
+        notified.as_mut().enable();
+        notified
     }
 
     pub(crate) fn pong(&self) -> Vec<Packet> {
-        tracing::info!("Pong!");
+        tracing::debug!("Pong!");
         self.ping_notify.notify_waiters();
         Vec::new()
     }
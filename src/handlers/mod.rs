@@ -1,3 +1,6 @@
+pub(super) mod auth;
 pub(super) mod connect;
-pub(super) mod publish;
+pub(crate) mod offline;
+pub(crate) mod publish;
 pub(super) mod subscribe;
+pub(crate) mod topic_alias;
@@ -0,0 +1,352 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use mqttbytes::v5::Publish;
+use serde::Deserialize;
+use tokio::sync::{oneshot, Notify};
+
+use crate::{
+    session::{QueuedPublish, SessionState, SharedSessionStore},
+    Error,
+};
+
+pub(crate) const DEFAULT_CAPACITY: usize = 1000;
+
+/// What happens to a message published while the client is offline and its queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued message, its publish fails with [`Error::MessageDropped`].
+    DropOldest,
+    /// Discard the new message, its publish fails with [`Error::MessageDropped`].
+    DropNewest,
+    /// Wait until queued messages are sent after the client connects.
+    Block,
+    /// Fail with [`Error::QueueFull`].
+    #[default]
+    Error,
+}
+
+pub(crate) enum Enqueue {
+    /// The client is connected and nothing is queued, the message is sent right away.
+    Send(Publish),
+    Queued(Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>),
+    /// The queue is full, the message can be offered again once the notify is notified.
+    Full(Publish, Arc<Notify>),
+}
+
+// Messages published while the client is not connected, sent in order once it connects.
+pub(crate) struct OfflineQueue {
+    messages: VecDeque<QueuedMessage>,
+    capacity: usize,
+    overflow: OverflowPolicy,
+    persistent: bool,
+    store: SharedSessionStore,
+    next_id: u64,
+    // Messages bypass the queue only when connected and all queued messages were sent.
+    connected: bool,
+    closed: bool,
+    // Identifies the connection so that sending queued messages stops once it is lost.
+    connection: u64,
+    // Notified whenever a queued message is removed.
+    space: Arc<Notify>,
+}
+
+pub(crate) struct QueuedMessage {
+    pub publish: Publish,
+    // Messages loaded from the session store have nobody waiting for them.
+    pub done: Option<oneshot::Sender<Result<(), Error>>>,
+    id: u64,
+    queued_at: SystemTime,
+}
+
+impl QueuedMessage {
+    pub fn finish(&mut self, result: Result<(), Error>) {
+        match self.done.take() {
+            Some(done) => {
+                let _ = done.send(result);
+            }
+            None => {
+                if let Err(error) = result {
+                    tracing::warn!(%error, topic = self.publish.topic, "Queued message was not delivered.");
+                }
+            }
+        }
+    }
+
+    // Time left until the message expires, `None` if it never does.
+    fn remaining(&self, now: SystemTime) -> Option<Duration> {
+        let interval = self.publish.properties.as_ref()?.message_expiry_interval?;
+        let elapsed = now.duration_since(self.queued_at).unwrap_or_default();
+        Some(Duration::from_secs(interval.into()).saturating_sub(elapsed))
+    }
+}
+
+impl OfflineQueue {
+    pub fn new(
+        store: SharedSessionStore,
+        state: &SessionState,
+        capacity: usize,
+        overflow: OverflowPolicy,
+        persistent: bool,
+    ) -> Self {
+        let messages: VecDeque<_> = state
+            .queued
+            .iter()
+            .map(|queued| QueuedMessage {
+                publish: queued.publish.clone(),
+                done: None,
+                id: queued.id,
+                queued_at: queued.queued_at,
+            })
+            .collect();
+        let next_id = messages.back().map_or(0, |message| message.id + 1);
+
+        Self {
+            messages,
+            capacity,
+            overflow,
+            persistent,
+            store,
+            next_id,
+            connected: false,
+            closed: false,
+            connection: 0,
+            space: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn enqueue(&mut self, publish: Publish) -> Result<Enqueue, Error> {
+        if self.closed {
+            return Err(Error::ConnectionClosed);
+        }
+        // A capacity of zero disables the queue.
+        if self.connected || self.capacity == 0 {
+            return Ok(Enqueue::Send(publish));
+        }
+
+        self.purge_expired();
+        if self.messages.len() >= self.capacity {
+            match self.overflow {
+                OverflowPolicy::DropOldest => {
+                    if let Some(oldest) = self.messages.pop_front() {
+                        tracing::warn!(
+                            topic = oldest.publish.topic,
+                            "Offline queue is full, dropping the oldest message."
+                        );
+                        self.removed(oldest, Err(Error::MessageDropped));
+                    }
+                }
+                OverflowPolicy::DropNewest => {
+                    tracing::warn!(
+                        topic = publish.topic,
+                        "Offline queue is full, dropping the message."
+                    );
+                    return Err(Error::MessageDropped);
+                }
+                OverflowPolicy::Block => return Ok(Enqueue::Full(publish, self.space.clone())),
+                OverflowPolicy::Error => return Err(Error::QueueFull),
+            }
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let queued_at = SystemTime::now();
+        // With `FileStore` this rewrites the whole file, see `ClientBuilder::set_persist_offline_queue`.
+        if self.persistent {
+            let queued = QueuedPublish {
+                id,
+                publish: publish.clone(),
+                queued_at,
+            };
+            self.store.update(|store| store.add_queued(&queued));
+        }
+
+        tracing::debug!(
+            topic = publish.topic,
+            "Client is offline, queueing the message."
+        );
+        let (done, receiver) = oneshot::channel();
+        self.messages.push_back(QueuedMessage {
+            publish,
+            done: Some(done),
+            id,
+            queued_at,
+        });
+        Ok(Enqueue::Queued(Box::pin(async move {
+            receiver.await.unwrap_or(Err(Error::ConnectionClosed))
+        })))
+    }
+
+    /// Identifies the current connection, to be passed to [`OfflineQueue::next`].
+    pub fn connection(&self) -> u64 {
+        self.connection
+    }
+
+    /// Takes the next message to be sent, or marks the client as connected once the queue is empty.
+    pub fn next(&mut self, connection: u64) -> Option<QueuedMessage> {
+        if connection != self.connection || self.closed {
+            return None;
+        }
+
+        self.purge_expired();
+        let Some(mut message) = self.messages.pop_front() else {
+            self.connected = true;
+            return None;
+        };
+        if self.persistent {
+            self.store.update(|store| store.remove_queued(message.id));
+        }
+        self.space.notify_waiters();
+
+        // The broker is told how long the message has left.
+        if let Some(remaining) = message.remaining(SystemTime::now()) {
+            if let Some(properties) = &mut message.publish.properties {
+                properties.message_expiry_interval = Some(remaining.as_secs().max(1) as u32);
+            }
+        }
+        Some(message)
+    }
+
+    pub fn disconnected(&mut self) {
+        self.connected = false;
+        self.connection += 1;
+    }
+
+    /// Fails everyone waiting, persistent messages are kept in the store for the next run.
+    pub fn closed(&mut self) {
+        self.disconnected();
+        self.closed = true;
+        for mut message in self.messages.drain(..) {
+            if let Some(done) = message.done.take() {
+                let _ = done.send(Err(Error::ConnectionClosed));
+            }
+        }
+        self.space.notify_waiters();
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    fn purge_expired(&mut self) {
+        let now = SystemTime::now();
+        let (expired, kept) = self
+            .messages
+            .drain(..)
+            .partition(|message| message.remaining(now) == Some(Duration::ZERO));
+        self.messages = kept;
+
+        for message in expired {
+            tracing::debug!(topic = message.publish.topic, "Queued message expired.");
+            self.removed(message, Err(Error::MessageExpired));
+        }
+    }
+
+    fn removed(&mut self, mut message: QueuedMessage, result: Result<(), Error>) {
+        if self.persistent {
+            self.store.update(|store| store.remove_queued(message.id));
+        }
+        message.finish(result);
+        self.space.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future;
+
+    use mqttbytes::{v5::PublishProperties, QoS};
+
+    use super::*;
+    use crate::session::MemoryStore;
+
+    fn queue(overflow: OverflowPolicy) -> OfflineQueue {
+        let store = SharedSessionStore::new(Box::new(MemoryStore::new()));
+        OfflineQueue::new(store, &SessionState::default(), 2, overflow, true)
+    }
+
+    fn enqueue(
+        queue: &mut OfflineQueue,
+        topic: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>> {
+        match queue.enqueue(Publish::new(topic, QoS::AtLeastOnce, "payload")) {
+            Ok(Enqueue::Queued(future)) => future,
+            Ok(_) => panic!("message was not queued"),
+            Err(error) => Box::pin(future::ready(Err(error))),
+        }
+    }
+
+    fn topics(queue: &mut OfflineQueue) -> Vec<String> {
+        let connection = queue.connection();
+        std::iter::from_fn(|| queue.next(connection))
+            .map(|message| message.publish.topic)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn overflow_policies() {
+        let mut drop_oldest = queue(OverflowPolicy::DropOldest);
+        let first = enqueue(&mut drop_oldest, "first");
+        drop(enqueue(&mut drop_oldest, "second"));
+        drop(enqueue(&mut drop_oldest, "third"));
+        assert!(matches!(first.await, Err(Error::MessageDropped)));
+        assert_eq!(topics(&mut drop_oldest), ["second", "third"]);
+
+        let mut drop_newest = queue(OverflowPolicy::DropNewest);
+        drop(enqueue(&mut drop_newest, "first"));
+        drop(enqueue(&mut drop_newest, "second"));
+        let third = enqueue(&mut drop_newest, "third");
+        assert!(matches!(third.await, Err(Error::MessageDropped)));
+        assert_eq!(topics(&mut drop_newest), ["first", "second"]);
+
+        let mut error = queue(OverflowPolicy::Error);
+        drop(enqueue(&mut error, "first"));
+        drop(enqueue(&mut error, "second"));
+        assert!(matches!(
+            enqueue(&mut error, "third").await,
+            Err(Error::QueueFull)
+        ));
+
+        let mut block = queue(OverflowPolicy::Block);
+        drop(enqueue(&mut block, "first"));
+        drop(enqueue(&mut block, "second"));
+        let publish = Publish::new("third", QoS::AtLeastOnce, "payload");
+        assert!(matches!(block.enqueue(publish), Ok(Enqueue::Full(..))));
+    }
+
+    #[test]
+    fn expired_messages_are_purged_and_queue_is_persisted() {
+        let mut queue = queue(OverflowPolicy::Error);
+        let mut expired = Publish::new("expired", QoS::AtLeastOnce, "payload");
+        expired.properties = Some(PublishProperties {
+            payload_format_indicator: None,
+            message_expiry_interval: Some(0),
+            topic_alias: None,
+            response_topic: None,
+            correlation_data: None,
+            user_properties: Vec::new(),
+            subscription_identifiers: Vec::new(),
+            content_type: None,
+        });
+        drop(enqueue(&mut queue, "kept"));
+        drop(queue.enqueue(expired));
+
+        let state = queue.store.load();
+        let reloaded =
+            OfflineQueue::new(queue.store.clone(), &state, 2, OverflowPolicy::Error, true);
+        assert_eq!(reloaded.len(), 2);
+
+        assert_eq!(topics(&mut queue), ["kept"]);
+        assert!(queue.store.load().queued.is_empty());
+        assert!(matches!(
+            queue.enqueue(Publish::new("sent", QoS::AtMostOnce, "")),
+            Ok(Enqueue::Send(_))
+        ));
+    }
+}
@@ -1,89 +1,425 @@
 use std::{
+    cmp::Ordering,
     collections::{HashMap, HashSet},
     future::{self, Future},
     pin::Pin,
     sync::Arc,
-    task::{ready, Context, Poll},
+    task::{Context, Poll},
 };
 
 use mqttbytes::{
-    v5::{Packet, PubAck, PubComp, PubRec, PubRel, Publish},
+    v5::{
+        Disconnect, DisconnectReasonCode, Packet, PubAck, PubAckReason, PubComp, PubCompReason,
+        PubRec, PubRecReason, PubRel, Publish,
+    },
     QoS,
 };
-use tokio::sync::Notify;
+use serde::Deserialize;
+use tokio::sync::{oneshot, Notify, OwnedSemaphorePermit, Semaphore};
 
-use crate::subscribe::router::{HandlerFuture, HandlerRouterWithClientState};
+use super::topic_alias::{AliasUse, IncomingAliases, OutgoingAliases};
+use crate::{
+    session::{Outgoing, SessionState, SharedSessionStore},
+    subscribe::router::{Admission, HandlerRouterWithClientState, MessageOrder},
+    Error, Handler,
+};
+
+// Resolves once a sent message is acknowledged.
+type Acknowledged = Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>;
 
 pub(crate) struct SentPublishHandler {
     next_id: u16,
-    pending_ack: HashMap<u16, (Publish, Arc<Notify>)>,
-    pending_rec: HashMap<u16, (Publish, Arc<Notify>)>,
-    pending_comp: HashSet<u16>,
+    pending_ack: HashMap<u16, PendingPublish>,
+    pending_rec: HashMap<u16, PendingPublish>,
+    pending_comp: HashMap<u16, Option<OwnedSemaphorePermit>>,
+    // Packet identifiers of unfinished messages in the order in which they were sent, used for retransmission.
+    sent_order: Vec<u16>,
+    store: SharedSessionStore,
+    // Slots for QoS 1 and 2 messages limited by the broker's Receive Maximum.
+    in_flight: Arc<Semaphore>,
+    receive_maximum: u16,
+    // Slots which should have been removed when the Receive Maximum decreased but were taken at the time.
+    deficit: usize,
+    flow_control: FlowControl,
+    aliases: OutgoingAliases,
+    // Notified when no more messages await acknowledgement.
+    all_finished: Arc<Notify>,
+}
+
+struct PendingPublish {
+    publish: Publish,
+    // Messages loaded from the session store have nobody waiting for them.
+    done: Option<oneshot::Sender<Result<(), Error>>>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl PendingPublish {
+    fn new(publish: Publish, permit: Option<OwnedSemaphorePermit>) -> Self {
+        Self {
+            publish,
+            done: None,
+            permit,
+        }
+    }
+
+    fn wait(&mut self) -> Acknowledged {
+        let (sender, receiver) = oneshot::channel();
+        self.done = Some(sender);
+        Box::pin(async move { receiver.await.unwrap_or(Err(Error::ConnectionClosed)) })
+    }
+
+    fn finish(&mut self, result: Result<(), Error>) {
+        if let Some(done) = self.done.take() {
+            let _ = done.send(result);
+        }
+    }
+}
+
+/// What happens to QoS 1 and 2 messages published while the broker's Receive Maximum is reached.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlowControl {
+    /// Wait until an acknowledgement frees a slot.
+    #[default]
+    Wait,
+    /// Fail with [`Error::ReceiveMaximumExceeded`].
+    FailFast,
 }
 
 pub(crate) struct ReceivedPublishHandler {
-    pending_rel: HashMap<u16, Publish>,
+    pending_rel: Arc<std::sync::Mutex<HashMap<u16, ExactlyOnceState>>>,
+    pending_acks: PendingAcks,
     publish_router: HandlerRouterWithClientState,
+    store: SharedSessionStore,
+    // QoS 1 and 2 messages which were not fully acknowledged yet, limited by our Receive Maximum.
+    in_flight: Arc<std::sync::Mutex<HashSet<u16>>>,
+    receive_maximum: u16,
+    aliases: IncomingAliases,
 }
 
 impl SentPublishHandler {
-    pub fn new() -> Self {
-        Self {
-            next_id: 0,
+    pub fn new(
+        store: SharedSessionStore,
+        state: &SessionState,
+        flow_control: FlowControl,
+        topic_aliases: bool,
+    ) -> Self {
+        let mut handler = Self {
+            next_id: state.next_id,
             pending_ack: HashMap::new(),
             pending_rec: HashMap::new(),
-            pending_comp: HashSet::new(),
+            pending_comp: HashMap::new(),
+            sent_order: Vec::new(),
+            store,
+            in_flight: Arc::new(Semaphore::new(u16::MAX.into())),
+            receive_maximum: u16::MAX,
+            deficit: 0,
+            flow_control,
+            aliases: OutgoingAliases::new(topic_aliases),
+            all_finished: Arc::new(Notify::new()),
+        };
+
+        // Slots for these are taken once the broker's Receive Maximum is known.
+        for outgoing in &state.outgoing {
+            let id = outgoing.pkid();
+            match outgoing {
+                Outgoing::Publish(publish) if publish.qos == QoS::ExactlyOnce => {
+                    handler
+                        .pending_rec
+                        .insert(id, PendingPublish::new(publish.clone(), None));
+                }
+                Outgoing::Publish(publish) => {
+                    handler
+                        .pending_ack
+                        .insert(id, PendingPublish::new(publish.clone(), None));
+                }
+                Outgoing::Release(_) => {
+                    handler.pending_comp.insert(id, None);
+                }
+            }
+            handler.sent_order.push(id);
+        }
+
+        handler
+    }
+
+    // Takes a slot for a QoS 1 or 2 message. The lock must not be held while the returned future is awaited.
+    pub fn reserve(
+        &self,
+        qos: QoS,
+    ) -> impl Future<Output = Result<Option<OwnedSemaphorePermit>, Error>> {
+        let in_flight = self.in_flight.clone();
+        let flow_control = self.flow_control;
+
+        async move {
+            if qos == QoS::AtMostOnce {
+                return Ok(None);
+            }
+
+            let permit = match flow_control {
+                FlowControl::Wait => in_flight.acquire_owned().await.ok(),
+                FlowControl::FailFast => in_flight.try_acquire_owned().ok(),
+            };
+
+            permit.map(Some).ok_or(Error::ReceiveMaximumExceeded)
         }
     }
 
-    pub fn publish(&mut self, publish: &mut Publish) -> Pin<Box<dyn Future<Output = ()> + Send>> {
-        match &publish.qos {
-            QoS::AtMostOnce => Box::pin(future::ready(())),
+    // The stored message keeps its topic, only the sent one uses an alias.
+    /// Registers the message and returns a future which resolves once it is acknowledged, along with the alias use to
+    /// pass to [`SentPublishHandler::alias_written`] once the message is written.
+    pub fn publish(
+        &mut self,
+        publish: &mut Publish,
+        permit: Option<OwnedSemaphorePermit>,
+    ) -> (Acknowledged, Option<AliasUse>) {
+        let future = self.store_publish(publish, permit);
+        let alias_use = self.aliases.apply(publish);
+        (future, alias_use)
+    }
+
+    pub fn alias_written(&mut self, alias_use: AliasUse, written: bool) {
+        self.aliases.written(alias_use, written);
+    }
+
+    fn store_publish(
+        &mut self,
+        publish: &mut Publish,
+        permit: Option<OwnedSemaphorePermit>,
+    ) -> Acknowledged {
+        let pending = match &publish.qos {
+            QoS::AtMostOnce => return Box::pin(future::ready(Ok(()))),
             QoS::AtLeastOnce => {
-                let id = self.next_id();
-                publish.pkid = id;
-                let notify = Arc::new(Notify::new());
-                self.pending_ack
-                    .insert(id, (publish.clone(), notify.clone()));
-                Box::pin(async move {
-                    notify.notified().await;
-                })
+                publish.pkid = self.next_id();
+                self.pending_ack.entry(publish.pkid)
             }
             QoS::ExactlyOnce => {
-                let id = self.next_id();
-                publish.pkid = id;
-                let notify = Arc::new(Notify::new());
-                self.pending_rec
-                    .insert(id, (publish.clone(), notify.clone()));
-                Box::pin(async move {
-                    notify.notified().await;
-                })
+                publish.pkid = self.next_id();
+                self.pending_rec.entry(publish.pkid)
             }
+        };
+        let future = pending
+            .or_insert(PendingPublish::new(publish.clone(), permit))
+            .wait();
+        self.sent(publish);
+        future
+    }
+
+    fn sent(&mut self, publish: &Publish) {
+        self.sent_order.push(publish.pkid);
+        self.store.update(|store| store.add_outgoing(publish));
+    }
+
+    fn finished(&mut self, id: u16) {
+        self.sent_order.retain(|sent| *sent != id);
+        self.store.update(|store| store.remove_outgoing(id));
+        if self.sent_order.is_empty() {
+            self.all_finished.notify_waiters();
         }
     }
 
+    /// Number of QoS 1 and 2 messages which were not fully acknowledged yet.
+    pub fn unfinished(&self) -> usize {
+        self.sent_order.len()
+    }
+
+    pub fn all_finished(&self) -> Arc<Notify> {
+        self.all_finished.clone()
+    }
+
     fn next_id(&mut self) -> u16 {
-        self.next_id = self.next_id.wrapping_add(1);
-        if self.next_id == 0 {
-            self.next_id += 1;
+        loop {
+            self.next_id = self.next_id.wrapping_add(1);
+            if self.next_id == 0 {
+                self.next_id += 1;
+            }
+            if !self.is_in_use(self.next_id) {
+                break;
+            }
+        }
+
+        let id = self.next_id;
+        self.store.update(|store| store.set_next_id(id));
+        id
+    }
+
+    fn is_in_use(&self, id: u16) -> bool {
+        self.pending_ack.contains_key(&id)
+            || self.pending_rec.contains_key(&id)
+            || self.pending_comp.contains_key(&id)
+    }
+
+    fn set_receive_maximum(&mut self, receive_maximum: u16) {
+        match receive_maximum.cmp(&self.receive_maximum) {
+            Ordering::Greater => {
+                let added = usize::from(receive_maximum - self.receive_maximum);
+                let covered = added.min(self.deficit);
+                self.deficit -= covered;
+                self.in_flight.add_permits(added - covered);
+            }
+            Ordering::Less => {
+                let removed = usize::from(self.receive_maximum - receive_maximum);
+                let forgotten = self.in_flight.forget_permits(removed);
+                if forgotten < removed {
+                    tracing::warn!(
+                        receive_maximum,
+                        "More messages are in flight than the broker allows."
+                    );
+                    self.deficit += removed - forgotten;
+                }
+            }
+            Ordering::Equal => {}
+        }
+        self.receive_maximum = receive_maximum;
+    }
+
+    // Frees the slot of a finished message unless it is one of the slots removed by a decreased Receive Maximum.
+    fn release(&mut self, permit: Option<OwnedSemaphorePermit>) {
+        if self.deficit == 0 {
+            return;
+        }
+        match permit {
+            Some(permit) => {
+                permit.forget();
+                self.deficit -= 1;
+            }
+            // Slots may also be freed elsewhere, e.g. when a reserved slot is not used.
+            None => self.deficit -= self.in_flight.forget_permits(self.deficit),
         }
-        self.next_id
+    }
+
+    // Called after CONNACK. Returns the packets which need to be retransmitted.
+    pub fn resume(
+        &mut self,
+        session_present: bool,
+        receive_maximum: Option<u16>,
+        topic_alias_maximum: Option<u16>,
+    ) -> Vec<Packet> {
+        self.set_receive_maximum(receive_maximum.unwrap_or(u16::MAX));
+        self.aliases.reset(topic_alias_maximum.unwrap_or(0));
+
+        if !session_present {
+            if !self.sent_order.is_empty() {
+                tracing::warn!(
+                    count = self.sent_order.len(),
+                    "Broker did not resume the session, discarding unacknowledged messages."
+                );
+            }
+            let pending: Vec<_> = self
+                .pending_ack
+                .drain()
+                .chain(self.pending_rec.drain())
+                .map(|(_, pending)| pending)
+                .collect();
+            for mut pending in pending {
+                pending.finish(Err(Error::ConnectionClosed));
+                self.release(pending.permit);
+            }
+            for (_, permit) in std::mem::take(&mut self.pending_comp) {
+                self.release(permit);
+            }
+            for id in std::mem::take(&mut self.sent_order) {
+                self.store.update(|store| store.remove_outgoing(id));
+            }
+            self.all_finished.notify_waiters();
+            return Vec::new();
+        }
+
+        let mut retransmit = Vec::new();
+        for id in &self.sent_order {
+            let (packet, permit) = if let Some(pending) = self.pending_ack.get_mut(id) {
+                let mut publish = pending.publish.clone();
+                publish.dup = true;
+                (Packet::Publish(publish), &mut pending.permit)
+            } else if let Some(pending) = self.pending_rec.get_mut(id) {
+                let mut publish = pending.publish.clone();
+                publish.dup = true;
+                (Packet::Publish(publish), &mut pending.permit)
+            } else if let Some(permit) = self.pending_comp.get_mut(id) {
+                (Packet::PubRel(PubRel::new(*id)), permit)
+            } else {
+                continue;
+            };
+
+            // Messages loaded from the session store do not hold a slot yet.
+            if permit.is_none() {
+                *permit = self.in_flight.clone().try_acquire_owned().ok();
+            }
+            retransmit.push(packet);
+        }
+        retransmit
+    }
+
+    /// Fails everyone waiting for an acknowledgement with [`Error::DeliveryUnknown`], the messages themselves are kept
+    /// for retransmission.
+    pub fn connection_lost(&mut self, error: impl Fn() -> Error) {
+        for pending in self
+            .pending_ack
+            .values_mut()
+            .chain(self.pending_rec.values_mut())
+        {
+            pending.finish(Err(Error::DeliveryUnknown(Box::new(error()))));
+        }
+    }
+
+    /// Fails and forgets a message which could not be sent.
+    pub fn discard(&mut self, id: u16, error: Error) {
+        let pending = self
+            .pending_ack
+            .remove(&id)
+            .or_else(|| self.pending_rec.remove(&id));
+        let permit = match pending {
+            Some(mut pending) => {
+                pending.finish(Err(error));
+                pending.permit
+            }
+            None => match self.pending_comp.remove(&id) {
+                Some(permit) => permit,
+                None => return,
+            },
+        };
+        self.release(permit);
+        self.finished(id);
     }
 
     pub fn puback(&mut self, puback: PubAck) -> Vec<Packet> {
         let id = puback.pkid;
-        // TODO check reason
-        let (_, notify) = self.pending_ack.remove(&id).unwrap();
-        notify.notify_one();
+        let Some(mut pending) = self.pending_ack.remove(&id) else {
+            tracing::warn!(pkid = id, "PUBACK received for unknown packet.");
+            return Vec::new();
+        };
+        let reason_string = puback
+            .properties
+            .and_then(|properties| properties.reason_string);
+        pending.finish(rejected(puback.reason as u8, reason_string));
+        self.release(pending.permit);
+        self.finished(id);
 
         Vec::new()
     }
 
     pub fn pubrec(&mut self, pubrec: PubRec) -> Vec<Packet> {
         let id = pubrec.pkid;
-        let (_, notify) = self.pending_rec.remove(&id).unwrap();
-        notify.notify_one();
-        self.pending_comp.insert(id);
+        let Some(mut pending) = self.pending_rec.remove(&id) else {
+            tracing::warn!(pkid = id, "PUBREC received for unknown packet.");
+            return Vec::new();
+        };
+        let reason_string = pubrec
+            .properties
+            .and_then(|properties| properties.reason_string);
+        pending.finish(rejected(pubrec.reason as u8, reason_string));
+
+        // Reason codes of 0x80 and above end the exchange.
+        if (pubrec.reason as u8) >= 0x80 {
+            tracing::warn!(pkid = id, reason = ?pubrec.reason, "Message was rejected.");
+            self.release(pending.permit);
+            self.finished(id);
+            return Vec::new();
+        }
+
+        // The slot is freed only after PUBCOMP.
+        self.pending_comp.insert(id, pending.permit);
+        self.store.update(|store| store.release_outgoing(id));
 
         vec![Packet::PubRel(PubRel::new(id))]
     }
@@ -94,71 +430,308 @@ impl SentPublishHandler {
 
     pub fn pubcomp(&mut self, pubcomp: PubComp) -> Vec<Packet> {
         let id = pubcomp.pkid;
-        self.pending_comp.remove(&id);
+        if let Some(permit) = self.pending_comp.remove(&id) {
+            self.release(permit);
+            self.finished(id);
+        }
 
         Vec::new()
     }
 }
 
+// Reason codes of 0x80 and above indicate failure.
+pub(crate) fn rejected(reason_code: u8, reason_string: Option<String>) -> Result<(), Error> {
+    if reason_code < 0x80 {
+        return Ok(());
+    }
+    tracing::warn!(reason_code, reason_string, "Rejected by the broker.");
+    Err(Error::Rejected {
+        reason_code,
+        reason_string,
+    })
+}
+
 impl ReceivedPublishHandler {
-    pub fn new(publish_router: HandlerRouterWithClientState) -> Self {
+    pub fn new(
+        publish_router: HandlerRouterWithClientState,
+        pending_acks: PendingAcks,
+        store: SharedSessionStore,
+        state: &SessionState,
+        receive_maximum: u16,
+        topic_alias_maximum: u16,
+    ) -> Self {
+        let pending_rel = state
+            .incoming
+            .iter()
+            .map(|id| (*id, ExactlyOnceState::Received))
+            .collect();
+
         Self {
-            pending_rel: HashMap::new(),
+            pending_rel: Arc::new(std::sync::Mutex::new(pending_rel)),
+            pending_acks,
             publish_router,
+            store,
+            in_flight: Arc::new(std::sync::Mutex::new(
+                state.incoming.iter().copied().collect(),
+            )),
+            receive_maximum,
+            aliases: IncomingAliases::new(topic_alias_maximum),
+        }
+    }
+
+    // Called after CONNACK.
+    pub fn resume(&mut self, session_present: bool) {
+        self.aliases.clear();
+        // Messages of a session the broker did not keep are never redelivered, handlers still running are not acknowledged.
+        if !session_present {
+            for (id, state) in self.pending_rel.lock().unwrap().drain() {
+                if let ExactlyOnceState::Received = state {
+                    self.store.update(|store| store.remove_incoming(id));
+                }
+            }
+            self.in_flight.lock().unwrap().clear();
         }
     }
 
+    /// Must be called for every received PUBLISH, in order, before it is routed.
+    pub(crate) fn resolve_topic_alias(
+        &mut self,
+        publish: &mut Publish,
+    ) -> Result<(), DisconnectReasonCode> {
+        self.aliases.resolve(publish)
+    }
+
+    pub(crate) fn admit(&mut self, topic: &str) -> Admission {
+        self.publish_router.admit(topic)
+    }
+
+    #[cfg(feature = "testing")]
+    pub(crate) fn route_for(&self, topic: &str) -> Option<String> {
+        self.publish_router.route_for(topic)
+    }
+
     pub(crate) fn publish(&mut self, publish: Publish) -> PublishFuture {
         tracing::info!(?publish, "Received publish packet.");
-        let handler_future = self.publish_router.handle(publish.clone());
-
-        match publish.qos {
-            QoS::AtMostOnce => PublishFuture::new(handler_future, Vec::new()),
-            QoS::AtLeastOnce => PublishFuture::new(
-                handler_future,
-                vec![Packet::PubAck(PubAck::new(publish.pkid))],
-            ),
-            QoS::ExactlyOnce => {
-                let reply = vec![Packet::PubRec(PubRec::new(publish.pkid))];
-                if !self.pending_rel.contains_key(&publish.pkid) {
-                    PublishFuture::new(None, reply)
-                } else {
-                    PublishFuture::new(handler_future, reply)
+        let pkid = publish.pkid;
+        let qos = publish.qos;
+
+        if qos == QoS::ExactlyOnce {
+            match self.pending_rel.lock().unwrap().get(&pkid) {
+                Some(ExactlyOnceState::Handling) => {
+                    tracing::debug!(pkid, "Duplicate of a message which is still being handled.");
+                    return PublishFuture::new(future::ready(Vec::new()));
+                }
+                Some(ExactlyOnceState::Received) => {
+                    tracing::debug!(pkid, "Duplicate of an already handled message.");
+                    return PublishFuture::new(future::ready(vec![Packet::PubRec(PubRec::new(
+                        pkid,
+                    ))]));
                 }
+                None => {}
             }
         }
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if qos != QoS::AtMostOnce && !in_flight.contains(&pkid) {
+            if in_flight.len() >= self.receive_maximum.into() {
+                tracing::error!(
+                    receive_maximum = self.receive_maximum,
+                    "Broker exceeded Receive Maximum, disconnecting."
+                );
+                let mut disconnect = Disconnect::new();
+                disconnect.reason_code = DisconnectReasonCode::ReceiveMaximumExceeded;
+                return PublishFuture::new(future::ready(vec![Packet::Disconnect(disconnect)]));
+            }
+            in_flight.insert(pkid);
+        }
+        drop(in_flight);
+
+        if qos == QoS::ExactlyOnce {
+            self.pending_rel
+                .lock()
+                .unwrap()
+                .insert(pkid, ExactlyOnceState::Handling);
+        }
+
+        let acknowledgement = match qos {
+            QoS::AtMostOnce => None,
+            QoS::AtLeastOnce | QoS::ExactlyOnce => Some(self.pending_acks.register(pkid)),
+        };
+        let handler_future = self.publish_router.handle(publish);
+        let pending_acks = self.pending_acks.clone();
+        let pending_rel = self.pending_rel.clone();
+        let in_flight = self.in_flight.clone();
+        let store = self.store.clone();
+
+        PublishFuture::new(async move {
+            if let Some(handler_future) = handler_future {
+                handler_future.await;
+            }
+
+            let Some(acknowledgement) = acknowledgement else {
+                return Vec::new();
+            };
+
+            // If the handler did not extract `Ack`, the message is acknowledged automatically.
+            let reason = if pending_acks.remove(pkid) {
+                PubAckReason::Success
+            } else {
+                match acknowledgement.await {
+                    Ok(reason) => reason,
+                    Err(_) => {
+                        tracing::warn!(
+                            pkid,
+                            "Ack dropped without acknowledging, the message will be redelivered."
+                        );
+                        // The redelivered message must be handled again and does not count against our Receive Maximum until then.
+                        pending_rel.lock().unwrap().remove(&pkid);
+                        in_flight.lock().unwrap().remove(&pkid);
+                        return Vec::new();
+                    }
+                }
+            };
+
+            match qos {
+                QoS::AtMostOnce => unreachable!("QoS 0 messages are not acknowledged."),
+                QoS::AtLeastOnce => {
+                    // Forgotten when the broker did not keep the session.
+                    if !in_flight.lock().unwrap().contains(&pkid) {
+                        return Vec::new();
+                    }
+                    let mut puback = PubAck::new(pkid);
+                    puback.reason = reason;
+                    vec![Packet::PubAck(puback)]
+                }
+                QoS::ExactlyOnce => {
+                    let mut pending_rel = pending_rel.lock().unwrap();
+                    if !matches!(pending_rel.get(&pkid), Some(ExactlyOnceState::Handling)) {
+                        return Vec::new();
+                    }
+                    // Reason codes of 0x80 and above end the exchange, no PUBREL will follow.
+                    if (reason as u8) < 0x80 {
+                        pending_rel.insert(pkid, ExactlyOnceState::Received);
+                        store.update(|store| store.add_incoming(pkid));
+                    } else {
+                        pending_rel.remove(&pkid);
+                    }
+
+                    let mut pubrec = PubRec::new(pkid);
+                    pubrec.reason = pubrec_reason(reason);
+                    vec![Packet::PubRec(pubrec)]
+                }
+            }
+        })
+    }
+
+    pub(crate) fn add_route<const ASYNC: bool, M: Send + 'static>(
+        &mut self,
+        route: &str,
+        handler: impl Handler<ASYNC, M, ()> + 'static,
+        order: Option<MessageOrder>,
+    ) -> Result<(), Error> {
+        self.publish_router.add(route, handler, order)
+    }
+
+    pub(crate) fn remove_route(&mut self, route: &str) -> bool {
+        self.publish_router.remove(route)
     }
 
-    pub(crate) fn puback(&self, _puback: &mut PubAck) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    pub(crate) fn puback(
+        &mut self,
+        puback: &mut PubAck,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        self.in_flight.lock().unwrap().remove(&puback.pkid);
         Box::pin(future::ready(()))
     }
 
-    pub(crate) fn pubrec(&self, _puback: &mut PubRec) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    pub(crate) fn pubrec(
+        &mut self,
+        pubrec: &mut PubRec,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        if (pubrec.reason as u8) >= 0x80 {
+            self.in_flight.lock().unwrap().remove(&pubrec.pkid);
+        }
         Box::pin(future::ready(()))
     }
 
     pub fn pubrel(&mut self, pubrel: PubRel) -> Vec<Packet> {
         let id = pubrel.pkid;
-        self.pending_rel.remove(&id);
+        let mut pubcomp = PubComp::new(id);
+
+        match self.pending_rel.lock().unwrap().remove(&id) {
+            Some(ExactlyOnceState::Received) => {
+                self.store.update(|store| store.remove_incoming(id));
+            }
+            Some(ExactlyOnceState::Handling) => {
+                tracing::warn!(pkid = id, "PUBREL received before PUBREC was sent.");
+            }
+            None => {
+                tracing::warn!(pkid = id, "PUBREL received for unknown packet.");
+                pubcomp.reason = PubCompReason::PacketIdentifierNotFound;
+            }
+        }
 
-        vec![Packet::PubComp(PubComp::new(id))]
+        vec![Packet::PubComp(pubcomp)]
     }
 
-    pub fn pubcomp(&mut self, _pubcomp: &mut PubComp) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    pub fn pubcomp(&mut self, pubcomp: &mut PubComp) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        self.in_flight.lock().unwrap().remove(&pubcomp.pkid);
         Box::pin(future::ready(()))
     }
 }
 
+enum ExactlyOnceState {
+    // The handler has been invoked but PUBREC has not been sent yet.
+    Handling,
+    // PUBREC has been sent, waiting for PUBREL.
+    Received,
+}
+
+// Acknowledgements waiting for a handler which extracted `Ack`.
+//
+// Keyed by packet identifier only, QoS 1 and QoS 2 messages share the identifiers and the broker cannot reuse one
+// until the message using it is acknowledged (MQTT-2.2.1-3), so two messages in flight never collide.
+#[derive(Clone, Default)]
+pub(crate) struct PendingAcks(Arc<std::sync::Mutex<HashMap<u16, oneshot::Sender<PubAckReason>>>>);
+
+impl PendingAcks {
+    fn register(&self, pkid: u16) -> oneshot::Receiver<PubAckReason> {
+        let (sender, receiver) = oneshot::channel();
+        self.0.lock().unwrap().insert(pkid, sender);
+        receiver
+    }
+
+    pub(crate) fn take(&self, pkid: u16) -> Option<oneshot::Sender<PubAckReason>> {
+        self.0.lock().unwrap().remove(&pkid)
+    }
+
+    fn remove(&self, pkid: u16) -> bool {
+        self.take(pkid).is_some()
+    }
+}
+
+fn pubrec_reason(reason: PubAckReason) -> PubRecReason {
+    match reason {
+        PubAckReason::Success => PubRecReason::Success,
+        PubAckReason::NoMatchingSubscribers => PubRecReason::NoMatchingSubscribers,
+        PubAckReason::UnspecifiedError => PubRecReason::UnspecifiedError,
+        PubAckReason::ImplementationSpecificError => PubRecReason::ImplementationSpecificError,
+        PubAckReason::NotAuthorized => PubRecReason::NotAuthorized,
+        PubAckReason::TopicNameInvalid => PubRecReason::TopicNameInvalid,
+        PubAckReason::PacketIdentifierInUse => PubRecReason::PacketIdentifierInUse,
+        PubAckReason::QuotaExceeded => PubRecReason::QuotaExceeded,
+        PubAckReason::PayloadFormatInvalid => PubRecReason::PayloadFormatInvalid,
+    }
+}
+
 pub(crate) struct PublishFuture {
-    handler_future: Option<HandlerFuture>,
-    packets: Vec<Packet>,
+    inner: Pin<Box<dyn Future<Output = Vec<Packet>> + Send>>,
 }
 
 impl PublishFuture {
-    fn new(handler_future: Option<HandlerFuture>, packets: Vec<Packet>) -> Self {
+    fn new(future: impl Future<Output = Vec<Packet>> + Send + 'static) -> Self {
         Self {
-            handler_future,
-            packets,
+            inner: Box::pin(future),
         }
     }
 }
@@ -167,11 +740,103 @@ impl Future for PublishFuture {
     type Output = Vec<Packet>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let this = self.get_mut();
-        if let Some(handler_future) = &mut this.handler_future {
-            ready!(Pin::new(handler_future).poll(cx));
+        self.get_mut().inner.as_mut().poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::MemoryStore;
+
+    fn sent_publish_handler(flow_control: FlowControl) -> SentPublishHandler {
+        let store = SharedSessionStore::new(Box::new(MemoryStore::new()));
+        SentPublishHandler::new(store, &SessionState::default(), flow_control, false)
+    }
+
+    #[tokio::test]
+    async fn receive_maximum_limits_messages_in_flight() {
+        let mut handler = sent_publish_handler(FlowControl::FailFast);
+        handler.resume(false, Some(1), None);
+
+        let permit = handler.reserve(QoS::AtLeastOnce).await.unwrap();
+        let mut publish = Publish::new("topic", QoS::AtLeastOnce, "payload");
+        drop(handler.publish(&mut publish, permit));
+
+        assert!(matches!(
+            handler.reserve(QoS::AtLeastOnce).await,
+            Err(Error::ReceiveMaximumExceeded)
+        ));
+        assert!(handler.reserve(QoS::AtMostOnce).await.is_ok());
+
+        handler.puback(PubAck::new(publish.pkid));
+        assert!(handler.reserve(QoS::AtLeastOnce).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn decreased_receive_maximum_applies_once_messages_are_acknowledged() {
+        let mut handler = sent_publish_handler(FlowControl::FailFast);
+        let mut sent = Vec::new();
+        for _ in 0..3 {
+            let permit = handler.reserve(QoS::AtLeastOnce).await.unwrap();
+            let mut publish = Publish::new("topic", QoS::AtLeastOnce, "payload");
+            drop(handler.publish(&mut publish, permit));
+            sent.push(publish.pkid);
+        }
+
+        handler.resume(true, Some(1), None);
+        for pkid in sent {
+            handler.puback(PubAck::new(pkid));
         }
 
-        Poll::Ready(this.packets.clone())
+        let _permit = handler.reserve(QoS::AtLeastOnce).await.unwrap();
+        assert!(matches!(
+            handler.reserve(QoS::AtLeastOnce).await,
+            Err(Error::ReceiveMaximumExceeded)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejected_message_fails_with_reason_code() {
+        let mut handler = sent_publish_handler(FlowControl::FailFast);
+        let permit = handler.reserve(QoS::AtLeastOnce).await.unwrap();
+        let mut publish = Publish::new("topic", QoS::AtLeastOnce, "payload");
+        let (acknowledged, _) = handler.publish(&mut publish, permit);
+
+        let mut puback = PubAck::new(publish.pkid);
+        puback.reason = PubAckReason::NotAuthorized;
+        handler.puback(puback);
+        assert!(matches!(
+            acknowledged.await,
+            Err(Error::Rejected {
+                reason_code: 0x87,
+                ..
+            })
+        ));
+        assert_eq!(handler.unfinished(), 0);
+    }
+
+    #[tokio::test]
+    async fn discarded_message_is_failed_and_forgotten() {
+        let mut handler = sent_publish_handler(FlowControl::FailFast);
+        handler.resume(false, Some(1), None);
+        let permit = handler.reserve(QoS::AtLeastOnce).await.unwrap();
+        let mut publish = Publish::new("topic", QoS::AtLeastOnce, "payload");
+        let (acknowledged, _) = handler.publish(&mut publish, permit);
+
+        handler.discard(
+            publish.pkid,
+            Error::PacketTooLarge {
+                size: 100,
+                maximum: 10,
+            },
+        );
+        assert!(matches!(
+            acknowledged.await,
+            Err(Error::PacketTooLarge { .. })
+        ));
+        assert_eq!(handler.unfinished(), 0);
+        assert!(handler.store.load().outgoing.is_empty());
+        assert!(handler.reserve(QoS::AtLeastOnce).await.is_ok());
     }
 }
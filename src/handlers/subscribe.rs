@@ -1,13 +1,20 @@
-use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc};
+use std::{collections::HashMap, future::Future, pin::Pin};
 
-use mqttbytes::v5::{Packet, SubAck, Subscribe, UnsubAck, Unsubscribe};
-use tokio::sync::Notify;
+use mqttbytes::v5::{Packet, SubAck, Subscribe, SubscribeFilter, UnsubAck, Unsubscribe};
+use tokio::sync::oneshot;
+
+use super::publish::rejected;
+use crate::Error;
+
+type Pending = oneshot::Sender<Result<(), Error>>;
 
 pub(crate) struct SubscribeHandler {
     next_sub_id: u16,
     next_unsub_id: u16,
-    pending_suback: HashMap<u16, Arc<Notify>>,
-    pending_unsuback: HashMap<u16, Arc<Notify>>,
+    pending_suback: HashMap<u16, Pending>,
+    pending_unsuback: HashMap<u16, Pending>,
+    // Filters which are subscribed again if the broker does not resume the session after reconnecting.
+    subscriptions: Vec<SubscribeFilter>,
 }
 
 impl SubscribeHandler {
@@ -17,6 +24,7 @@ impl SubscribeHandler {
             next_unsub_id: 0,
             pending_suback: HashMap::new(),
             pending_unsuback: HashMap::new(),
+            subscriptions: Vec::new(),
         }
     }
 
@@ -36,46 +44,174 @@ impl SubscribeHandler {
         *id
     }
 
+    fn wait(
+        pending: &mut HashMap<u16, Pending>,
+        id: u16,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>> {
+        let (sender, receiver) = oneshot::channel();
+        pending.insert(id, sender);
+
+        Box::pin(async move { receiver.await.unwrap_or(Err(Error::ConnectionClosed)) })
+    }
+
+    fn finish(pending: &mut HashMap<u16, Pending>, id: u16, result: Result<(), Error>) {
+        match pending.remove(&id) {
+            Some(sender) => {
+                let _ = sender.send(result);
+            }
+            None => tracing::warn!(pkid = id, "Acknowledgement received for unknown packet."),
+        }
+    }
+
     pub fn subscribe(
         &mut self,
         subscribe: &mut Subscribe,
-    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>> {
         let id = self.next_sub_id();
         subscribe.pkid = id;
-        let notify = Arc::new(Notify::new());
-        self.pending_suback.insert(id, notify.clone());
+        self.remember(subscribe);
 
-        Box::pin(async move {
-            notify.notified().await;
-        })
+        Self::wait(&mut self.pending_suback, id)
+    }
+
+    /// Forgets a SUBSCRIBE which could not be sent, its acknowledgement never comes.
+    pub fn subscribe_not_sent(&mut self, id: u16) {
+        self.pending_suback.remove(&id);
+    }
+
+    /// Records the filters so that they are subscribed to after reconnecting.
+    pub fn remember(&mut self, subscribe: &Subscribe) {
+        for filter in &subscribe.filters {
+            self.subscriptions
+                .retain(|subscription| subscription.path != filter.path);
+            self.subscriptions.push(filter.clone());
+        }
     }
 
     pub fn suback(&mut self, suback: SubAck) -> Vec<Packet> {
-        let id = suback.pkid;
-        // TODO check reason
-        let notify = self.pending_suback.remove(&id).unwrap();
-        notify.notify_one();
+        // Failure codes are above the granted QoS, so the highest one tells whether any filter was rejected.
+        let reason_code = suback
+            .return_codes
+            .iter()
+            .map(|code| *code as u8)
+            .max()
+            .unwrap_or_default();
+        let reason_string = suback
+            .properties
+            .and_then(|properties| properties.reason_string);
+        let result = rejected(reason_code, reason_string);
+        Self::finish(&mut self.pending_suback, suback.pkid, result);
         Vec::new()
     }
 
     pub fn unsubscribe(
         &mut self,
         unsubscribe: &mut Unsubscribe,
-    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>> {
         let id = self.next_unsub_id();
         unsubscribe.pkid = id;
-        let notify = Arc::new(Notify::new());
-        self.pending_unsuback.insert(id, notify.clone());
+        self.forget(&unsubscribe.filters);
 
-        Box::pin(async move {
-            notify.notified().await;
-        })
+        Self::wait(&mut self.pending_unsuback, id)
     }
 
-    pub fn unsuback(&mut self, unsuback: UnsubAck) -> Vec<Packet> {
-        let id = unsuback.pkid;
-        // TODO check reason
+    /// Stops subscribing to the filters after reconnecting.
+    pub fn forget(&mut self, filters: &[String]) {
+        self.subscriptions
+            .retain(|subscription| !filters.contains(&subscription.path));
+    }
+
+    /// Forgets an UNSUBSCRIBE which could not be sent, its acknowledgement never comes.
+    pub fn unsubscribe_not_sent(&mut self, id: u16) {
         self.pending_unsuback.remove(&id);
+    }
+
+    pub fn unsuback(&mut self, unsuback: UnsubAck) -> Vec<Packet> {
+        let reason_code = unsuback
+            .reasons
+            .iter()
+            .map(|reason| *reason as u8)
+            .max()
+            .unwrap_or_default();
+        let reason_string = unsuback
+            .properties
+            .and_then(|properties| properties.reason_string);
+        let result = rejected(reason_code, reason_string);
+        Self::finish(&mut self.pending_unsuback, unsuback.pkid, result);
         Vec::new()
     }
+
+    /// SUBSCRIBE restoring all current subscriptions, if there are any.
+    pub fn resubscribe(&self) -> Option<Subscribe> {
+        if self.subscriptions.is_empty() {
+            return None;
+        }
+        Some(Subscribe::new_many(self.subscriptions.iter().cloned()))
+    }
+
+    /// Fails everyone waiting for an acknowledgement. Packets are not retransmitted so their acknowledgements never come.
+    pub fn connection_lost(&mut self, error: impl Fn() -> Error) {
+        for (_, sender) in self
+            .pending_suback
+            .drain()
+            .chain(self.pending_unsuback.drain())
+        {
+            let _ = sender.send(Err(error()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqttbytes::{
+        v5::{SubscribeReasonCode, UnsubAckReason},
+        QoS,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn failed_subscription_is_rejected() {
+        let mut handler = SubscribeHandler::new();
+        let mut subscribe = Subscribe::new_many([
+            SubscribeFilter::new("allowed".to_owned(), QoS::AtLeastOnce),
+            SubscribeFilter::new("forbidden".to_owned(), QoS::AtLeastOnce),
+        ]);
+        let subscribed = handler.subscribe(&mut subscribe);
+        handler.suback(SubAck::new(
+            subscribe.pkid,
+            vec![
+                SubscribeReasonCode::QoS1,
+                SubscribeReasonCode::NotAuthorized,
+            ],
+        ));
+        assert!(matches!(
+            subscribed.await,
+            Err(Error::Rejected {
+                reason_code: 0x87,
+                ..
+            })
+        ));
+
+        let mut unsubscribe = Unsubscribe::new("allowed");
+        let unsubscribed = handler.unsubscribe(&mut unsubscribe);
+        let mut unsuback = UnsubAck::new(unsubscribe.pkid);
+        unsuback.reasons.push(UnsubAckReason::NoSubscriptionExisted);
+        handler.unsuback(unsuback);
+        assert!(unsubscribed.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn packets_which_were_not_sent_are_forgotten() {
+        let mut handler = SubscribeHandler::new();
+        let mut subscribe = Subscribe::new("topic", QoS::AtLeastOnce);
+        drop(handler.subscribe(&mut subscribe));
+        handler.subscribe_not_sent(subscribe.pkid);
+        let mut unsubscribe = Unsubscribe::new("topic");
+        drop(handler.unsubscribe(&mut unsubscribe));
+        handler.unsubscribe_not_sent(unsubscribe.pkid);
+
+        assert!(handler.pending_suback.is_empty());
+        assert!(handler.pending_unsuback.is_empty());
+    }
 }
@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+
+use mqttbytes::v5::{DisconnectReasonCode, Publish, PublishProperties};
+
+// Topic aliases are valid only for a single connection so both sides are cleared after every CONNACK.
+
+/// Topics the broker assigned to aliases in received messages.
+pub(crate) struct IncomingAliases {
+    maximum: u16,
+    topics: HashMap<u16, String>,
+}
+
+impl IncomingAliases {
+    pub fn new(maximum: u16) -> Self {
+        Self {
+            maximum,
+            topics: HashMap::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.topics.clear();
+    }
+
+    /// Fills in the topic of a message which only carries an alias.
+    pub fn resolve(&mut self, publish: &mut Publish) -> Result<(), DisconnectReasonCode> {
+        let Some(alias) = publish
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.topic_alias)
+        else {
+            return Ok(());
+        };
+
+        if alias == 0 || alias > self.maximum {
+            tracing::error!(
+                alias,
+                maximum = self.maximum,
+                "Received invalid topic alias."
+            );
+            return Err(DisconnectReasonCode::TopicAliasInvalid);
+        }
+
+        if publish.topic.is_empty() {
+            let Some(topic) = self.topics.get(&alias) else {
+                tracing::error!(alias, "Received unknown topic alias.");
+                return Err(DisconnectReasonCode::ProtocolError);
+            };
+            publish.topic = topic.clone();
+        } else {
+            self.topics.insert(alias, publish.topic.clone());
+        }
+
+        Ok(())
+    }
+}
+
+/// Aliases assigned to topics of sent messages, limited by the broker's Topic Alias Maximum.
+///
+/// Once all aliases are taken, the least recently used one is reassigned to the next new topic. An alias is reassigned
+/// only after all messages using it were written, otherwise the broker could apply the new topic to them.
+pub(crate) struct OutgoingAliases {
+    enabled: bool,
+    maximum: u16,
+    aliases: HashMap<String, OutgoingAlias>,
+    // Incremented on every reset so that messages written on a previous connection do not affect aliases.
+    connection: u64,
+    // Incremented on every use to find the least recently used alias.
+    uses: u64,
+}
+
+struct OutgoingAlias {
+    alias: u16,
+    // Whether a message carrying both the topic and the alias was written.
+    established: bool,
+    // Messages using the alias which were not written yet.
+    unwritten: usize,
+    last_used: u64,
+}
+
+/// Message using an alias, see [`OutgoingAliases::written`].
+pub(crate) struct AliasUse {
+    topic: String,
+    connection: u64,
+    // The message carries both the topic and the alias.
+    establishing: bool,
+}
+
+impl OutgoingAliases {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            maximum: 0,
+            aliases: HashMap::new(),
+            connection: 0,
+            uses: 0,
+        }
+    }
+
+    pub fn reset(&mut self, maximum: u16) {
+        self.maximum = maximum;
+        self.aliases.clear();
+        self.connection += 1;
+    }
+
+    /// Replaces the topic with its alias once the broker knows it.
+    ///
+    /// Until a message carrying both is written, messages on the topic carry both as well, so the alias is never used
+    /// before it is established even if messages are written out of order.
+    pub fn apply(&mut self, publish: &mut Publish) -> Option<AliasUse> {
+        if !self.enabled {
+            return None;
+        }
+
+        self.uses += 1;
+        if !self.aliases.contains_key(&publish.topic) {
+            let alias = self.free_alias()?;
+            self.aliases.insert(
+                publish.topic.clone(),
+                OutgoingAlias {
+                    alias,
+                    established: false,
+                    unwritten: 0,
+                    last_used: 0,
+                },
+            );
+        }
+        let entry = self.aliases.get_mut(&publish.topic)?;
+        entry.unwritten += 1;
+        entry.last_used = self.uses;
+        let alias = entry.alias;
+        let establishing = !entry.established;
+        let topic = if establishing {
+            publish.topic.clone()
+        } else {
+            std::mem::take(&mut publish.topic)
+        };
+
+        publish
+            .properties
+            .get_or_insert_with(|| PublishProperties {
+                payload_format_indicator: None,
+                message_expiry_interval: None,
+                topic_alias: None,
+                response_topic: None,
+                correlation_data: None,
+                user_properties: Vec::new(),
+                subscription_identifiers: Vec::new(),
+                content_type: None,
+            })
+            .topic_alias = Some(alias);
+
+        Some(AliasUse {
+            topic,
+            connection: self.connection,
+            establishing,
+        })
+    }
+
+    /// Called once the message was written, or with `written` false if it could not be sent. Once a message carrying
+    /// both the topic and its alias was written, later messages carry only the alias.
+    pub fn written(&mut self, alias_use: AliasUse, written: bool) {
+        if alias_use.connection != self.connection {
+            return;
+        }
+        if let Some(alias) = self.aliases.get_mut(&alias_use.topic) {
+            alias.unwritten -= 1;
+            alias.established |= written && alias_use.establishing;
+        }
+    }
+
+    // A new alias while there are some left, otherwise the least recently used one which no unwritten message uses.
+    fn free_alias(&mut self) -> Option<u16> {
+        if self.aliases.len() < self.maximum.into() {
+            return Some(self.aliases.len() as u16 + 1);
+        }
+
+        let topic = self
+            .aliases
+            .iter()
+            .filter(|(_, alias)| alias.unwritten == 0)
+            .min_by_key(|(_, alias)| alias.last_used)
+            .map(|(topic, _)| topic.clone())?;
+        self.aliases.remove(&topic).map(|alias| alias.alias)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqttbytes::QoS;
+
+    use super::*;
+
+    fn alias(publish: &Publish) -> Option<u16> {
+        publish.properties.as_ref()?.topic_alias
+    }
+
+    #[test]
+    fn outgoing_alias_is_resolved_by_receiver() {
+        let mut outgoing = OutgoingAliases::new(true);
+        outgoing.reset(1);
+        let mut incoming = IncomingAliases::new(1);
+
+        for topic in ["foo", "bar", "foo", "foo"] {
+            let mut publish = Publish::new(topic, QoS::AtMostOnce, []);
+            let alias_use = outgoing.apply(&mut publish).unwrap();
+            outgoing.written(alias_use, true);
+            incoming.resolve(&mut publish).unwrap();
+            assert_eq!(publish.topic, topic);
+        }
+
+        let mut publish = Publish::new("foo", QoS::AtMostOnce, []);
+        assert!(outgoing.apply(&mut publish).is_some());
+        assert_eq!(publish.topic, "");
+    }
+
+    #[test]
+    fn alias_is_used_only_once_established() {
+        let mut outgoing = OutgoingAliases::new(true);
+        outgoing.reset(1);
+
+        let mut first = Publish::new("foo", QoS::AtMostOnce, []);
+        let establishing = outgoing.apply(&mut first).unwrap();
+        // The first message was not written yet, so the next one cannot rely on the alias.
+        let mut second = Publish::new("foo", QoS::AtMostOnce, []);
+        let previous_connection = outgoing.apply(&mut second).unwrap();
+        assert_eq!(second.topic, "foo");
+
+        outgoing.written(establishing, true);
+        let mut third = Publish::new("foo", QoS::AtMostOnce, []);
+        outgoing.apply(&mut third).unwrap();
+        assert_eq!(third.topic, "");
+
+        // Messages written on a previous connection do not establish aliases.
+        outgoing.reset(1);
+        let mut fourth = Publish::new("foo", QoS::AtMostOnce, []);
+        outgoing.apply(&mut fourth).unwrap();
+        outgoing.written(previous_connection, true);
+        let mut fifth = Publish::new("foo", QoS::AtMostOnce, []);
+        outgoing.apply(&mut fifth).unwrap();
+        assert_eq!(fifth.topic, "foo");
+    }
+
+    #[test]
+    fn least_recently_used_alias_is_reassigned_once_written() {
+        let mut outgoing = OutgoingAliases::new(true);
+        outgoing.reset(2);
+        for topic in ["foo", "bar", "foo"] {
+            let mut publish = Publish::new(topic, QoS::AtMostOnce, []);
+            let alias_use = outgoing.apply(&mut publish).unwrap();
+            outgoing.written(alias_use, true);
+        }
+
+        let mut publish = Publish::new("baz", QoS::AtMostOnce, []);
+        let unwritten = outgoing.apply(&mut publish).unwrap();
+        assert_eq!((publish.topic.as_str(), alias(&publish)), ("baz", Some(2)));
+
+        // `foo` is the least recently used alias.
+        let mut publish = Publish::new("bar", QoS::AtMostOnce, []);
+        let _also_unwritten = outgoing.apply(&mut publish).unwrap();
+        assert_eq!((publish.topic.as_str(), alias(&publish)), ("bar", Some(1)));
+        // Both aliases are used by messages which were not written yet.
+        let mut publish = Publish::new("qux", QoS::AtMostOnce, []);
+        assert!(outgoing.apply(&mut publish).is_none());
+        assert_eq!((publish.topic.as_str(), alias(&publish)), ("qux", None));
+
+        outgoing.written(unwritten, false);
+        let mut publish = Publish::new("qux", QoS::AtMostOnce, []);
+        outgoing.apply(&mut publish).unwrap();
+        assert_eq!((publish.topic.as_str(), alias(&publish)), ("qux", Some(2)));
+    }
+}
@@ -1,15 +1,43 @@
+mod auth;
+#[cfg(feature = "broker")]
+pub mod broker;
 mod client;
 mod connection;
+mod error;
 mod handlers;
 mod router;
+mod session;
 mod subscribe;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod v4;
 
+pub use auth::Authenticator;
+#[cfg(feature = "scram")]
+pub use auth::ScramSha256;
 pub use client::Client;
 pub use client::ClientBuilder;
 pub use client::ClientState;
+pub use client::RouteGuard;
+pub use client::ShutdownReport;
+pub use client::SubscriptionStream;
+#[cfg(feature = "tls")]
+pub use client::TlsConfig;
+pub use client::Will;
+pub use client::{ClientConfig, OfflineQueueConfig, ReconnectConfig, TlsFiles, WillConfig};
+pub use client::{
+    ConnectionEvent, ConnectionState, EndpointStrategy, ReconnectPolicy, ServerDisconnect,
+};
+pub use error::Error;
+pub use handlers::offline::OverflowPolicy;
+pub use handlers::publish::FlowControl;
+pub use mqttbytes::{Protocol, QoS};
 pub use router::Publisher;
 pub use router::Subscriber;
+#[cfg(feature = "sled")]
+pub use session::SledStore;
+pub use session::{FileStore, MemoryStore, Outgoing, QueuedPublish, SessionState, SessionStore};
 pub use subscribe::extractor::*;
 pub use subscribe::handler::Handler;
 pub use subscribe::router::HandlerRouter;
-pub use subscribe::router::HandlerRouterBuilder;
+pub use subscribe::router::{HandlerRouterBuilder, MessageOrder, RouteOptions};
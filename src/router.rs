@@ -1,23 +1,35 @@
-use std::{convert::Infallible, future::Future, pin::Pin, sync::Arc};
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::{pin, Pin},
+    sync::Arc,
+};
 
 use mqttbytes::{
-    v5::{Packet, Publish, Subscribe},
-    QoS,
+    v5::{
+        ConnectReturnCode, Disconnect, DisconnectReasonCode, Packet, Publish, PublishProperties,
+        Subscribe, Unsubscribe,
+    },
+    Protocol, QoS,
 };
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
-    sync::Mutex,
+    sync::{broadcast, Mutex},
 };
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
 use crate::{
-    connection::Connection,
+    auth::packet::Auth,
+    client::{event, ClientOptions, ConnectionEvent, ServerDisconnect},
+    connection::{Connection, Reader, Writer},
     handlers::{
+        auth::AuthHandler,
         connect::ConnectHandler,
-        publish::{ReceivedPublishHandler, SentPublishHandler},
+        offline::{Enqueue, OfflineQueue},
+        publish::{PendingAcks, ReceivedPublishHandler, SentPublishHandler},
         subscribe::SubscribeHandler,
     },
-    ClientState, Extractable, HandlerRouter,
+    ClientState, Error, Extractable, HandlerRouter,
 };
 
 pub(crate) struct Router<R, W> {
@@ -27,6 +39,15 @@ pub(crate) struct Router<R, W> {
     pub sent_publish: Arc<Mutex<SentPublishHandler>>,
     pub received_publish: Arc<Mutex<ReceivedPublishHandler>>,
     pub subscribe: Arc<Mutex<SubscribeHandler>>,
+    pub auth: Arc<Mutex<AuthHandler>>,
+    pub offline: Arc<Mutex<OfflineQueue>>,
+
+    pub events: broadcast::Sender<ConnectionEvent>,
+
+    // Cancelled once the client starts shutting down.
+    pub shutdown: CancellationToken,
+    // Tasks running message handlers.
+    pub handlers: TaskTracker,
 }
 
 impl<R, W> Clone for Router<R, W> {
@@ -37,6 +58,11 @@ impl<R, W> Clone for Router<R, W> {
             sent_publish: self.sent_publish.clone(),
             received_publish: self.received_publish.clone(),
             subscribe: self.subscribe.clone(),
+            auth: self.auth.clone(),
+            offline: self.offline.clone(),
+            events: self.events.clone(),
+            shutdown: self.shutdown.clone(),
+            handlers: self.handlers.clone(),
         }
     }
 }
@@ -51,10 +77,62 @@ where
 
         let responses = match packet {
             Packet::Connect(_) => unreachable!("Client cannot receive connect."),
-            Packet::ConnAck(packet) => self.connect.lock().await.connack(packet),
+            Packet::ConnAck(packet) => {
+                let session_present = packet.session_present;
+                let code = packet.code;
+                let receive_maximum = packet
+                    .properties
+                    .as_ref()
+                    .and_then(|properties| properties.receive_max);
+                let max_packet_size = packet
+                    .properties
+                    .as_ref()
+                    .and_then(|properties| properties.max_packet_size);
+                let topic_alias_maximum = packet
+                    .properties
+                    .as_ref()
+                    .and_then(|properties| properties.topic_alias_max);
+                self.connection.set_max_outgoing_size(max_packet_size);
+                let authenticated = self.auth.lock().await.connack(&packet);
+                let responses = self.connect.lock().await.connack(packet);
+                if let Err(reason_code) = authenticated {
+                    self.disconnect_with(reason_code).await;
+                    return;
+                }
+                // A refusal never has a session, resuming would discard the stored messages.
+                if code != ConnectReturnCode::Success {
+                    self.event(ConnectionEvent::ConnectionRefused { code });
+                    return;
+                }
+
+                self.received_publish.lock().await.resume(session_present);
+                let retransmit = self.sent_publish.lock().await.resume(
+                    session_present,
+                    receive_maximum,
+                    topic_alias_maximum,
+                );
+                // These packets were already prepared when they were sent for the first time.
+                for packet in retransmit {
+                    tracing::debug!(?packet, "Retransmitting packet.");
+                    match self.connection.send(&packet) {
+                        Ok(sent) => sent.await,
+                        Err(error) => self.send_failed(&packet, error).await,
+                    }
+                }
+
+                self.event(ConnectionEvent::Connected { session_present });
+                responses
+            }
             Packet::Disconnect(packet) => {
-                // We ignore disconnect packets for now. We should prevent the client from sending any more MQTT packets after this and close the connection. For now we just let the receiving control loop finish on its own after the connection is closed by the peer.
-                tracing::debug!(disconnect = ?packet, "DISCONNECT received.");
+                let disconnect = ServerDisconnect::from(packet);
+                tracing::info!(?disconnect, "Disconnected by the broker.");
+                self.connect
+                    .lock()
+                    .await
+                    .server_disconnect(disconnect.clone());
+                self.event(ConnectionEvent::ServerDisconnect(disconnect));
+                // No more packets may be sent, the receive loop ends once the broker closes the connection.
+                self.shutdown().await;
                 Vec::new()
             }
             Packet::PingReq => unreachable!("Client cannot receive ping request."),
@@ -81,17 +159,120 @@ where
 
         tracing::debug!(?responses, "Sending responses.");
 
+        let disconnect = responses
+            .iter()
+            .any(|response| matches!(response, Packet::Disconnect(_)));
+
         for response in responses {
             self.route_sent(response).await;
         }
+
+        if disconnect {
+            self.shutdown().await;
+        }
+    }
+
+    pub async fn route_auth(&self, auth: Auth) {
+        tracing::debug!(?auth, "Routing received AUTH.");
+
+        let response = self.auth.lock().await.auth(auth);
+        match response {
+            Ok(Some(response)) => match self.connection.send_auth(&response) {
+                Ok(sent) => sent.await,
+                Err(error) => {
+                    tracing::error!(%error, "Unable to send AUTH, disconnecting.");
+                    self.auth.lock().await.disconnected();
+                    self.disconnect_with(DisconnectReasonCode::ImplementationSpecificError)
+                        .await;
+                }
+            },
+            Ok(None) => {}
+            Err(reason_code) => self.disconnect_with(reason_code).await,
+        }
+    }
+
+    pub async fn reauthenticate(&self) -> Result<(), Error> {
+        if self.connection.protocol() == Protocol::V4 {
+            return Err(Error::UnsupportedByProtocol("re-authentication"));
+        }
+        let (auth, finished) = self.auth.lock().await.reauthenticate()?;
+        self.connection.send_auth(&auth)?.await;
+        finished.await.unwrap_or_else(|_| {
+            Err(Error::Authentication(
+                "re-authentication was abandoned".to_owned(),
+            ))
+        })
+    }
+
+    // Closes the connection because of a protocol violation or failed authentication.
+    pub async fn disconnect_with(&self, reason_code: DisconnectReasonCode) {
+        let mut disconnect = Disconnect::new();
+        disconnect.reason_code = reason_code;
+        self.route_sent(Packet::Disconnect(disconnect)).await;
+        self.shutdown().await;
+    }
+
+    pub fn event(&self, event: ConnectionEvent) {
+        tracing::debug!(?event, "Connection event.");
+        // Nobody may be listening.
+        let _ = self.events.send(event);
+    }
+
+    /// Fails pending operations after the connection closed unexpectedly, returns the broker's DISCONNECT if there was one.
+    pub async fn connection_lost(&self) -> Option<ServerDisconnect> {
+        let disconnect = self.connect.lock().await.connection_lost();
+        let error = || {
+            disconnect
+                .as_ref()
+                .map_or(Error::ConnectionClosed, ServerDisconnect::error)
+        };
+
+        self.sent_publish.lock().await.connection_lost(error);
+        self.subscribe.lock().await.connection_lost(error);
+        self.auth.lock().await.disconnected();
+        self.offline.lock().await.disconnected();
+
+        disconnect
+    }
+
+    /// Waits until all sent QoS 1 and 2 messages are acknowledged.
+    pub async fn acknowledged(&self) {
+        loop {
+            let sent_publish = self.sent_publish.lock().await;
+            if sent_publish.unfinished() == 0 {
+                return;
+            }
+            let all_finished = sent_publish.all_finished();
+            let mut notified = pin!(all_finished.notified());
+            notified.as_mut().enable();
+            drop(sent_publish);
+            notified.await;
+        }
     }
 
     pub async fn route_sent(&self, mut packet: Packet) {
         tracing::debug!(?packet, "Routing sent packet.");
 
         let future = self.prepare_packet(&mut packet).await;
-        self.connection.send(&packet).unwrap().await;
-        future.await;
+        match self.connection.send(&packet) {
+            Ok(sent) => {
+                sent.await;
+                future.await;
+            }
+            Err(error) => self.send_failed(&packet, error).await,
+        }
+    }
+
+    // Gives up on a packet which cannot be encoded or exceeds the broker's Maximum Packet Size, so that a message is not
+    // retransmitted on every reconnect.
+    async fn send_failed(&self, packet: &Packet, error: Error) {
+        tracing::error!(%error, ?packet, "Unable to send packet.");
+        let id = match packet {
+            Packet::Publish(publish) => publish.pkid,
+            Packet::PubRel(pubrel) => pubrel.pkid,
+            _ => return,
+        };
+        self.sent_publish.lock().await.discard(id, error);
     }
 
     // This function (and every function in the match inside) both mutates the packet before it can be sent (e.g. adds packet ID to PUBLISH packets) and provides a future that resolves after the packet has been resolved (e.g. PUBLISH wih QoS 1 has been acknowledged).
@@ -100,6 +281,7 @@ where
         packet: &mut Packet,
     ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
         match packet {
+            // Authentication data is added by the caller which can give up if it cannot be created.
             Packet::Connect(packet) => self.connect.lock().await.connect(packet),
             Packet::ConnAck(_) => unreachable!("Client cannot send connect acknowledgement."),
             Packet::Disconnect(packet) => self.connect.lock().await.disconnect(packet),
@@ -107,21 +289,23 @@ where
             Packet::PingReq => self.connect.lock().await.ping(),
             Packet::PingResp => unreachable!("Client cannot send ping response."),
 
-            Packet::Publish(packet) => self.sent_publish.lock().await.publish(packet),
+            Packet::Publish(_) => {
+                unreachable!("PUBLISH is sent through `Publisher` which applies flow control.")
+            }
             Packet::PubAck(packet) => self.received_publish.lock().await.puback(packet),
             Packet::PubRec(packet) => self.received_publish.lock().await.pubrec(packet),
             Packet::PubRel(packet) => self.sent_publish.lock().await.pubrel(packet),
             Packet::PubComp(packet) => self.received_publish.lock().await.pubcomp(packet),
 
-            Packet::Subscribe(packet) => self.subscribe.lock().await.subscribe(packet),
+            Packet::Subscribe(_) => unreachable!("SUBSCRIBE is sent through `Subscriber`."),
             Packet::SubAck(_) => unreachable!("Client cannot send subscribe acknowledgement."),
 
-            Packet::Unsubscribe(packet) => self.subscribe.lock().await.unsubscribe(packet),
+            Packet::Unsubscribe(_) => unreachable!("UNSUBSCRIBE is sent through `Subscriber`."),
             Packet::UnsubAck(_) => unreachable!("Client cannot send unsubscribe acknowledgement."),
         }
     }
 
-    pub async fn shutdown(&mut self) {
+    pub async fn shutdown(&self) {
         if let Err(error) = self.connection.shutdown().await {
             tracing::warn!(
                 error.debug = format!("{error:?}"),
@@ -131,26 +315,61 @@ where
     }
 }
 
-impl Router<OwnedReadHalf, OwnedWriteHalf> {
+impl Router<Reader, Writer> {
     pub(crate) fn new(
-        connection: Arc<Connection<OwnedReadHalf, OwnedWriteHalf>>,
+        connection: Arc<Connection<Reader, Writer>>,
         router: HandlerRouter,
+        options: ClientOptions,
     ) -> Self {
-        let sent_publish = Arc::new(Mutex::new(SentPublishHandler::new()));
+        let state = options.store.load();
+
+        let sent_publish = Arc::new(Mutex::new(SentPublishHandler::new(
+            options.store.clone(),
+            &state,
+            options.flow_control,
+            options.outgoing_topic_aliases,
+        )));
         let subscribe = Arc::new(Mutex::new(SubscribeHandler::new()));
-
-        let publisher = Publisher::new(connection.clone(), sent_publish.clone());
-        let subscriber = Subscriber::new(connection.clone(), subscribe.clone());
+        let offline = Arc::new(Mutex::new(OfflineQueue::new(
+            options.store.clone(),
+            &state,
+            options.offline_queue_capacity,
+            options.overflow_policy,
+            options.persist_offline_queue,
+        )));
+
+        let shutdown = CancellationToken::new();
+        let publisher = Publisher::new(
+            connection.clone(),
+            sent_publish.clone(),
+            offline.clone(),
+            shutdown.clone(),
+        );
+        let connect = Arc::new(Mutex::new(ConnectHandler::new(options.keep_alive)));
+        let subscriber = Subscriber::new(connection.clone(), connect.clone(), subscribe.clone());
+
+        let pending_acks = PendingAcks::default();
 
         let client_state = ClientState {
             publisher,
             subscriber,
+            pending_acks: pending_acks.clone(),
+            shutdown: shutdown.clone(),
         };
 
         let router = router.build(client_state);
 
-        let connect = Arc::new(Mutex::new(ConnectHandler::new()));
-        let received_publish = Arc::new(Mutex::new(ReceivedPublishHandler::new(router)));
+        let auth = Arc::new(Mutex::new(AuthHandler::new(options.authenticator)));
+        let received_publish = Arc::new(Mutex::new(ReceivedPublishHandler::new(
+            router,
+            pending_acks,
+            options.store.clone(),
+            &state,
+            options.receive_maximum,
+            options.topic_alias_maximum,
+        )));
+
+        let (events, _) = broadcast::channel(event::CAPACITY);
 
         Self {
             connection,
@@ -158,34 +377,165 @@ impl Router<OwnedReadHalf, OwnedWriteHalf> {
             sent_publish,
             received_publish,
             subscribe,
+            auth,
+            offline,
+            events,
+            shutdown,
+            handlers: TaskTracker::new(),
         }
     }
+
+    pub(crate) fn publisher(&self) -> Publisher {
+        Publisher::new(
+            self.connection.clone(),
+            self.sent_publish.clone(),
+            self.offline.clone(),
+            self.shutdown.clone(),
+        )
+    }
+
+    pub(crate) fn subscriber(&self) -> Subscriber {
+        Subscriber::new(
+            self.connection.clone(),
+            self.connect.clone(),
+            self.subscribe.clone(),
+        )
+    }
 }
 
 #[derive(Clone)]
 pub struct Publisher {
-    connection: Arc<Connection<OwnedReadHalf, OwnedWriteHalf>>,
+    connection: Arc<Connection<Reader, Writer>>,
     sent_publish: Arc<Mutex<SentPublishHandler>>,
+    offline: Arc<Mutex<OfflineQueue>>,
+    shutdown: CancellationToken,
 }
 
 impl Publisher {
     fn new(
-        connection: Arc<Connection<OwnedReadHalf, OwnedWriteHalf>>,
+        connection: Arc<Connection<Reader, Writer>>,
         sent_publish: Arc<Mutex<SentPublishHandler>>,
+        offline: Arc<Mutex<OfflineQueue>>,
+        shutdown: CancellationToken,
     ) -> Self {
         Self {
             connection,
             sent_publish,
+            offline,
+            shutdown,
         }
     }
 
-    pub async fn publish(&self, topic: &str, qos: QoS, payload: &[u8]) {
+    /// Resolves once the message is sent, or acknowledged for QoS 1 and 2. Messages published while the client is offline are queued.
+    ///
+    /// Fails with [`Error::Rejected`] if the broker refuses the message, and with [`Error::DeliveryUnknown`] if the connection is lost before it is acknowledged.
+    pub async fn publish(&self, topic: &str, qos: QoS, payload: &[u8]) -> Result<(), Error> {
+        self.publish_message(Publish::new(topic, qos, payload))
+            .await
+    }
+
+    /// Like [`Publisher::publish`], e.g. to set the Message Expiry Interval after which a queued message is discarded.
+    pub async fn publish_with_properties(
+        &self,
+        topic: &str,
+        qos: QoS,
+        payload: &[u8],
+        properties: PublishProperties,
+    ) -> Result<(), Error> {
         let mut publish = Publish::new(topic, qos, payload);
+        publish.properties = Some(properties);
+        self.publish_message(publish).await
+    }
+
+    /// Like [`Publisher::publish`] for a prepared message, e.g. with the retain flag set. The packet identifier is assigned by the client.
+    pub async fn publish_message(&self, mut publish: Publish) -> Result<(), Error> {
+        if self.shutdown.is_cancelled() {
+            return Err(Error::ShuttingDown);
+        }
+        self.connection.check_publish_size(&publish)?;
+
+        loop {
+            let mut offline = self.offline.lock().await;
+            match offline.enqueue(publish)? {
+                Enqueue::Send(returned) => {
+                    publish = returned;
+                    break;
+                }
+                Enqueue::Queued(future) => {
+                    drop(offline);
+                    return future.await;
+                }
+                Enqueue::Full(returned, space) => {
+                    publish = returned;
+                    let mut notified = pin!(space.notified());
+                    notified.as_mut().enable();
+                    drop(offline);
+                    notified.await;
+                }
+            }
+        }
 
-        let future = self.sent_publish.lock().await.publish(&mut publish);
-        let packet = Packet::Publish(publish);
-        self.connection.send(&packet).unwrap().await;
-        future.await;
+        self.send(publish).await?.await
+    }
+
+    // Returns a future which resolves once the message is acknowledged.
+    async fn send(
+        &self,
+        mut publish: Publish,
+    ) -> Result<impl Future<Output = Result<(), Error>>, Error> {
+        let reserve = self.sent_publish.lock().await.reserve(publish.qos);
+        let permit = reserve.await?;
+
+        let mut sent_publish = self.sent_publish.lock().await;
+        let (future, alias_use) = sent_publish.publish(&mut publish, permit);
+        let pkid = publish.pkid;
+        let sent = match self.connection.send(&Packet::Publish(publish)) {
+            Ok(sent) => sent,
+            Err(error) => {
+                if let Some(alias_use) = alias_use {
+                    sent_publish.alias_written(alias_use, false);
+                }
+                // Nobody waits for messages at QoS 0.
+                if pkid == 0 {
+                    return Err(error);
+                }
+                // Fails the returned future with the error right away.
+                sent_publish.discard(pkid, error);
+                return Ok(future);
+            }
+        };
+        drop(sent_publish);
+
+        sent.await;
+        if let Some(alias_use) = alias_use {
+            self.sent_publish
+                .lock()
+                .await
+                .alias_written(alias_use, true);
+        }
+        Ok(future)
+    }
+
+    /// Sends messages queued while the client was offline, in order, until the queue is empty or the connection is lost again.
+    ///
+    /// Acknowledgements are awaited in tasks spawned on `tracker` so that shutdown waits for them.
+    pub(crate) async fn flush_offline(&self, tracker: &TaskTracker) {
+        let connection = self.offline.lock().await.connection();
+        loop {
+            let Some(mut message) = self.offline.lock().await.next(connection) else {
+                return;
+            };
+            tracing::debug!(topic = message.publish.topic, "Sending queued message.");
+            match self.send(message.publish.clone()).await {
+                Ok(acknowledged) => {
+                    tracker.spawn(async move {
+                        let result = acknowledged.await;
+                        message.finish(result);
+                    });
+                }
+                Err(error) => message.finish(Err(error)),
+            }
+        }
     }
 }
 
@@ -203,28 +553,77 @@ impl<S> Extractable<S> for Publisher {
 
 #[derive(Clone)]
 pub struct Subscriber {
-    connection: Arc<Connection<OwnedReadHalf, OwnedWriteHalf>>,
+    connection: Arc<Connection<Reader, Writer>>,
+    connect: Arc<Mutex<ConnectHandler>>,
     subscribe: Arc<Mutex<SubscribeHandler>>,
 }
 
 impl Subscriber {
     fn new(
-        connection: Arc<Connection<OwnedReadHalf, OwnedWriteHalf>>,
+        connection: Arc<Connection<Reader, Writer>>,
+        connect: Arc<Mutex<ConnectHandler>>,
         subscribe: Arc<Mutex<SubscribeHandler>>,
     ) -> Self {
         Self {
             connection,
+            connect,
             subscribe,
         }
     }
 
-    pub async fn subscribe(&self, topic: &str) {
-        let mut subscribe = Subscribe::new(topic, QoS::ExactlyOnce);
+    /// Fails with [`Error::ConnectionClosed`] while the client is not connected.
+    pub async fn subscribe(&self, topic: &str) -> Result<(), Error> {
+        self.send_subscribe(Subscribe::new(topic, QoS::ExactlyOnce))
+            .await
+    }
+
+    pub(crate) async fn send_subscribe(&self, mut subscribe: Subscribe) -> Result<(), Error> {
+        // Held until the subscription is registered so that losing the connection fails it.
+        let connect = self.connect.lock().await;
+        if !connect.is_connected() {
+            return Err(Error::ConnectionClosed);
+        }
+        let mut handler = self.subscribe.lock().await;
+        drop(connect);
+        let future = handler.subscribe(&mut subscribe);
+        let pkid = subscribe.pkid;
+        let sent = match self.connection.send(&Packet::Subscribe(subscribe)) {
+            Ok(sent) => sent,
+            Err(error) => {
+                handler.subscribe_not_sent(pkid);
+                return Err(error);
+            }
+        };
+        drop(handler);
 
-        let future = self.subscribe.lock().await.subscribe(&mut subscribe);
-        let packet = Packet::Subscribe(subscribe);
-        self.connection.send(&packet).unwrap().await;
-        future.await;
+        sent.await;
+        future.await
+    }
+
+    pub(crate) async fn unsubscribe(&self, topic: &str) -> Result<(), Error> {
+        let mut unsubscribe = Unsubscribe::new(topic);
+
+        let connect = self.connect.lock().await;
+        let mut handler = self.subscribe.lock().await;
+        if !connect.is_connected() {
+            // Not subscribed to again after reconnecting.
+            handler.forget(&unsubscribe.filters);
+            return Err(Error::ConnectionClosed);
+        }
+        drop(connect);
+        let future = handler.unsubscribe(&mut unsubscribe);
+        let pkid = unsubscribe.pkid;
+        let sent = match self.connection.send(&Packet::Unsubscribe(unsubscribe)) {
+            Ok(sent) => sent,
+            Err(error) => {
+                handler.unsubscribe_not_sent(pkid);
+                return Err(error);
+            }
+        };
+        drop(handler);
+
+        sent.await;
+        future.await
     }
 }
 
@@ -242,14 +641,12 @@ impl<S> Extractable<S> for Subscriber {
 
 #[cfg(test)]
 mod tests {
-    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-
     use super::*;
 
     fn is_send<T: Send>() {}
 
     #[allow(dead_code)]
     fn assert_send() {
-        is_send::<Router<OwnedReadHalf, OwnedWriteHalf>>();
+        is_send::<Router<Reader, Writer>>();
     }
 }
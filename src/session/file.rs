@@ -0,0 +1,161 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use mqttbytes::v5::Publish;
+
+use super::{QueuedPublish, SessionState, SessionStore};
+
+/// Stores the session state in a single file which is rewritten on every change.
+///
+/// Each change is synced to disk before the client continues, which blocks the runtime thread processing the packet.
+/// The whole state is rewritten, so the cost grows with the number of stored messages.
+pub struct FileStore {
+    path: PathBuf,
+    state: SessionState,
+}
+
+impl FileStore {
+    /// Opens the store, loading any state previously saved at `path`.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let state = match fs::read(&path) {
+            Ok(content) => SessionState::decode(content.into())?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => SessionState::default(),
+            Err(error) => return Err(error),
+        };
+
+        Ok(Self { path, state })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        // Write to a temporary file first so that a crash does not leave a partially written state behind.
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+
+        let mut file = File::create(&temporary)?;
+        file.write_all(&self.state.encode()?)?;
+        file.sync_all()?;
+        fs::rename(&temporary, &self.path)?;
+
+        // Sync the directory as well so that the rename itself survives a crash.
+        #[cfg(unix)]
+        if let Some(parent) = self.path.parent() {
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+            File::open(parent)?.sync_all()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl SessionStore for FileStore {
+    fn load(&mut self) -> io::Result<SessionState> {
+        Ok(self.state.clone())
+    }
+
+    fn set_next_id(&mut self, id: u16) -> io::Result<()> {
+        self.state.next_id = id;
+        self.save()
+    }
+
+    fn add_outgoing(&mut self, publish: &Publish) -> io::Result<()> {
+        self.state.add_outgoing(publish);
+        self.save()
+    }
+
+    fn release_outgoing(&mut self, pkid: u16) -> io::Result<()> {
+        self.state.release_outgoing(pkid);
+        self.save()
+    }
+
+    fn remove_outgoing(&mut self, pkid: u16) -> io::Result<()> {
+        self.state.remove_outgoing(pkid);
+        self.save()
+    }
+
+    fn add_incoming(&mut self, pkid: u16) -> io::Result<()> {
+        self.state.add_incoming(pkid);
+        self.save()
+    }
+
+    fn remove_incoming(&mut self, pkid: u16) -> io::Result<()> {
+        self.state.remove_incoming(pkid);
+        self.save()
+    }
+
+    fn add_queued(&mut self, queued: &QueuedPublish) -> io::Result<()> {
+        self.state.add_queued(queued);
+        self.save()
+    }
+
+    fn remove_queued(&mut self, id: u64) -> io::Result<()> {
+        self.state.remove_queued(id);
+        self.save()
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.state = SessionState::default();
+        self.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqttbytes::QoS;
+
+    use super::*;
+    use crate::session::Outgoing;
+
+    #[test]
+    fn state_survives_reopening() {
+        let path = std::env::temp_dir().join(format!("qute-session-{}", std::process::id()));
+
+        let mut first = Publish::new("first", QoS::AtLeastOnce, "payload");
+        first.pkid = 7;
+        let mut second = Publish::new("second", QoS::ExactlyOnce, "payload");
+        second.pkid = 8;
+
+        let mut store = FileStore::open(&path).unwrap();
+        store.clear().unwrap();
+        store.set_next_id(8).unwrap();
+        store.add_outgoing(&first).unwrap();
+        store.add_outgoing(&second).unwrap();
+        store.release_outgoing(8).unwrap();
+        store.add_incoming(3).unwrap();
+        let mut queued = QueuedPublish {
+            id: 1,
+            publish: Publish::new("queued", QoS::AtLeastOnce, "payload"),
+            queued_at: std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(60),
+        };
+        queued.publish.properties = Some(mqttbytes::v5::PublishProperties {
+            payload_format_indicator: None,
+            message_expiry_interval: Some(30),
+            topic_alias: None,
+            response_topic: None,
+            correlation_data: None,
+            user_properties: Vec::new(),
+            subscription_identifiers: Vec::new(),
+            content_type: None,
+        });
+        store.add_queued(&queued).unwrap();
+        drop(store);
+
+        let state = FileStore::open(&path).unwrap().load().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(state.next_id, 8);
+        assert_eq!(
+            state.outgoing,
+            vec![Outgoing::Publish(first), Outgoing::Release(8)]
+        );
+        assert_eq!(state.incoming, vec![3]);
+        assert_eq!(state.queued, vec![queued]);
+    }
+}
@@ -0,0 +1,357 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use mqttbytes::{
+    v5::{Packet, Publish},
+    QoS,
+};
+
+#[cfg(feature = "sled")]
+pub use self::sled::SledStore;
+pub use file::FileStore;
+
+mod file;
+#[cfg(feature = "sled")]
+mod sled;
+
+/// Persists the session state so that in-flight messages survive a restart of the process.
+///
+/// Stores are called while the client processes packets, so they should not block for long.
+pub trait SessionStore: Send + 'static {
+    fn load(&mut self) -> io::Result<SessionState>;
+
+    fn set_next_id(&mut self, id: u16) -> io::Result<()>;
+
+    /// Outgoing PUBLISH with QoS 1 or 2 which was not acknowledged yet.
+    fn add_outgoing(&mut self, publish: &Publish) -> io::Result<()>;
+    /// PUBREC was received for an outgoing PUBLISH, only PUBREL needs to be retransmitted from now on.
+    fn release_outgoing(&mut self, pkid: u16) -> io::Result<()>;
+    fn remove_outgoing(&mut self, pkid: u16) -> io::Result<()>;
+
+    /// Incoming QoS 2 message which was acknowledged with PUBREC but not released yet.
+    fn add_incoming(&mut self, pkid: u16) -> io::Result<()>;
+    fn remove_incoming(&mut self, pkid: u16) -> io::Result<()>;
+
+    /// Message published while the client was offline, only used if the offline queue is persistent.
+    fn add_queued(&mut self, queued: &QueuedPublish) -> io::Result<()>;
+    /// Queued message which was sent, dropped or expired.
+    fn remove_queued(&mut self, id: u64) -> io::Result<()>;
+
+    fn clear(&mut self) -> io::Result<()>;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SessionState {
+    pub next_id: u16,
+    /// Outgoing messages in the order in which they were sent.
+    pub outgoing: Vec<Outgoing>,
+    pub incoming: Vec<u16>,
+    /// Messages published while the client was offline, in the order in which they were published.
+    pub queued: Vec<QueuedPublish>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedPublish {
+    pub id: u64,
+    pub publish: Publish,
+    /// Used to expire the message according to its Message Expiry Interval.
+    pub queued_at: SystemTime,
+}
+
+impl QueuedPublish {
+    pub(crate) fn encode(&self, buf: &mut BytesMut) -> io::Result<()> {
+        buf.put_u64(self.id);
+        let queued_at = self
+            .queued_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        buf.put_u64(queued_at.as_millis() as u64);
+        buf.put_u8(self.publish.qos as u8);
+
+        // The message has no packet identifier yet which `mqttbytes` only accepts for QoS 0.
+        let mut publish = self.publish.clone();
+        publish.qos = QoS::AtMostOnce;
+        let mut packet = BytesMut::new();
+        publish.write(&mut packet).map_err(invalid_data)?;
+        buf.put_u32(packet.len() as u32);
+        buf.put(packet);
+        Ok(())
+    }
+
+    pub(crate) fn decode(buf: &mut Bytes) -> io::Result<Self> {
+        let id = read_u64(buf)?;
+        let queued_at = SystemTime::UNIX_EPOCH + Duration::from_millis(read_u64(buf)?);
+        let qos = mqttbytes::qos(read_u8(buf)?).map_err(invalid_data)?;
+
+        let len = read_u32(buf)? as usize;
+        if buf.remaining() < len {
+            return Err(invalid_data("truncated PUBLISH"));
+        }
+        let mut packet = BytesMut::from(&buf.split_to(len)[..]);
+        let mut publish = match mqttbytes::v5::read(&mut packet, len).map_err(invalid_data)? {
+            Packet::Publish(publish) => publish,
+            packet => return Err(invalid_data(format!("unexpected packet {packet:?}"))),
+        };
+        publish.qos = qos;
+
+        Ok(Self {
+            id,
+            publish,
+            queued_at,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::large_enum_variant)]
+pub enum Outgoing {
+    /// PUBLISH waiting for PUBACK or PUBREC.
+    Publish(Publish),
+    /// PUBREL waiting for PUBCOMP.
+    Release(u16),
+}
+
+impl Outgoing {
+    pub fn pkid(&self) -> u16 {
+        match self {
+            Outgoing::Publish(publish) => publish.pkid,
+            Outgoing::Release(pkid) => *pkid,
+        }
+    }
+
+    pub(crate) fn encode(&self, buf: &mut BytesMut) -> io::Result<()> {
+        match self {
+            Outgoing::Publish(publish) => {
+                buf.put_u8(0);
+                let mut packet = BytesMut::new();
+                publish.write(&mut packet).map_err(invalid_data)?;
+                buf.put_u32(packet.len() as u32);
+                buf.put(packet);
+            }
+            Outgoing::Release(pkid) => {
+                buf.put_u8(1);
+                buf.put_u16(*pkid);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn decode(buf: &mut Bytes) -> io::Result<Self> {
+        match read_u8(buf)? {
+            0 => {
+                let len = read_u32(buf)? as usize;
+                if buf.remaining() < len {
+                    return Err(invalid_data("truncated PUBLISH"));
+                }
+                let mut packet = BytesMut::from(&buf.split_to(len)[..]);
+                match mqttbytes::v5::read(&mut packet, len).map_err(invalid_data)? {
+                    Packet::Publish(publish) => Ok(Outgoing::Publish(publish)),
+                    packet => Err(invalid_data(format!("unexpected packet {packet:?}"))),
+                }
+            }
+            1 => Ok(Outgoing::Release(read_u16(buf)?)),
+            tag => Err(invalid_data(format!("unknown entry tag {tag}"))),
+        }
+    }
+}
+
+impl SessionState {
+    fn add_outgoing(&mut self, publish: &Publish) {
+        self.remove_outgoing(publish.pkid);
+        self.outgoing.push(Outgoing::Publish(publish.clone()));
+    }
+
+    fn release_outgoing(&mut self, pkid: u16) {
+        if let Some(outgoing) = self.outgoing.iter_mut().find(|o| o.pkid() == pkid) {
+            *outgoing = Outgoing::Release(pkid);
+        }
+    }
+
+    fn remove_outgoing(&mut self, pkid: u16) {
+        self.outgoing.retain(|outgoing| outgoing.pkid() != pkid);
+    }
+
+    fn add_incoming(&mut self, pkid: u16) {
+        if !self.incoming.contains(&pkid) {
+            self.incoming.push(pkid);
+        }
+    }
+
+    fn remove_incoming(&mut self, pkid: u16) {
+        self.incoming.retain(|id| *id != pkid);
+    }
+
+    fn add_queued(&mut self, queued: &QueuedPublish) {
+        self.queued.push(queued.clone());
+    }
+
+    fn remove_queued(&mut self, id: u64) {
+        self.queued.retain(|queued| queued.id != id);
+    }
+
+    fn encode(&self) -> io::Result<BytesMut> {
+        let mut buf = BytesMut::new();
+        buf.put_u16(self.next_id);
+        buf.put_u16(self.incoming.len() as u16);
+        for pkid in &self.incoming {
+            buf.put_u16(*pkid);
+        }
+        buf.put_u16(self.outgoing.len() as u16);
+        for outgoing in &self.outgoing {
+            outgoing.encode(&mut buf)?;
+        }
+        buf.put_u32(self.queued.len() as u32);
+        for queued in &self.queued {
+            queued.encode(&mut buf)?;
+        }
+        Ok(buf)
+    }
+
+    fn decode(mut buf: Bytes) -> io::Result<Self> {
+        let next_id = read_u16(&mut buf)?;
+        let incoming = (0..read_u16(&mut buf)?)
+            .map(|_| read_u16(&mut buf))
+            .collect::<io::Result<_>>()?;
+        let outgoing = (0..read_u16(&mut buf)?)
+            .map(|_| Outgoing::decode(&mut buf))
+            .collect::<io::Result<_>>()?;
+        // States saved before messages could be queued end here.
+        let queued = if buf.has_remaining() {
+            (0..read_u32(&mut buf)?)
+                .map(|_| QueuedPublish::decode(&mut buf))
+                .collect::<io::Result<_>>()?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Self {
+            next_id,
+            outgoing,
+            incoming,
+            queued,
+        })
+    }
+}
+
+/// Keeps the session state in memory only, i.e. it is lost when the process exits.
+#[derive(Default)]
+pub struct MemoryStore {
+    state: SessionState,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&mut self) -> io::Result<SessionState> {
+        Ok(self.state.clone())
+    }
+
+    fn set_next_id(&mut self, id: u16) -> io::Result<()> {
+        self.state.next_id = id;
+        Ok(())
+    }
+
+    fn add_outgoing(&mut self, publish: &Publish) -> io::Result<()> {
+        self.state.add_outgoing(publish);
+        Ok(())
+    }
+
+    fn release_outgoing(&mut self, pkid: u16) -> io::Result<()> {
+        self.state.release_outgoing(pkid);
+        Ok(())
+    }
+
+    fn remove_outgoing(&mut self, pkid: u16) -> io::Result<()> {
+        self.state.remove_outgoing(pkid);
+        Ok(())
+    }
+
+    fn add_incoming(&mut self, pkid: u16) -> io::Result<()> {
+        self.state.add_incoming(pkid);
+        Ok(())
+    }
+
+    fn remove_incoming(&mut self, pkid: u16) -> io::Result<()> {
+        self.state.remove_incoming(pkid);
+        Ok(())
+    }
+
+    fn add_queued(&mut self, queued: &QueuedPublish) -> io::Result<()> {
+        self.state.add_queued(queued);
+        Ok(())
+    }
+
+    fn remove_queued(&mut self, id: u64) -> io::Result<()> {
+        self.state.remove_queued(id);
+        Ok(())
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.state = SessionState::default();
+        Ok(())
+    }
+}
+
+fn invalid_data(error: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+fn read_u8(buf: &mut Bytes) -> io::Result<u8> {
+    if buf.remaining() < 1 {
+        return Err(invalid_data("unexpected end of session state"));
+    }
+    Ok(buf.get_u8())
+}
+
+fn read_u16(buf: &mut Bytes) -> io::Result<u16> {
+    if buf.remaining() < 2 {
+        return Err(invalid_data("unexpected end of session state"));
+    }
+    Ok(buf.get_u16())
+}
+
+fn read_u32(buf: &mut Bytes) -> io::Result<u32> {
+    if buf.remaining() < 4 {
+        return Err(invalid_data("unexpected end of session state"));
+    }
+    Ok(buf.get_u32())
+}
+
+fn read_u64(buf: &mut Bytes) -> io::Result<u64> {
+    if buf.remaining() < 8 {
+        return Err(invalid_data("unexpected end of session state"));
+    }
+    Ok(buf.get_u64())
+}
+
+#[derive(Clone)]
+pub(crate) struct SharedSessionStore(Arc<Mutex<Box<dyn SessionStore>>>);
+
+impl SharedSessionStore {
+    pub(crate) fn new(store: Box<dyn SessionStore>) -> Self {
+        Self(Arc::new(Mutex::new(store)))
+    }
+
+    pub(crate) fn load(&self) -> SessionState {
+        self.0.lock().unwrap().load().unwrap_or_else(|error| {
+            tracing::error!(%error, "Unable to load session state, starting with an empty one.");
+            SessionState::default()
+        })
+    }
+
+    // Failing to persist the state is logged but does not interrupt processing of the packet.
+    pub(crate) fn update(&self, update: impl FnOnce(&mut dyn SessionStore) -> io::Result<()>) {
+        let mut store = self.0.lock().unwrap();
+        if let Err(error) = update(store.as_mut()) {
+            tracing::error!(%error, "Unable to persist session state.");
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use std::{collections::HashMap, io, path::Path};
+
+use bytes::{Bytes, BytesMut};
+use mqttbytes::v5::Publish;
+
+use super::{invalid_data, Outgoing, QueuedPublish, SessionState, SessionStore};
+
+const NEXT_ID: &[u8] = b"next_id";
+const INCOMING: u8 = b'i';
+const OUTGOING: u8 = b'o';
+const QUEUED: u8 = b'q';
+
+/// Stores the session state in a [sled](https://docs.rs/sled) database.
+pub struct SledStore {
+    db: sled::Db,
+    // Outgoing entries are keyed by a sequence number so that they are loaded in the order in which they were sent.
+    outgoing: HashMap<u16, u64>,
+    next_sequence: u64,
+}
+
+impl SledStore {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::with_db(sled::open(path)?)
+    }
+
+    pub fn with_db(db: sled::Db) -> io::Result<Self> {
+        Ok(Self {
+            db,
+            outgoing: HashMap::new(),
+            next_sequence: 0,
+        })
+    }
+
+    fn outgoing_key(sequence: u64) -> [u8; 9] {
+        let mut key = [OUTGOING; 9];
+        key[1..].copy_from_slice(&sequence.to_be_bytes());
+        key
+    }
+
+    fn queued_key(id: u64) -> [u8; 9] {
+        let mut key = [QUEUED; 9];
+        key[1..].copy_from_slice(&id.to_be_bytes());
+        key
+    }
+
+    fn incoming_key(pkid: u16) -> [u8; 3] {
+        let [high, low] = pkid.to_be_bytes();
+        [INCOMING, high, low]
+    }
+
+    fn insert_outgoing(&mut self, outgoing: &Outgoing, sequence: u64) -> io::Result<()> {
+        let mut value = BytesMut::new();
+        outgoing.encode(&mut value)?;
+        self.db
+            .insert(Self::outgoing_key(sequence), value.as_ref())?;
+        self.outgoing.insert(outgoing.pkid(), sequence);
+        self.flush()
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+impl SessionStore for SledStore {
+    fn load(&mut self) -> io::Result<SessionState> {
+        let mut state = SessionState::default();
+
+        if let Some(next_id) = self.db.get(NEXT_ID)? {
+            let next_id = next_id
+                .as_ref()
+                .try_into()
+                .map_err(|_| invalid_data("invalid packet identifier"))?;
+            state.next_id = u16::from_be_bytes(next_id);
+        }
+
+        for entry in self.db.scan_prefix([INCOMING]) {
+            let (key, _) = entry?;
+            let pkid = key[1..]
+                .try_into()
+                .map_err(|_| invalid_data("invalid packet identifier"))?;
+            state.incoming.push(u16::from_be_bytes(pkid));
+        }
+
+        self.outgoing.clear();
+        for entry in self.db.scan_prefix([OUTGOING]) {
+            let (key, value) = entry?;
+            let sequence = key[1..]
+                .try_into()
+                .map_err(|_| invalid_data("invalid sequence number"))?;
+            let sequence = u64::from_be_bytes(sequence);
+            let outgoing = Outgoing::decode(&mut Bytes::copy_from_slice(&value))?;
+
+            self.outgoing.insert(outgoing.pkid(), sequence);
+            self.next_sequence = sequence + 1;
+            state.outgoing.push(outgoing);
+        }
+
+        // Keys of queued messages are their increasing identifiers.
+        for entry in self.db.scan_prefix([QUEUED]) {
+            let (_, value) = entry?;
+            let queued = QueuedPublish::decode(&mut Bytes::copy_from_slice(&value))?;
+            state.queued.push(queued);
+        }
+
+        Ok(state)
+    }
+
+    fn set_next_id(&mut self, id: u16) -> io::Result<()> {
+        self.db.insert(NEXT_ID, &id.to_be_bytes())?;
+        self.flush()
+    }
+
+    fn add_outgoing(&mut self, publish: &Publish) -> io::Result<()> {
+        if let Some(sequence) = self.outgoing.remove(&publish.pkid) {
+            self.db.remove(Self::outgoing_key(sequence))?;
+        }
+
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.insert_outgoing(&Outgoing::Publish(publish.clone()), sequence)
+    }
+
+    fn release_outgoing(&mut self, pkid: u16) -> io::Result<()> {
+        match self.outgoing.get(&pkid) {
+            // Keep the sequence number so that the PUBREL is retransmitted in the original order.
+            Some(&sequence) => self.insert_outgoing(&Outgoing::Release(pkid), sequence),
+            None => Ok(()),
+        }
+    }
+
+    fn remove_outgoing(&mut self, pkid: u16) -> io::Result<()> {
+        if let Some(sequence) = self.outgoing.remove(&pkid) {
+            self.db.remove(Self::outgoing_key(sequence))?;
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn add_incoming(&mut self, pkid: u16) -> io::Result<()> {
+        self.db.insert(Self::incoming_key(pkid), &[])?;
+        self.flush()
+    }
+
+    fn remove_incoming(&mut self, pkid: u16) -> io::Result<()> {
+        self.db.remove(Self::incoming_key(pkid))?;
+        self.flush()
+    }
+
+    fn add_queued(&mut self, queued: &QueuedPublish) -> io::Result<()> {
+        let mut value = BytesMut::new();
+        queued.encode(&mut value)?;
+        self.db
+            .insert(Self::queued_key(queued.id), value.as_ref())?;
+        self.flush()
+    }
+
+    fn remove_queued(&mut self, id: u64) -> io::Result<()> {
+        self.db.remove(Self::queued_key(id))?;
+        self.flush()
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.db.clear()?;
+        self.outgoing.clear();
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqttbytes::QoS;
+
+    use super::*;
+
+    #[test]
+    fn state_survives_reopening() {
+        // Reopening the same path in one process races with sled's background flusher which holds the lock.
+        let db = sled::Config::new().temporary(true).open().unwrap();
+
+        let mut first = Publish::new("first", QoS::AtLeastOnce, "payload");
+        first.pkid = 7;
+        let mut second = Publish::new("second", QoS::ExactlyOnce, "payload");
+        second.pkid = 8;
+        let mut third = Publish::new("third", QoS::AtLeastOnce, "payload");
+        third.pkid = 9;
+
+        let mut store = SledStore::with_db(db.clone()).unwrap();
+        store.set_next_id(9).unwrap();
+        store.add_outgoing(&first).unwrap();
+        store.add_outgoing(&second).unwrap();
+        store.add_outgoing(&third).unwrap();
+        store.release_outgoing(8).unwrap();
+        store.remove_outgoing(9).unwrap();
+        store.add_incoming(3).unwrap();
+        store.add_incoming(4).unwrap();
+        store.remove_incoming(4).unwrap();
+        let queued = QueuedPublish {
+            id: 1,
+            publish: Publish::new("queued", QoS::AtLeastOnce, "payload"),
+            queued_at: std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(60),
+        };
+        store.add_queued(&queued).unwrap();
+        drop(store);
+
+        let state = SledStore::with_db(db).unwrap().load().unwrap();
+
+        assert_eq!(state.next_id, 9);
+        assert_eq!(
+            state.outgoing,
+            vec![Outgoing::Publish(first), Outgoing::Release(8)]
+        );
+        assert_eq!(state.incoming, vec![3]);
+        assert_eq!(state.queued, vec![queued]);
+    }
+}
@@ -2,9 +2,11 @@ use std::convert::Infallible;
 use std::fmt::Debug;
 
 use bytes::Bytes;
-use mqttbytes::v5::Publish;
+use mqttbytes::v5::{PubAckReason, Publish};
 use mqttbytes::QoS;
 use serde::Deserialize;
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
 
 use crate::client::ClientState;
 
@@ -109,3 +111,58 @@ where
         serde_json::from_slice(&publish.payload).map(Json)
     }
 }
+
+/// Cancelled when the client starts shutting down, so that long-running handlers can finish early.
+impl<S> Extractable<S> for CancellationToken {
+    type Rejection = Infallible;
+
+    fn extract(
+        _publish: &Publish,
+        _state: &S,
+        client_state: &ClientState,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(client_state.shutdown.clone())
+    }
+}
+
+/// Acknowledges the message manually.
+///
+/// Extracting `Ack` disables the automatic acknowledgement after the handler finishes. If it is dropped without calling [`Ack::ack`] or [`Ack::ack_with_reason`], no acknowledgement is sent and the broker redelivers the message after reconnecting.
+pub struct Ack {
+    sender: Option<oneshot::Sender<PubAckReason>>,
+}
+
+impl Ack {
+    pub fn ack(self) {
+        self.ack_with_reason(PubAckReason::Success);
+    }
+
+    /// Acknowledges the message with the given reason code, e.g. [`PubAckReason::ImplementationSpecificError`] when processing failed.
+    ///
+    /// For QoS 2 messages the reason is sent in PUBREC.
+    pub fn ack_with_reason(mut self, reason: PubAckReason) {
+        if let Some(sender) = self.sender.take() {
+            if sender.send(reason).is_err() {
+                tracing::debug!("Acknowledgement is no longer awaited.");
+            }
+        }
+    }
+}
+
+impl<S> Extractable<S> for Ack {
+    type Rejection = Infallible;
+
+    fn extract(
+        publish: &Publish,
+        _state: &S,
+        client_state: &ClientState,
+    ) -> Result<Self, Self::Rejection> {
+        // QoS 0 messages are never acknowledged, calling `Ack` on them has no effect.
+        let sender = match publish.qos {
+            QoS::AtMostOnce => None,
+            QoS::AtLeastOnce | QoS::ExactlyOnce => client_state.pending_acks.take(publish.pkid),
+        };
+
+        Ok(Ack { sender })
+    }
+}
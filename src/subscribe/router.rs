@@ -2,14 +2,19 @@ use std::{
     collections::HashMap,
     convert::Infallible,
     pin::Pin,
+    sync::Arc,
     task::{ready, Context, Poll},
 };
 
 use futures_core::{future::BoxFuture, Future};
 use mqttbytes::v5::Publish;
+use tokio::sync::{
+    oneshot::{self, error::TryRecvError},
+    Semaphore,
+};
 use tower::{util::BoxCloneService, Service};
 
-use crate::{ClientState, Handler};
+use crate::{ClientState, Error, Handler};
 
 use super::handler::{ErasedClientlessHandlerService, ErasedHandler};
 
@@ -18,6 +23,29 @@ enum RouteHandler<S> {
     WithState(Box<dyn ErasedClientlessHandlerService>),
 }
 
+impl<S> RouteHandler<S>
+where
+    S: Clone + Send + 'static,
+{
+    fn with_state<S2>(self, state: S) -> RouteHandler<S2> {
+        match self {
+            RouteHandler::WithoutState(handler) => {
+                RouteHandler::WithState(Box::new(handler.with_state(state)))
+            }
+            RouteHandler::WithState(service) => RouteHandler::WithState(service),
+        }
+    }
+}
+
+impl RouteHandler<()> {
+    fn into_service(self) -> Box<dyn ErasedClientlessHandlerService> {
+        match self {
+            RouteHandler::WithoutState(handler) => Box::new(handler.with_state(())),
+            RouteHandler::WithState(service) => service,
+        }
+    }
+}
+
 impl<S: Clone> Clone for RouteHandler<S> {
     fn clone(&self) -> Self {
         match self {
@@ -27,29 +55,102 @@ impl<S: Clone> Clone for RouteHandler<S> {
     }
 }
 
+/// Which messages must be handled one after another in the order they were received.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum MessageOrder {
+    /// Messages are handled concurrently and may finish in any order.
+    #[default]
+    Concurrent,
+    /// Messages on the same topic are handled one at a time.
+    PerTopic,
+    /// Messages with the same value of the named path parameter are handled one at a time.
+    ///
+    /// Routes without such parameter fall back to [`MessageOrder::PerTopic`].
+    PerParameter(String),
+}
+
+/// Options applied to a single route, see [`HandlerRouterBuilder::add_with_options`].
+#[derive(Debug, Clone, Default)]
+pub struct RouteOptions {
+    concurrency_limit: Option<usize>,
+    order: Option<MessageOrder>,
+}
+
+impl RouteOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits how many messages matching the route can be handled at the same time. Must not be zero.
+    ///
+    /// When the limit is reached, further messages matching the route wait until one of the handlers finishes.
+    pub fn concurrency_limit(mut self, limit: usize) -> Self {
+        self.concurrency_limit = Some(limit);
+        self
+    }
+
+    /// Overrides the order set by [`HandlerRouterBuilder::order`] for this route.
+    pub fn order(mut self, order: MessageOrder) -> Self {
+        self.order = Some(order);
+        self
+    }
+}
+
 pub struct HandlerRouterBuilder<S = ()> {
-    routes: HashMap<String, RouteHandler<S>>,
+    routes: HashMap<String, (RouteHandler<S>, RouteOptions)>,
+    fallback: Option<RouteHandler<S>>,
+    order: MessageOrder,
 }
 
 impl<S> HandlerRouterBuilder<S> {
     pub fn new() -> Self {
         Self {
             routes: HashMap::new(),
+            fallback: None,
+            order: MessageOrder::default(),
         }
     }
 
+    /// Sets the order in which messages are handled for all routes which do not set their own.
+    ///
+    /// A message is handled only after the previous message with the same ordering key was acknowledged.
+    pub fn order(&mut self, order: MessageOrder) {
+        self.order = order;
+    }
+
     pub fn add<const ASYNC: bool, M: Send + 'static>(
         &mut self,
         route: &str,
         handler: impl Handler<ASYNC, M, S> + 'static,
     ) where
         S: Clone + Send + 'static,
+    {
+        self.add_with_options(route, handler, RouteOptions::default());
+    }
+
+    pub fn add_with_options<const ASYNC: bool, M: Send + 'static>(
+        &mut self,
+        route: &str,
+        handler: impl Handler<ASYNC, M, S> + 'static,
+        options: RouteOptions,
+    ) where
+        S: Clone + Send + 'static,
     {
         let erased = handler.erased();
         let without_state = RouteHandler::WithoutState(erased.clone_boxed());
         // Add leading slash so that catch-all works. This slash will also be added when handling PUBLISH packets.
         let route = format!("/{route}");
-        self.routes.insert(route, without_state);
+        self.routes.insert(route, (without_state, options));
+    }
+
+    /// Handler called for messages which do not match any route, e.g. from topics subscribed through [`Subscriber`](crate::Subscriber).
+    pub fn fallback<const ASYNC: bool, M: Send + 'static>(
+        &mut self,
+        handler: impl Handler<ASYNC, M, S> + 'static,
+    ) where
+        S: Clone + Send + 'static,
+    {
+        self.fallback = Some(RouteHandler::WithoutState(handler.erased()));
     }
 
     pub fn with_state<S2>(self, state: S) -> HandlerRouterBuilder<S2>
@@ -57,19 +158,19 @@ impl<S> HandlerRouterBuilder<S> {
         S: Clone + Send + 'static,
         S2: Clone + Send + 'static,
     {
-        let mut routes = HashMap::<String, RouteHandler<S2>>::new();
+        let mut routes = HashMap::<String, (RouteHandler<S2>, RouteOptions)>::new();
 
-        for (key, route) in self.routes {
-            let route: RouteHandler<S2> = match route {
-                RouteHandler::WithoutState(handler) => {
-                    RouteHandler::WithState(Box::new(handler.with_state(state.clone())))
-                }
-                RouteHandler::WithState(service) => RouteHandler::WithState(service),
-            };
-            routes.insert(key, route);
+        for (key, (route, options)) in self.routes {
+            routes.insert(key, (route.with_state(state.clone()), options));
         }
 
-        HandlerRouterBuilder::<S2> { routes }
+        let fallback = self.fallback.map(|fallback| fallback.with_state(state));
+
+        HandlerRouterBuilder::<S2> {
+            routes,
+            fallback,
+            order: self.order,
+        }
     }
 }
 
@@ -77,33 +178,67 @@ impl HandlerRouterBuilder<()> {
     pub fn build(self) -> HandlerRouter {
         let mut new_routes = HashMap::new();
 
-        for (key, route) in self.routes {
-            let route = match route {
-                RouteHandler::WithoutState(handler) => Box::new(handler.with_state(())),
-                RouteHandler::WithState(service) => service,
-            };
-            new_routes.insert(key, route);
+        for (key, (route, options)) in self.routes {
+            new_routes.insert(key, (route.into_service(), options));
         }
 
-        HandlerRouter { routes: new_routes }
+        HandlerRouter {
+            routes: new_routes,
+            fallback: self.fallback.map(RouteHandler::into_service),
+            order: self.order,
+        }
     }
 }
 
 pub struct HandlerRouter {
-    routes: HashMap<String, Box<dyn ErasedClientlessHandlerService>>,
+    routes: HashMap<String, (Box<dyn ErasedClientlessHandlerService>, RouteOptions)>,
+    fallback: Option<Box<dyn ErasedClientlessHandlerService>>,
+    order: MessageOrder,
 }
 
 impl HandlerRouter {
     pub(crate) fn build(self, client_state: ClientState) -> HandlerRouterWithClientState {
         let mut router = matchit::Router::new();
+        let mut routes = HashMap::new();
 
-        for (key, route) in self.routes {
-            router
-                .insert(key, route.get_service(client_state.clone()))
-                .unwrap();
+        for (key, (route, options)) in self.routes {
+            let route = Route {
+                pattern: key.clone(),
+                service: route.get_service(client_state.clone()),
+                limit: options
+                    .concurrency_limit
+                    .map(|limit| Arc::new(Semaphore::new(limit))),
+                order: options.order.unwrap_or_else(|| self.order.clone()),
+            };
+            router.insert(key.clone(), route.clone()).unwrap();
+            routes.insert(key, route);
+        }
+
+        let fallback = self
+            .fallback
+            .map(|fallback| fallback.get_service(client_state.clone()));
+
+        HandlerRouterWithClientState {
+            inner: router,
+            routes,
+            fallback,
+            order: self.order,
+            queues: HashMap::new(),
+            prune_at: MIN_PRUNED_QUEUES,
+            client_state,
         }
+    }
 
-        HandlerRouterWithClientState { inner: router }
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        for (route, (_, options)) in &self.routes {
+            if options.concurrency_limit == Some(0) {
+                return Err(Error::InvalidConfig(format!(
+                    "concurrency limit of route `{}` must not be zero",
+                    &route[1..]
+                )));
+            }
+        }
+        Ok(())
     }
 
     pub(crate) fn get_routes(&self) -> Vec<String> {
@@ -115,16 +250,160 @@ impl HandlerRouter {
     }
 }
 
+#[derive(Clone)]
+struct Route {
+    pattern: String,
+    service: BoxCloneService<Publish, (), Infallible>,
+    limit: Option<Arc<Semaphore>>,
+    order: MessageOrder,
+}
+
+/// What has to happen before a received message can be handled, see [`HandlerRouterWithClientState::admit`].
+pub(crate) struct Admission {
+    pub(crate) limit: Option<Arc<Semaphore>>,
+    pub(crate) turn: Option<Turn>,
+}
+
+/// Place of a message in the queue of messages with the same ordering key.
+pub(crate) struct Turn {
+    previous: Option<oneshot::Receiver<()>>,
+    // Dropped after the message is handled, which lets the next message in the queue proceed.
+    _done: oneshot::Sender<()>,
+}
+
+impl Turn {
+    pub(crate) async fn wait(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            // The sender is only ever dropped.
+            let _ = previous.await;
+        }
+    }
+}
+
+const MIN_PRUNED_QUEUES: usize = 64;
+
 pub(crate) struct HandlerRouterWithClientState {
-    inner: matchit::Router<BoxCloneService<Publish, (), Infallible>>,
+    inner: matchit::Router<Route>,
+    // Kept alongside `inner` because `matchit` does not support removing routes.
+    routes: HashMap<String, Route>,
+    fallback: Option<BoxCloneService<Publish, (), Infallible>>,
+    order: MessageOrder,
+    // Last message in each queue keyed by route and ordering key.
+    queues: HashMap<(String, String), oneshot::Receiver<()>>,
+    // Number of queues at which the finished ones are forgotten, doubles with the queues still running.
+    prune_at: usize,
+    client_state: ClientState,
 }
 
 impl HandlerRouterWithClientState {
+    /// Adds a route handled in the given order, or in the order set by [`HandlerRouterBuilder::order`] if none is given.
+    pub(crate) fn add<const ASYNC: bool, M: Send + 'static>(
+        &mut self,
+        route: &str,
+        handler: impl Handler<ASYNC, M, ()> + 'static,
+        order: Option<MessageOrder>,
+    ) -> Result<(), Error> {
+        let service = handler
+            .erased()
+            .with_state(())
+            .get_service(self.client_state.clone());
+        let key = format!("/{route}");
+        let service = Route {
+            pattern: key.clone(),
+            service,
+            limit: None,
+            order: order.unwrap_or_else(|| self.order.clone()),
+        };
+
+        self.inner
+            .insert(key.clone(), service.clone())
+            .map_err(|source| Error::InvalidRoute {
+                route: route.to_owned(),
+                source,
+            })?;
+        self.routes.insert(key, service);
+
+        Ok(())
+    }
+
+    pub(crate) fn remove(&mut self, route: &str) -> bool {
+        if self.routes.remove(&format!("/{route}")).is_none() {
+            return false;
+        }
+
+        let mut router = matchit::Router::new();
+        for (key, service) in &self.routes {
+            router
+                .insert(key.clone(), service.clone())
+                .expect("Routes were validated when they were added.");
+        }
+        self.inner = router;
+
+        true
+    }
+
+    /// Called for each message in the order they are received, before any of them is handled.
+    pub(crate) fn admit(&mut self, topic: &str) -> Admission {
+        let path = format!("/{topic}");
+        let (route, limit, key) = match self.inner.at(&path) {
+            Ok(router_match) => {
+                let route = router_match.value;
+                let key = match &route.order {
+                    MessageOrder::Concurrent => None,
+                    MessageOrder::PerTopic => Some(topic.to_owned()),
+                    MessageOrder::PerParameter(name) => {
+                        Some(router_match.params.get(name).unwrap_or(topic).to_owned())
+                    }
+                };
+                (Some(route.pattern.clone()), route.limit.clone(), key)
+            }
+            Err(_) if self.fallback.is_some() && self.order != MessageOrder::Concurrent => {
+                (Some(String::new()), None, Some(topic.to_owned()))
+            }
+            Err(_) => (None, None, None),
+        };
+
+        let (Some(route), Some(key)) = (route, key) else {
+            return Admission { limit, turn: None };
+        };
+
+        // Forget queues whose last message was already handled, only once there are enough of them so that the scan is amortized.
+        if self.queues.len() >= self.prune_at {
+            self.queues
+                .retain(|_, previous| matches!(previous.try_recv(), Err(TryRecvError::Empty)));
+            self.prune_at = (self.queues.len() * 2).max(MIN_PRUNED_QUEUES);
+        }
+
+        let (done, next) = oneshot::channel();
+        let previous = self.queues.insert((route, key), next);
+
+        Admission {
+            limit,
+            turn: Some(Turn {
+                previous,
+                _done: done,
+            }),
+        }
+    }
+
+    /// Route whose handler runs for messages on the topic, an empty string for the fallback.
+    #[cfg(feature = "testing")]
+    pub(crate) fn route_for(&self, topic: &str) -> Option<String> {
+        match self.inner.at(&format!("/{topic}")) {
+            Ok(router_match) => Some(router_match.value.pattern[1..].to_owned()),
+            Err(_) if self.fallback.is_some() => Some(String::new()),
+            Err(_) => None,
+        }
+    }
+
     pub(crate) fn handle(&mut self, publish: Publish) -> Option<HandlerFuture> {
         let route = format!("/{}", publish.topic);
         if let Ok(router_match) = self.inner.at_mut(&route) {
-            let service = router_match.value;
-            Some(HandlerFuture::new(service.clone(), publish))
+            let service = router_match.value.service.clone();
+            Some(HandlerFuture::new(service, publish))
+        } else if let Some(fallback) = &self.fallback {
+            tracing::debug!(topic = %publish.topic, "No matching route found, using fallback.");
+            Some(HandlerFuture::new(fallback.clone(), publish))
         } else {
             tracing::debug!(topic = %publish.topic, "No matching route found.");
             None
@@ -132,6 +411,60 @@ impl HandlerRouterWithClientState {
     }
 }
 
+// Converts a route such as `devices/:id/*rest` into an MQTT topic filter such as `devices/+/#`.
+pub(crate) fn route_to_filter(route: &str) -> String {
+    route
+        .split('/')
+        .map(|level| match level.chars().next() {
+            Some(':') => "+",
+            Some('*') => "#",
+            _ => level,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Converts an MQTT topic filter such as `devices/+/#` into a route such as `devices/:1/*rest`.
+//
+// Filters containing `:` or `*` are rejected because the route would treat them as parameters.
+pub(crate) fn filter_to_route(filter: &str) -> Result<String, Error> {
+    if filter.contains([':', '*']) {
+        return Err(Error::UnroutableFilter(filter.to_owned()));
+    }
+
+    let route = filter
+        .split('/')
+        .enumerate()
+        .map(|(index, level)| match level {
+            "+" => format!(":{index}"),
+            "#" => "*rest".to_owned(),
+            level => level.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    Ok(route)
+}
+
+// Whether the topic matches the MQTT topic filter. Wildcards at the first level do not match topics starting with `$`.
+#[cfg(any(feature = "broker", feature = "testing"))]
+pub(crate) fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter = filter.split('/');
+    let mut topic = topic.split('/');
+    loop {
+        match (filter.next(), topic.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(level), Some(topic_level)) if level == topic_level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
 impl<S> Default for HandlerRouterBuilder<S> {
     fn default() -> Self {
         Self::new()
@@ -178,3 +511,84 @@ impl Future for HandlerFuture {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_to_filter_replaces_wildcards() {
+        assert_eq!(route_to_filter("foo/bar"), "foo/bar");
+        assert_eq!(route_to_filter("foo/:bar"), "foo/+");
+        assert_eq!(route_to_filter(":foo/bar/:baz"), "+/bar/+");
+        assert_eq!(route_to_filter("foo/*rest"), "foo/#");
+    }
+
+    #[test]
+    #[cfg(any(feature = "broker", feature = "testing"))]
+    fn topic_matches_wildcards() {
+        assert!(topic_matches("a/+/c", "a/b/c"));
+        assert!(topic_matches("a/#", "a"));
+        assert!(topic_matches("#", "a/b"));
+        assert!(!topic_matches("a/+", "a/b/c"));
+        assert!(!topic_matches("a/b", "a/c"));
+        assert!(!topic_matches("#", "$SYS/uptime"));
+        assert!(topic_matches("$SYS/#", "$SYS/uptime"));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "testing")]
+    async fn fallback_handles_only_unmatched_messages() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        };
+
+        use mqttbytes::QoS;
+
+        use crate::testing::RouterHarness;
+
+        let matched = Arc::new(AtomicUsize::new(0));
+        let unmatched = Arc::new(Mutex::new(Vec::new()));
+        let mut router = HandlerRouterBuilder::new();
+        router.add("sensors/:id", {
+            let matched = matched.clone();
+            move || {
+                matched.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        router.fallback({
+            let unmatched = unmatched.clone();
+            move |publish: Publish| {
+                unmatched.lock().unwrap().push(publish.topic);
+            }
+        });
+        let mut harness = RouterHarness::new(router.build()).await.unwrap();
+
+        harness
+            .dispatch(Publish::new("sensors/1", QoS::AtMostOnce, ""))
+            .await;
+        harness
+            .dispatch(Publish::new("other/1", QoS::AtMostOnce, ""))
+            .await;
+        assert_eq!(matched.load(Ordering::SeqCst), 1);
+        assert_eq!(*unmatched.lock().unwrap(), ["other/1"]);
+    }
+
+    #[test]
+    fn filter_to_route_round_trips() {
+        for filter in ["foo/bar", "foo/+", "+/bar/+", "foo/#", "#"] {
+            assert_eq!(route_to_filter(&filter_to_route(filter).unwrap()), filter);
+        }
+    }
+
+    #[test]
+    fn filters_with_route_syntax_are_rejected() {
+        for filter in ["foo/:bar", "foo/*", "foo/a*b/+"] {
+            assert!(matches!(
+                filter_to_route(filter),
+                Err(Error::UnroutableFilter(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,557 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use mqttbytes::{
+    v5::{
+        ConnAck, ConnAckProperties, Connect, ConnectReturnCode, Disconnect, DisconnectReasonCode,
+        Packet, PubAck, PubComp, PubRec, PubRel, Publish, SubAck, Subscribe, SubscribeReasonCode,
+        UnsubAck, UnsubAckReason, Unsubscribe,
+    },
+    Protocol, QoS,
+};
+use tokio::{
+    net::ToSocketAddrs,
+    sync::{mpsc, Mutex as AsyncMutex},
+};
+
+use crate::{
+    client::reconnect::{Connector, Endpoints},
+    connection::{BoxedTransport, Connection, Incoming, Reader, Writer},
+    subscribe::router::topic_matches,
+    Client, ClientBuilder, Error, HandlerRouter,
+};
+
+// Size of the in-memory buffer in each direction of a connection.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+type BrokerConnection = Arc<Connection<Reader, Writer>>;
+
+/// MQTT broker running in the same process, clients connect to it through in-memory streams.
+///
+/// Messages are routed between the clients with QoS 0, 1 and 2. Tests can inject messages, inspect messages published by the clients and simulate faults.
+///
+/// Sessions of clients connecting with `clean_session` set to `false` are kept after they disconnect, but messages are not queued for them while they are offline.
+#[derive(Clone)]
+pub struct MockBroker {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    published: AsyncMutex<mpsc::UnboundedReceiver<Publish>>,
+    published_sender: mpsc::UnboundedSender<Publish>,
+}
+
+#[derive(Default)]
+struct State {
+    sessions: HashMap<String, Session>,
+    next_connection: u64,
+    published: Vec<Publish>,
+    drop_acks: bool,
+    delay: Duration,
+}
+
+struct Session {
+    // Identifies the connection so that a closed connection does not end a session taken over by a newer one.
+    connection: Option<(u64, BrokerConnection)>,
+    clean_session: bool,
+    subscriptions: Vec<(String, QoS)>,
+    // Received QoS 2 messages which were not released yet.
+    pending_rel: HashSet<u16>,
+    next_pkid: u16,
+}
+
+impl Session {
+    fn next_pkid(&mut self) -> u16 {
+        self.next_pkid = self.next_pkid.checked_add(1).unwrap_or(1);
+        self.next_pkid
+    }
+}
+
+impl Default for MockBroker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockBroker {
+    pub fn new() -> Self {
+        let (published_sender, published) = mpsc::unbounded_channel();
+        Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State::default()),
+                published: AsyncMutex::new(published),
+                published_sender,
+            }),
+        }
+    }
+
+    /// Builds a client connected to this broker like [`ClientBuilder::build`]. The builder's address is not used, reconnects connect to this broker again.
+    pub async fn connect<Address: ToSocketAddrs>(
+        &self,
+        builder: ClientBuilder<Address>,
+        publish_router: HandlerRouter,
+    ) -> Result<Client, Error> {
+        let endpoints = Endpoints::with_connector(self.connector(builder.protocol()));
+        builder.build_with(publish_router, endpoints).await
+    }
+
+    fn connector(&self, protocol: Protocol) -> Connector {
+        let inner = self.inner.clone();
+        Arc::new(move || {
+            let inner = inner.clone();
+            Box::pin(async move {
+                let (client, server) = tokio::io::duplex(BUFFER_SIZE);
+                let connection = Connection::with_stream(Box::new(server), u32::MAX, protocol);
+                tokio::spawn(inner.serve(Arc::new(connection)));
+                Ok(Box::new(client) as BoxedTransport)
+            })
+        })
+    }
+
+    /// Sends a message to all connected clients subscribed to a matching topic filter and returns their number.
+    pub async fn publish(&self, topic: &str, qos: QoS, payload: impl Into<Bytes>) -> usize {
+        let publish = Publish::from_bytes(topic, qos, payload.into());
+        let outgoing = self.inner.route(&mut self.inner.state(), &publish);
+        let count = outgoing.len();
+        self.inner.send_all(outgoing).await;
+        count
+    }
+
+    /// All messages published by clients so far, including retransmissions.
+    pub fn published(&self) -> Vec<Publish> {
+        self.inner.state().published.clone()
+    }
+
+    /// Waits for the next message published by a client.
+    pub async fn next_published(&self) -> Publish {
+        self.inner
+            .published
+            .lock()
+            .await
+            .recv()
+            .await
+            .expect("Sender is owned by the broker.")
+    }
+
+    /// Client identifiers of the connected clients.
+    pub fn clients(&self) -> Vec<String> {
+        self.inner
+            .state()
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.connection.is_some())
+            .map(|(client_id, _)| client_id.clone())
+            .collect()
+    }
+
+    /// Topic filters the client is subscribed to.
+    pub fn subscriptions(&self, client_id: &str) -> Vec<String> {
+        self.inner
+            .state()
+            .sessions
+            .get(client_id)
+            .map(|session| {
+                session
+                    .subscriptions
+                    .iter()
+                    .map(|(filter, _)| filter.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Stops sending PUBACK, PUBREC and PUBCOMP for messages published by clients.
+    pub fn set_drop_acks(&self, drop_acks: bool) {
+        self.inner.state().drop_acks = drop_acks;
+    }
+
+    /// Waits before sending every packet.
+    pub fn set_delay(&self, delay: Duration) {
+        self.inner.state().delay = delay;
+    }
+
+    /// Closes all connections without sending DISCONNECT, like a crashed broker.
+    pub async fn disconnect_all(&self) {
+        for connection in self.inner.take_connections() {
+            let _ = connection.shutdown().await;
+        }
+    }
+
+    /// Sends DISCONNECT with the reason code to all clients and closes their connections.
+    pub async fn disconnect_all_with(&self, reason_code: DisconnectReasonCode) {
+        for connection in self.inner.take_connections() {
+            let mut disconnect = Disconnect::new();
+            disconnect.reason_code = reason_code;
+            if let Ok(send) = connection.send(&Packet::Disconnect(disconnect)) {
+                send.await;
+            }
+            let _ = connection.shutdown().await;
+        }
+    }
+}
+
+impl Inner {
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("Broker state is never poisoned.")
+    }
+
+    async fn serve(self: Arc<Self>, connection: BrokerConnection) {
+        let id = {
+            let mut state = self.state();
+            state.next_connection += 1;
+            state.next_connection
+        };
+        let mut client_id = None;
+
+        loop {
+            let packet = match connection.recv().await {
+                Ok(Some(Incoming::Packet(packet))) => packet,
+                Ok(Some(Incoming::Auth(_))) => {
+                    tracing::warn!("Mock broker does not support enhanced authentication.");
+                    continue;
+                }
+                Ok(None) => break,
+                Err(error) => {
+                    tracing::warn!(?error, "Mock broker was unable to read packet.");
+                    break;
+                }
+            };
+
+            if let Packet::Connect(connect) = &packet {
+                client_id = Some(connect.client_id.clone());
+            }
+            let Some(client_id) = &client_id else {
+                tracing::warn!(?packet, "Mock broker expected CONNECT.");
+                break;
+            };
+            let disconnected = matches!(packet, Packet::Disconnect(_));
+            let outgoing = self.handle(id, &connection, client_id, packet);
+            self.send_all(outgoing).await;
+            if disconnected {
+                break;
+            }
+        }
+
+        if let Some(client_id) = client_id {
+            self.closed(id, &client_id);
+        }
+    }
+
+    fn handle(
+        &self,
+        id: u64,
+        connection: &BrokerConnection,
+        client_id: &str,
+        packet: Packet,
+    ) -> Vec<(BrokerConnection, Packet)> {
+        let mut state = self.state();
+        if let Packet::Connect(connect) = packet {
+            let connack = Self::connected(&mut state, id, connection, connect);
+            return vec![(connection.clone(), connack)];
+        }
+
+        let drop_acks = state.drop_acks;
+        let Some(session) = state.sessions.get_mut(client_id) else {
+            return Vec::new();
+        };
+        let mut outgoing = Vec::new();
+        let mut reply = |packet| outgoing.push((connection.clone(), packet));
+
+        match packet {
+            Packet::Publish(publish) => {
+                // A retransmitted QoS 2 message which was already received is only acknowledged again.
+                let duplicate =
+                    publish.qos == QoS::ExactlyOnce && session.pending_rel.contains(&publish.pkid);
+                if publish.qos == QoS::ExactlyOnce {
+                    session.pending_rel.insert(publish.pkid);
+                }
+                if !drop_acks {
+                    match publish.qos {
+                        QoS::AtMostOnce => {}
+                        QoS::AtLeastOnce => reply(Packet::PubAck(PubAck::new(publish.pkid))),
+                        QoS::ExactlyOnce => reply(Packet::PubRec(PubRec::new(publish.pkid))),
+                    }
+                }
+                if !duplicate {
+                    let _ = self.published_sender.send(publish.clone());
+                    state.published.push(publish.clone());
+                    outgoing.extend(self.route(&mut state, &publish));
+                }
+            }
+            Packet::PubRel(pubrel) => {
+                session.pending_rel.remove(&pubrel.pkid);
+                if !drop_acks {
+                    reply(Packet::PubComp(PubComp::new(pubrel.pkid)));
+                }
+            }
+            Packet::PubRec(pubrec) => reply(Packet::PubRel(PubRel::new(pubrec.pkid))),
+            Packet::PubAck(_) | Packet::PubComp(_) => {}
+            Packet::Subscribe(subscribe) => {
+                let suback = Self::subscribe(session, subscribe);
+                reply(Packet::SubAck(suback));
+            }
+            Packet::Unsubscribe(unsubscribe) => {
+                let unsuback = Self::unsubscribe(session, unsubscribe);
+                reply(Packet::UnsubAck(unsuback));
+            }
+            Packet::PingReq => reply(Packet::PingResp),
+            Packet::Disconnect(_) => {}
+            packet => tracing::warn!(?packet, "Mock broker received unexpected packet."),
+        }
+        outgoing
+    }
+
+    fn connected(
+        state: &mut State,
+        id: u64,
+        connection: &BrokerConnection,
+        connect: Connect,
+    ) -> Packet {
+        let resumed = match state.sessions.remove(&connect.client_id) {
+            Some(mut session) if !connect.clean_session => {
+                // The newer connection takes over the session.
+                if let Some((_, previous)) = session.connection.take() {
+                    tokio::spawn(async move { previous.shutdown().await });
+                }
+                Some(session)
+            }
+            _ => None,
+        };
+        let session_present = resumed.is_some();
+        let mut session = resumed.unwrap_or(Session {
+            connection: None,
+            clean_session: connect.clean_session,
+            subscriptions: Vec::new(),
+            pending_rel: HashSet::new(),
+            next_pkid: 0,
+        });
+        session.connection = Some((id, connection.clone()));
+        session.clean_session = connect.clean_session;
+        state.sessions.insert(connect.client_id, session);
+
+        let mut connack = ConnAck::new(ConnectReturnCode::Success, session_present);
+        // `mqttbytes` cannot read a CONNACK without properties.
+        if connection.protocol() == Protocol::V5 {
+            connack.properties = Some(ConnAckProperties::new());
+        }
+        Packet::ConnAck(connack)
+    }
+
+    fn subscribe(session: &mut Session, subscribe: Subscribe) -> SubAck {
+        let return_codes = subscribe
+            .filters
+            .into_iter()
+            .map(|filter| {
+                session
+                    .subscriptions
+                    .retain(|(path, _)| *path != filter.path);
+                session.subscriptions.push((filter.path, filter.qos));
+                match filter.qos {
+                    QoS::AtMostOnce => SubscribeReasonCode::QoS0,
+                    QoS::AtLeastOnce => SubscribeReasonCode::QoS1,
+                    QoS::ExactlyOnce => SubscribeReasonCode::QoS2,
+                }
+            })
+            .collect();
+        SubAck::new(subscribe.pkid, return_codes)
+    }
+
+    fn unsubscribe(session: &mut Session, unsubscribe: Unsubscribe) -> UnsubAck {
+        let mut unsuback = UnsubAck::new(unsubscribe.pkid);
+        for filter in unsubscribe.filters {
+            let before = session.subscriptions.len();
+            session.subscriptions.retain(|(path, _)| *path != filter);
+            unsuback
+                .reasons
+                .push(if session.subscriptions.len() < before {
+                    UnsubAckReason::Success
+                } else {
+                    UnsubAckReason::NoSubscriptionExisted
+                });
+        }
+        unsuback
+    }
+
+    // Copies of the message for every connected client with a matching subscription.
+    fn route(&self, state: &mut State, publish: &Publish) -> Vec<(BrokerConnection, Packet)> {
+        let mut outgoing = Vec::new();
+        for session in state.sessions.values_mut() {
+            let Some((_, connection)) = session.connection.clone() else {
+                continue;
+            };
+            let granted = session
+                .subscriptions
+                .iter()
+                .filter(|(filter, _)| topic_matches(filter, &publish.topic))
+                .map(|(_, qos)| *qos)
+                .reduce(|a, b| if a > b { a } else { b });
+            let Some(granted) = granted else {
+                continue;
+            };
+
+            let mut delivered = Publish::from_bytes(
+                publish.topic.clone(),
+                if publish.qos > granted {
+                    granted
+                } else {
+                    publish.qos
+                },
+                publish.payload.clone(),
+            );
+            if delivered.qos != QoS::AtMostOnce {
+                delivered.pkid = session.next_pkid();
+            }
+            delivered.properties = publish.properties.clone().map(|mut properties| {
+                properties.topic_alias = None;
+                properties
+            });
+            outgoing.push((connection, Packet::Publish(delivered)));
+        }
+        outgoing
+    }
+
+    async fn send_all(&self, outgoing: Vec<(BrokerConnection, Packet)>) {
+        let delay = self.state().delay;
+        for (connection, packet) in outgoing {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            match connection.send(&packet) {
+                Ok(send) => send.await,
+                Err(error) => tracing::warn!(%error, "Mock broker was unable to send packet."),
+            }
+        }
+    }
+
+    fn take_connections(&self) -> Vec<BrokerConnection> {
+        let mut state = self.state();
+        let connections = state
+            .sessions
+            .values_mut()
+            .filter_map(|session| session.connection.take())
+            .map(|(_, connection)| connection)
+            .collect();
+        state.sessions.retain(|_, session| !session.clean_session);
+        connections
+    }
+
+    fn closed(&self, id: u64, client_id: &str) {
+        let mut state = self.state();
+        let Some(session) = state.sessions.get_mut(client_id) else {
+            return;
+        };
+        if !matches!(session.connection, Some((current, _)) if current == id) {
+            return;
+        }
+        if session.clean_session {
+            state.sessions.remove(client_id);
+        } else {
+            session.connection = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::{Error, HandlerRouterBuilder, ReconnectPolicy};
+
+    #[tokio::test]
+    async fn routes_messages_between_clients() {
+        let broker = MockBroker::new();
+
+        let (sender, mut received) = mpsc::unbounded_channel();
+        let mut router = HandlerRouterBuilder::new();
+        router.add("sensors/:id", move |publish: Publish| {
+            let _ = sender.send(publish);
+            async {}
+        });
+        let mut builder = ClientBuilder::new("mock");
+        builder.set_client_id("subscriber");
+        let subscriber = broker.connect(builder, router.build()).await.unwrap();
+        assert_eq!(broker.subscriptions("subscriber"), ["sensors/+"]);
+
+        let mut builder = ClientBuilder::new("mock");
+        builder.set_client_id("publisher");
+        let publisher = broker
+            .connect(builder, HandlerRouterBuilder::new().build())
+            .await
+            .unwrap();
+        publisher
+            .publish("sensors/1", QoS::ExactlyOnce, b"21.5")
+            .await
+            .unwrap();
+        assert_eq!(broker.next_published().await.payload, "21.5");
+        assert_eq!(received.recv().await.unwrap().topic, "sensors/1");
+
+        assert_eq!(broker.publish("sensors/2", QoS::AtLeastOnce, "22").await, 1);
+        assert_eq!(broker.publish("other", QoS::AtLeastOnce, "").await, 0);
+        let injected = received.recv().await.unwrap();
+        assert_eq!(
+            (injected.topic.as_str(), injected.qos),
+            ("sensors/2", QoS::AtLeastOnce)
+        );
+
+        let mut clients = broker.clients();
+        clients.sort();
+        assert_eq!(clients, ["publisher", "subscriber"]);
+        subscriber.shutdown().await;
+        publisher.shutdown().await;
+        assert!(broker.clients().is_empty());
+    }
+
+    #[tokio::test]
+    async fn simulates_dropped_acks_delays_and_disconnects() {
+        let broker = MockBroker::new();
+        broker.set_drop_acks(true);
+
+        let mut builder = ClientBuilder::new("mock");
+        builder
+            .set_client_id("client")
+            .set_clean_session(false)
+            .set_reconnect_policy(ReconnectPolicy::Backoff {
+                initial_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(10),
+            });
+        let client = broker
+            .connect(builder, HandlerRouterBuilder::new().build())
+            .await
+            .unwrap();
+
+        let publish = tokio::spawn({
+            let client = client.clone();
+            async move { client.publish("topic", QoS::AtLeastOnce, b"payload").await }
+        });
+        assert!(!broker.next_published().await.dup);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!publish.is_finished());
+
+        // The message is retransmitted and acknowledged once the client reconnects and resumes its session.
+        broker.set_drop_acks(false);
+        broker.disconnect_all().await;
+        assert!(matches!(
+            publish.await.unwrap(),
+            Err(Error::DeliveryUnknown(_))
+        ));
+        assert!(broker.next_published().await.dup);
+
+        broker.set_delay(Duration::from_millis(50));
+        let start = Instant::now();
+        client
+            .publish("topic", QoS::AtLeastOnce, b"payload")
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(client
+            .shutdown_graceful(Duration::from_secs(1))
+            .await
+            .is_clean());
+    }
+}
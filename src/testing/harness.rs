@@ -0,0 +1,208 @@
+use mqttbytes::{
+    v5::{Packet, Publish},
+    QoS,
+};
+
+use crate::{Client, ClientBuilder, Error, HandlerRouter};
+
+use super::MockBroker;
+
+/// Runs messages through the routes, extractors and handlers of a [`HandlerRouter`] without a real broker.
+///
+/// Handlers get a [`Publisher`](crate::Publisher) and [`Subscriber`](crate::Subscriber) connected to a [`MockBroker`] which records what they publish and subscribe to.
+pub struct RouterHarness {
+    broker: MockBroker,
+    client: Client,
+    next_pkid: u16,
+}
+
+/// Which handler ran for a dispatched message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandledBy {
+    Route(String),
+    Fallback,
+    /// No route matched and there is no fallback.
+    Nobody,
+}
+
+/// Outcome of [`RouterHarness::dispatch`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dispatch {
+    pub handled_by: HandledBy,
+    /// PUBACK or PUBREC sent for QoS 1 and 2 messages, `None` for QoS 0 or if the handler dropped its [`Ack`](crate::Ack).
+    pub acknowledgement: Option<Packet>,
+}
+
+impl RouterHarness {
+    /// Fails like [`ClientBuilder::build`] if the router or client options are invalid.
+    pub async fn new(router: HandlerRouter) -> Result<Self, Error> {
+        let broker = MockBroker::new();
+        let mut builder = ClientBuilder::new("harness");
+        builder.set_client_id("harness");
+        let client = broker.connect(builder, router).await?;
+        Ok(Self {
+            broker,
+            client,
+            next_pkid: 0,
+        })
+    }
+
+    /// Handles the message as if it was received from the broker and waits until its handler finishes.
+    ///
+    /// QoS 1 and 2 messages without a packet identifier get one assigned.
+    ///
+    /// Messages go through the same [`MessageOrder`](crate::MessageOrder) and route concurrency limits as in the client, but since they are dispatched one at a time they never wait for each other. The client's handler concurrency limit does not apply.
+    pub async fn dispatch(&mut self, mut publish: Publish) -> Dispatch {
+        if publish.qos != QoS::AtMostOnce && publish.pkid == 0 {
+            self.next_pkid = self.next_pkid.checked_add(1).unwrap_or(1);
+            publish.pkid = self.next_pkid;
+        }
+
+        let router = self.client.router();
+        let mut received_publish = router.received_publish.lock().await;
+        let handled_by = match received_publish.route_for(&publish.topic) {
+            Some(route) if route.is_empty() => HandledBy::Fallback,
+            Some(route) => HandledBy::Route(route),
+            None => HandledBy::Nobody,
+        };
+        // Admitted like in the client, although the message cannot wait for another one as each dispatch waits for its handler.
+        let admission = received_publish.admit(&publish.topic);
+        drop(received_publish);
+        let mut turn = admission.turn;
+        if let Some(turn) = &mut turn {
+            turn.wait().await;
+        }
+        let permit = match admission.limit {
+            Some(limit) => Some(
+                limit
+                    .acquire_owned()
+                    .await
+                    .expect("Semaphore is never closed."),
+            ),
+            None => None,
+        };
+
+        let handled = router.received_publish.lock().await.publish(publish);
+        let responses = handled.await;
+        drop((permit, turn));
+        let acknowledgement = responses
+            .iter()
+            .find(|response| matches!(response, Packet::PubAck(_) | Packet::PubRec(_)))
+            .cloned();
+        // Sent like any other response so that the QoS 2 exchange completes.
+        for response in responses {
+            router.route_sent(response).await;
+        }
+
+        Dispatch {
+            handled_by,
+            acknowledgement,
+        }
+    }
+
+    /// Messages published by the handlers so far.
+    pub fn published(&self) -> Vec<Publish> {
+        self.broker.published()
+    }
+
+    /// Topic filters of the routes and those the handlers subscribed to.
+    pub fn subscriptions(&self) -> Vec<String> {
+        self.broker.subscriptions("harness")
+    }
+
+    /// Broker the handlers are connected to, e.g. to simulate faults.
+    pub fn broker(&self) -> &MockBroker {
+        &self.broker
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqttbytes::v5::{PubAck, PubAckReason};
+
+    use super::*;
+    use crate::{Ack, HandlerRouterBuilder, MessageOrder, Publisher, RouteOptions};
+
+    #[tokio::test]
+    async fn dispatches_through_routes_and_records_publishes() {
+        let mut router = HandlerRouterBuilder::new();
+        router.add(
+            "requests/:id",
+            |publish: Publish, publisher: Publisher| async move {
+                let topic = publish.topic.replace("requests", "responses");
+                publisher
+                    .publish(&topic, QoS::AtLeastOnce, &publish.payload)
+                    .await
+                    .unwrap();
+            },
+        );
+        router.add("rejected", |ack: Ack| async move {
+            ack.ack_with_reason(PubAckReason::NotAuthorized);
+        });
+        let mut harness = RouterHarness::new(router.build()).await.unwrap();
+        assert_eq!(harness.subscriptions().len(), 2);
+
+        let dispatch = harness
+            .dispatch(Publish::new("requests/1", QoS::AtLeastOnce, "ping"))
+            .await;
+        assert_eq!(
+            dispatch.handled_by,
+            HandledBy::Route("requests/:id".to_owned())
+        );
+        assert_eq!(
+            dispatch.acknowledgement,
+            Some(Packet::PubAck(PubAck::new(1)))
+        );
+        let [published] = &harness.published()[..] else {
+            panic!("expected one message");
+        };
+        assert_eq!(
+            (published.topic.as_str(), &published.payload[..]),
+            ("responses/1", &b"ping"[..])
+        );
+
+        let dispatch = harness
+            .dispatch(Publish::new("requests/2", QoS::ExactlyOnce, "ping"))
+            .await;
+        assert!(matches!(dispatch.acknowledgement, Some(Packet::PubRec(_))));
+        assert_eq!(harness.published().len(), 2);
+
+        let dispatch = harness
+            .dispatch(Publish::new("rejected", QoS::AtLeastOnce, ""))
+            .await;
+        let Some(Packet::PubAck(puback)) = dispatch.acknowledgement else {
+            panic!("expected PUBACK");
+        };
+        assert_eq!(puback.reason, PubAckReason::NotAuthorized);
+
+        let dispatch = harness
+            .dispatch(Publish::new("unknown", QoS::AtMostOnce, ""))
+            .await;
+        assert_eq!(
+            dispatch,
+            Dispatch {
+                handled_by: HandledBy::Nobody,
+                acknowledgement: None
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn ordered_and_limited_routes_are_dispatched() {
+        let mut router = HandlerRouterBuilder::new();
+        router.order(MessageOrder::PerTopic);
+        router.add_with_options(
+            "limited",
+            |_: Publish| async {},
+            RouteOptions::default().concurrency_limit(1),
+        );
+        let mut harness = RouterHarness::new(router.build()).await.unwrap();
+
+        for _ in 0..2 {
+            let dispatch = harness
+                .dispatch(Publish::new("limited", QoS::AtLeastOnce, ""))
+                .await;
+            assert_eq!(dispatch.handled_by, HandledBy::Route("limited".to_owned()));
+        }
+    }
+}
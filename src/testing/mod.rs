@@ -0,0 +1,7 @@
+//! Utilities for testing applications which use the client, enabled by the `testing` feature.
+
+pub use broker::MockBroker;
+pub use harness::{Dispatch, HandledBy, RouterHarness};
+
+mod broker;
+mod harness;
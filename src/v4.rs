@@ -0,0 +1,226 @@
+use bytes::BytesMut;
+use mqttbytes::{
+    v4,
+    v5::{
+        ConnAck, Connect, ConnectReturnCode, LastWill, Login, Packet, PubAck, PubComp, PubRec,
+        PubRel, Publish, SubAck, Subscribe, SubscribeFilter, SubscribeReasonCode, UnsubAck,
+        Unsubscribe,
+    },
+    Error, Protocol, QoS,
+};
+
+// The client works with v5 packets only, MQTT 3.1.1 packets are translated when they are written and read.
+// Properties and reason codes have no equivalent in 3.1.1 and are dropped.
+
+pub(crate) fn write(packet: &Packet, buf: &mut BytesMut) -> Result<usize, Error> {
+    match packet {
+        Packet::Connect(connect) => v4::Connect {
+            protocol: Protocol::V4,
+            keep_alive: connect.keep_alive,
+            client_id: connect.client_id.clone(),
+            clean_session: connect.clean_session,
+            last_will: connect.last_will.as_ref().map(|will| v4::LastWill {
+                topic: will.topic.clone(),
+                message: will.message.clone(),
+                qos: will.qos,
+                retain: will.retain,
+            }),
+            login: connect
+                .login
+                .as_ref()
+                .map(|login| v4::Login::new(login.username.as_str(), login.password.as_str())),
+        }
+        .write(buf),
+        Packet::ConnAck(connack) => {
+            v4::ConnAck::new(connack_code(connack.code), connack.session_present).write(buf)
+        }
+        Packet::Publish(publish) => v4::Publish {
+            dup: publish.dup,
+            qos: publish.qos,
+            retain: publish.retain,
+            topic: publish.topic.clone(),
+            pkid: publish.pkid,
+            payload: publish.payload.clone(),
+        }
+        .write(buf),
+        Packet::PubAck(puback) => v4::PubAck::new(puback.pkid).write(buf),
+        Packet::PubRec(pubrec) => v4::PubRec::new(pubrec.pkid).write(buf),
+        Packet::PubRel(pubrel) => v4::PubRel::new(pubrel.pkid).write(buf),
+        Packet::PubComp(pubcomp) => v4::PubComp::new(pubcomp.pkid).write(buf),
+        Packet::Subscribe(subscribe) => {
+            let mut packet = v4::Subscribe::new_many(
+                subscribe
+                    .filters
+                    .iter()
+                    .map(|filter| v4::SubscribeFilter::new(filter.path.clone(), filter.qos)),
+            );
+            packet.pkid = subscribe.pkid;
+            packet.write(buf)
+        }
+        Packet::SubAck(suback) => v4::SubAck::new(
+            suback.pkid,
+            suback
+                .return_codes
+                .iter()
+                .map(|code| match code {
+                    SubscribeReasonCode::QoS0 => v4::SubscribeReasonCode::Success(QoS::AtMostOnce),
+                    SubscribeReasonCode::QoS1 => v4::SubscribeReasonCode::Success(QoS::AtLeastOnce),
+                    SubscribeReasonCode::QoS2 => v4::SubscribeReasonCode::Success(QoS::ExactlyOnce),
+                    _ => v4::SubscribeReasonCode::Failure,
+                })
+                .collect(),
+        )
+        .write(buf),
+        Packet::Unsubscribe(unsubscribe) => v4::Unsubscribe {
+            pkid: unsubscribe.pkid,
+            topics: unsubscribe.filters.clone(),
+        }
+        .write(buf),
+        Packet::UnsubAck(unsuback) => v4::UnsubAck::new(unsuback.pkid).write(buf),
+        // A DISCONNECT without a reason code always discards the will in 3.1.1.
+        Packet::Disconnect(_) => v4::Disconnect.write(buf),
+        Packet::PingReq => v4::PingReq.write(buf),
+        Packet::PingResp => v4::PingResp.write(buf),
+    }
+}
+
+pub(crate) fn read(buf: &mut BytesMut, max_size: usize) -> Result<Packet, Error> {
+    let packet = match v4::read(buf, max_size)? {
+        v4::Packet::Connect(connect) => {
+            let mut packet = Connect::new(connect.client_id);
+            packet.protocol = Protocol::V4;
+            packet.keep_alive = connect.keep_alive;
+            packet.clean_session = connect.clean_session;
+            packet.last_will = connect.last_will.map(|will| LastWill {
+                topic: will.topic,
+                message: will.message,
+                qos: will.qos,
+                retain: will.retain,
+                properties: None,
+            });
+            packet.login = connect.login.map(|login| Login {
+                username: login.username,
+                password: login.password,
+            });
+            Packet::Connect(packet)
+        }
+        v4::Packet::ConnAck(connack) => Packet::ConnAck(ConnAck::new(
+            match connack.code {
+                v4::ConnectReturnCode::Success => ConnectReturnCode::Success,
+                v4::ConnectReturnCode::RefusedProtocolVersion => {
+                    ConnectReturnCode::UnsupportedProtocolVersion
+                }
+                v4::ConnectReturnCode::BadClientId => ConnectReturnCode::ClientIdentifierNotValid,
+                v4::ConnectReturnCode::ServiceUnavailable => ConnectReturnCode::ServerUnavailable,
+                v4::ConnectReturnCode::BadUserNamePassword => {
+                    ConnectReturnCode::BadUserNamePassword
+                }
+                v4::ConnectReturnCode::NotAuthorized => ConnectReturnCode::NotAuthorized,
+            },
+            connack.session_present,
+        )),
+        v4::Packet::Publish(publish) => {
+            let mut packet = Publish::from_bytes(publish.topic, publish.qos, publish.payload);
+            packet.dup = publish.dup;
+            packet.retain = publish.retain;
+            packet.pkid = publish.pkid;
+            Packet::Publish(packet)
+        }
+        v4::Packet::PubAck(puback) => Packet::PubAck(PubAck::new(puback.pkid)),
+        v4::Packet::PubRec(pubrec) => Packet::PubRec(PubRec::new(pubrec.pkid)),
+        v4::Packet::PubRel(pubrel) => Packet::PubRel(PubRel::new(pubrel.pkid)),
+        v4::Packet::PubComp(pubcomp) => Packet::PubComp(PubComp::new(pubcomp.pkid)),
+        v4::Packet::Subscribe(subscribe) => {
+            let mut packet = Subscribe::new_many(
+                subscribe
+                    .filters
+                    .into_iter()
+                    .map(|filter| SubscribeFilter::new(filter.path, filter.qos)),
+            );
+            packet.pkid = subscribe.pkid;
+            Packet::Subscribe(packet)
+        }
+        v4::Packet::SubAck(suback) => Packet::SubAck(SubAck::new(
+            suback.pkid,
+            suback
+                .return_codes
+                .into_iter()
+                .map(|code| match code {
+                    v4::SubscribeReasonCode::Success(QoS::AtMostOnce) => SubscribeReasonCode::QoS0,
+                    v4::SubscribeReasonCode::Success(QoS::AtLeastOnce) => SubscribeReasonCode::QoS1,
+                    v4::SubscribeReasonCode::Success(QoS::ExactlyOnce) => SubscribeReasonCode::QoS2,
+                    v4::SubscribeReasonCode::Failure => SubscribeReasonCode::Unspecified,
+                })
+                .collect(),
+        )),
+        v4::Packet::Unsubscribe(unsubscribe) => Packet::Unsubscribe(Unsubscribe {
+            pkid: unsubscribe.pkid,
+            filters: unsubscribe.topics,
+            properties: None,
+        }),
+        v4::Packet::UnsubAck(unsuback) => Packet::UnsubAck(UnsubAck::new(unsuback.pkid)),
+        v4::Packet::PingReq => Packet::PingReq,
+        v4::Packet::PingResp => Packet::PingResp,
+        v4::Packet::Disconnect => Packet::Disconnect(mqttbytes::v5::Disconnect::new()),
+    };
+    Ok(packet)
+}
+
+fn connack_code(code: ConnectReturnCode) -> v4::ConnectReturnCode {
+    match code {
+        ConnectReturnCode::Success => v4::ConnectReturnCode::Success,
+        ConnectReturnCode::UnsupportedProtocolVersion => {
+            v4::ConnectReturnCode::RefusedProtocolVersion
+        }
+        ConnectReturnCode::ClientIdentifierNotValid => v4::ConnectReturnCode::BadClientId,
+        ConnectReturnCode::BadUserNamePassword => v4::ConnectReturnCode::BadUserNamePassword,
+        ConnectReturnCode::NotAuthorized | ConnectReturnCode::Banned => {
+            v4::ConnectReturnCode::NotAuthorized
+        }
+        _ => v4::ConnectReturnCode::ServiceUnavailable,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_round_trip_without_properties() {
+        let mut publish = Publish::new("topic", QoS::ExactlyOnce, "payload");
+        publish.pkid = 7;
+        publish.retain = true;
+        let mut subscribe = Subscribe::new("topic/#", QoS::AtLeastOnce);
+        subscribe.pkid = 3;
+        let mut connect = Connect::new("client");
+        connect.protocol = Protocol::V4;
+        connect.login = Some(Login {
+            username: "user".to_owned(),
+            password: "password".to_owned(),
+        });
+
+        for packet in [
+            Packet::Connect(connect),
+            Packet::ConnAck(ConnAck::new(ConnectReturnCode::NotAuthorized, true)),
+            Packet::Publish(publish),
+            Packet::PubRel(PubRel::new(7)),
+            Packet::Subscribe(subscribe),
+            Packet::SubAck(SubAck::new(3, vec![SubscribeReasonCode::QoS1])),
+            Packet::UnsubAck(UnsubAck::new(4)),
+            Packet::PingResp,
+        ] {
+            let mut buf = BytesMut::new();
+            write(&packet, &mut buf).unwrap();
+            assert_eq!(read(&mut buf, usize::MAX).unwrap(), packet);
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn connect_declares_protocol_level_4() {
+        let mut buf = BytesMut::new();
+        write(&Packet::Connect(Connect::new("client")), &mut buf).unwrap();
+        // Fixed header, remaining length and the "MQTT" protocol name precede the level.
+        assert_eq!(buf[8], 4);
+    }
+}